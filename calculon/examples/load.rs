@@ -0,0 +1,128 @@
+// Load generator for comparing server configurations, e.g. --shards 1 against --shards 8.
+//
+// Start a release build of the server with the configuration to measure, discarding its per-line
+// logging so that the terminal is not the bottleneck:
+//
+//   cargo run --release -- --shards 1 > /dev/null
+//
+// and then, in another terminal:
+//
+//   cargo run --release --example load -- --clients 16 --commands 2000
+//
+// Every client opens its own TCP connection and sends ADD 1 the given number of times, waiting for
+// each reply before sending the next command. Once all clients are done, the overall rate is
+// printed along with TOTAL, which has to equal clients * commands on a freshly started server.
+// Restart the server with the other configuration and run the same command again to compare.
+
+use std::error::Error;
+use std::time::Instant;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4673";
+
+struct Options {
+    address: String,
+    clients: usize,
+    commands: usize,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut options = Options {
+            address: DEFAULT_ADDRESS.to_string(),
+            clients: 16,
+            commands: 2000,
+        };
+
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} requires a value."));
+
+            match arg.as_str() {
+                "--address" => options.address = value()?,
+                "--clients" => options.clients = parse_count(&arg, &value()?)?,
+                "--commands" => options.commands = parse_count(&arg, &value()?)?,
+                _ => return Err(format!("Unknown argument {arg}.")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_count(arg: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{arg} must be a whole number of at least 1.")),
+    }
+}
+
+// Connects and reads past the greeting, so that what follows are replies to our own commands.
+async fn connect(address: &str) -> Result<BufReader<TcpStream>, Box<dyn Error + Send + Sync>> {
+    let mut connection = BufReader::new(TcpStream::connect(address).await?);
+
+    let mut greeting = String::new();
+    connection.read_line(&mut greeting).await?;
+
+    Ok(connection)
+}
+
+async fn send(
+    connection: &mut BufReader<TcpStream>,
+    command: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    connection
+        .get_mut()
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await?;
+
+    let mut reply = String::new();
+
+    if connection.read_line(&mut reply).await? == 0 {
+        return Err("the server closed the connection".into());
+    }
+
+    Ok(reply.trim_end().to_string())
+}
+
+async fn run_client(address: String, commands: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut connection = connect(&address).await?;
+
+    for _ in 0..commands {
+        send(&mut connection, "ADD 1").await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let options = Options::from_args()?;
+
+    let started = Instant::now();
+
+    let clients: Vec<_> = (0..options.clients)
+        .map(|_| tokio::spawn(run_client(options.address.clone(), options.commands)))
+        .collect();
+
+    for client in clients {
+        client.await??;
+    }
+
+    let elapsed = started.elapsed();
+    let total_commands = options.clients * options.commands;
+
+    println!(
+        "{} clients sent {total_commands} commands in {elapsed:.2?}: {:.0} commands/s",
+        options.clients,
+        total_commands as f64 / elapsed.as_secs_f64()
+    );
+
+    let mut connection = connect(&options.address).await?;
+    println!("{}", send(&mut connection, "TOTAL").await?);
+
+    Ok(())
+}
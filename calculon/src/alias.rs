@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::command_names;
+
+// Replaces the first word of the line with its alias expansion, keeping any further words as
+// arguments. Aliases may expand to other aliases, so this repeats until the first word is not an
+// alias. Registration rejects loops, so this always terminates.
+pub fn expand_aliases(line: &str, aliases: &HashMap<String, String>) -> String {
+    let mut expanded = line.to_string();
+
+    loop {
+        let (first, rest) = split_first_word(&expanded);
+
+        let Some(expansion) = aliases.get(first) else {
+            return expanded;
+        };
+
+        expanded = format!("{expansion} {rest}");
+    }
+}
+
+// Checks whether `name` may be registered as an alias for `expansion` on top of the existing aliases.
+pub fn validate_alias(
+    name: &str,
+    expansion: &str,
+    aliases: &HashMap<String, String>,
+) -> Result<(), String> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("invalid alias name '{name}'"));
+    }
+
+    if is_built_in(name) {
+        return Err(format!("cannot alias over built-in command {name}"));
+    }
+
+    // Follow the chain of aliases that the new expansion leads to. If it comes back around to the
+    // alias being defined, using it would expand forever.
+    let mut target = split_first_word(expansion).0;
+
+    loop {
+        if target == name {
+            return Err(format!("alias {name} would expand to itself"));
+        }
+
+        match aliases.get(target) {
+            Some(next) => target = split_first_word(next).0,
+            None => return Ok(()),
+        }
+    }
+}
+
+fn is_built_in(name: &str) -> bool {
+    command_names().any(|command| command == name)
+}
+
+fn split_first_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+
+    match line.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, rest),
+        None => (line, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(definitions: &[(&str, &str)]) -> HashMap<String, String> {
+        definitions
+            .iter()
+            .map(|(name, expansion)| (name.to_string(), expansion.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn aliases_expand_through_chains() {
+        let aliases = aliases(&[("a", "ADD"), ("five", "a 5")]);
+
+        assert_eq!(expand_aliases("a 1 2", &aliases), "ADD 1 2");
+        assert_eq!(expand_aliases("five 3", &aliases).trim_end(), "ADD 5 3");
+        assert_eq!(expand_aliases("SHOW", &aliases), "SHOW");
+    }
+
+    #[test]
+    fn loops_and_built_ins_are_refused() {
+        let aliases = aliases(&[("a", "b"), ("b", "c")]);
+
+        assert!(validate_alias("c", "a", &aliases).is_err());
+        assert!(validate_alias("d", "d 1", &aliases).is_err());
+        assert!(validate_alias("ADD", "SUBTRACT", &aliases).is_err());
+        assert!(validate_alias("my alias", "ADD", &aliases).is_err());
+        assert!(validate_alias("c", "ADD 1", &aliases).is_ok());
+    }
+}
@@ -0,0 +1,231 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+// A reference client for calculon. It runs a file of commands against the server, one command per
+// line as in a --script file, and prints every command with the response to it:
+//
+//     cargo run --bin calculon-client -- commands.txt
+//
+// The connection's state is kept in a SESSION. Should the connection drop, the client reconnects,
+// RESUMEs the session and resends the command it was waiting for. That command may already have
+// been handled before the connection dropped, in which case it is handled twice. A command is only
+// resent once: if the connection drops again, the command itself is the likely cause, e.g. one
+// that crashes the server, and the client gives up rather than sending it forever.
+//
+// Responses are not delimited by the protocol and some commands send none at all, so every command
+// is followed by a NOP with a tag of its own. Everything up to the OK with that tag is the response.
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4673";
+
+// How many times in a row the client tries to reconnect before giving up.
+const DEFAULT_RETRIES: u32 = 5;
+
+// How long to wait before the first attempt to reconnect. Every further attempt waits this much
+// longer than the previous one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+struct Args {
+    address: String,
+    retries: u32,
+    script: PathBuf,
+}
+
+impl Args {
+    fn from_args() -> Result<Self, String> {
+        let mut address = DEFAULT_ADDRESS.to_string();
+        let mut retries = DEFAULT_RETRIES;
+        let mut script = None;
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--address" => address = parse_value(&arg, args.next())?,
+                "--retries" => retries = parse_value(&arg, args.next())?,
+                _ if arg.starts_with("--") => return Err(format!("Unknown argument: {arg}")),
+                _ if script.is_none() => script = Some(PathBuf::from(arg)),
+                _ => return Err(format!("Only one script can be run, not also {arg}")),
+            }
+        }
+
+        let script = script.ok_or(
+            "Usage: calculon-client [--address 127.0.0.1:4673] [--retries 5] commands.txt",
+        )?;
+
+        Ok(Self {
+            address,
+            retries,
+            script,
+        })
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{name} requires a value."))?;
+
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for {name}: {value}"))
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+
+    // Number of the next NOP sent to mark the end of a response.
+    next_tag: u64,
+}
+
+impl Connection {
+    // Connects and reads past the greeting.
+    fn open(address: &str) -> io::Result<Self> {
+        let writer = TcpStream::connect(address)?;
+        let reader = BufReader::new(writer.try_clone()?);
+
+        let mut connection = Self {
+            reader,
+            writer,
+            next_tag: 1,
+        };
+
+        connection.read_line()?;
+        Ok(connection)
+    }
+
+    // Sends the command and returns the lines of the response to it, which may be none at all.
+    fn run(&mut self, command: &str) -> io::Result<Vec<String>> {
+        let tag = format!("client-{}", self.next_tag);
+        self.next_tag += 1;
+
+        // Both in one write, so the NOP cannot be lost without the command being lost too.
+        self.writer
+            .write_all(format!("{command}\r\nNOP {tag}\r\n").as_bytes())?;
+
+        let end = format!("OK {tag}");
+        let mut response = Vec::new();
+
+        loop {
+            let line = self.read_line()?;
+
+            if line == end {
+                return Ok(response);
+            }
+
+            response.push(line);
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection",
+            ));
+        }
+
+        Ok(line.trim_end().to_string())
+    }
+
+    fn start_session(&mut self) -> io::Result<String> {
+        let response = self.run("SESSION")?;
+
+        response
+            .first()
+            .and_then(|line| line.strip_prefix("SESSION "))
+            .map(str::to_string)
+            .ok_or_else(|| {
+                io::Error::other(format!("unexpected response to SESSION: {response:?}"))
+            })
+    }
+
+    fn resume(&mut self, token: &str) -> io::Result<()> {
+        let response = self.run(&format!("RESUME {token}"))?;
+
+        if response.first() != Some(&format!("RESUMED {token}")) {
+            return Err(io::Error::other(format!(
+                "the session could not be resumed: {}",
+                response.join(" ")
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// Reconnects and resumes the session. The server only keeps the session once it has noticed that
+// the previous connection is gone, so failing to resume is retried too.
+fn reconnect(args: &Args, token: &str) -> Result<Connection, String> {
+    let mut last_error = None;
+
+    for attempt in 1..=args.retries {
+        thread::sleep(RETRY_BACKOFF * attempt);
+
+        let reconnected = Connection::open(&args.address).and_then(|mut connection| {
+            connection.resume(token)?;
+            Ok(connection)
+        });
+
+        match reconnected {
+            Ok(connection) => {
+                eprintln!("Reconnected to {} and resumed the session", args.address);
+                return Ok(connection);
+            }
+            Err(e) => {
+                eprintln!("Warning: reconnecting, attempt {attempt} failed: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => format!("Gave up reconnecting to {}: {e}", args.address),
+        None => format!("Lost the connection to {} and --retries is 0", args.address),
+    })
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::from_args()?;
+
+    let script = fs::read_to_string(&args.script)
+        .map_err(|e| format!("Failed to read script {}: {e}", args.script.display()))?;
+
+    let mut connection = Connection::open(&args.address)
+        .map_err(|e| format!("Failed to connect to {}: {e}", args.address))?;
+    let token = connection.start_session()?;
+
+    for command in script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        println!("> {command}");
+
+        let response = match connection.run(command) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Warning: lost the connection to {}: {e}", args.address);
+                connection = reconnect(&args, &token)?;
+
+                connection.run(command).map_err(|e| {
+                    format!(
+                        "Lost the connection to {} again while resending {command}: {e}",
+                        args.address
+                    )
+                })?
+            }
+        };
+
+        for line in response {
+            println!("{line}");
+        }
+    }
+
+    Ok(())
+}
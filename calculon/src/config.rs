@@ -0,0 +1,139 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Command line configuration of the server. Every setting has a default, so no arguments are required.
+#[derive(Debug)]
+pub struct Config {
+    // The TCP port that clients connect to.
+    pub port: u16,
+
+    // How long the state of a disconnected session is kept around for RESUME.
+    pub session_ttl: Duration,
+
+    // Whether the DELAY command is available. It exists for scripted testing only.
+    pub enable_delay: bool,
+
+    // If set, commands are also accepted as UDP datagrams on this port.
+    pub udp_port: Option<u16>,
+
+    // If set, the HTTP facade listens on this port.
+    pub http_port: Option<u16>,
+
+    // Commands taking longer than this to handle are logged as slow.
+    pub slow_command_threshold: Duration,
+
+    // Commands taking longer than this to handle are abandoned and reported as failed.
+    pub command_timeout: Duration,
+
+    // If set, the server logs that it is idle once no TCP client has been connected for this long.
+    pub idle_grace: Option<Duration>,
+
+    // How many modifications of X each connection remembers for UNDO, HISTORY and GRAPH.
+    pub history_size: usize,
+
+    // If set, the commands in this file are run against the global state at startup.
+    pub script: Option<PathBuf>,
+
+    // Whether a failing command in the startup script aborts startup rather than just being logged.
+    pub strict_script: bool,
+
+    // If set, every command line received from a TCP client is appended to this file.
+    pub transcript: Option<PathBuf>,
+
+    // Number of independent shards that X and the registers are split into. Every connection works
+    // on one of them, so connections on different shards never contend for the same lock.
+    pub shards: usize,
+
+    // If set, SHUTDOWN stops the server when given this token. Without it, SHUTDOWN is disabled.
+    // The argument of SHUTDOWN is redacted from the server's log and from the transcript.
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 4673,
+            session_ttl: Duration::from_secs(600),
+            enable_delay: false,
+            udp_port: None,
+            http_port: None,
+            slow_command_threshold: Duration::from_millis(100),
+            command_timeout: Duration::from_secs(5),
+            history_size: 1000,
+            idle_grace: None,
+            script: None,
+            strict_script: false,
+            transcript: None,
+            shards: 1,
+            admin_token: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--port" => config.port = parse_value(&arg, args.next())?,
+                "--session-ttl" => {
+                    config.session_ttl = Duration::from_secs(parse_value(&arg, args.next())?);
+                }
+                "--enable-delay" => config.enable_delay = true,
+                "--udp-port" => config.udp_port = Some(parse_value(&arg, args.next())?),
+                "--http-port" => config.http_port = Some(parse_value(&arg, args.next())?),
+                "--slow-command-ms" => {
+                    config.slow_command_threshold =
+                        Duration::from_millis(parse_value(&arg, args.next())?);
+                }
+                "--command-timeout-ms" => {
+                    config.command_timeout = Duration::from_millis(parse_value(&arg, args.next())?);
+                }
+                "--idle-grace-secs" => {
+                    config.idle_grace = Some(Duration::from_secs(parse_value(&arg, args.next())?));
+                }
+                "--history-size" => config.history_size = parse_value(&arg, args.next())?,
+                "--script" => config.script = Some(parse_value(&arg, args.next())?),
+                "--strict-script" => config.strict_script = true,
+                "--transcript" => config.transcript = Some(parse_value(&arg, args.next())?),
+                "--shards" => config.shards = parse_value(&arg, args.next())?,
+                "--admin-token" => config.admin_token = Some(parse_value(&arg, args.next())?),
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+
+        if config.history_size == 0 {
+            return Err("--history-size must be at least 1.".to_string());
+        }
+
+        if config.shards == 0 {
+            return Err("--shards must be at least 1.".to_string());
+        }
+
+        if config.command_timeout.is_zero() {
+            return Err("--command-timeout-ms must be at least 1.".to_string());
+        }
+
+        if config.admin_token.as_deref() == Some("") {
+            return Err("--admin-token must not be empty.".to_string());
+        }
+
+        if config.strict_script && config.script.is_none() {
+            return Err("--strict-script requires --script.".to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{name} requires a value."))?;
+
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for {name}: {value}"))
+}
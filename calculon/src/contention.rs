@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Counts how often a lock was taken, how often it had to be waited for and how long all the waiting
+// took together, as shown by STATS. Only atomics, so recording costs next to nothing compared to
+// taking the lock itself.
+#[derive(Debug, Default)]
+pub struct LockContention {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+impl LockContention {
+    pub fn record_uncontended(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_wait(&self, waited: Duration) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+
+        // Saturating rather than wrapping, though it takes centuries of waiting to get there.
+        let nanos = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        _ = self
+            .wait_nanos
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                Some(total.saturating_add(nanos))
+            });
+    }
+
+    // E.g. "lock taken 120 times, waited for 7 times (5.8%), 1.2ms waited in total". The counters
+    // are read one by one, so under load they can be a little out of step with each other.
+    pub fn summary(&self) -> String {
+        let acquisitions = self.acquisitions.load(Ordering::Relaxed);
+        let contended = self.contended.load(Ordering::Relaxed);
+        let waited = Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed));
+
+        let percent = if acquisitions == 0 {
+            0.0
+        } else {
+            contended as f64 / acquisitions as f64 * 100.0
+        };
+
+        format!(
+            "lock taken {acquisitions} times, waited for {contended} times ({percent:.1}%), {waited:?} waited in total"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_waits_are_added_up() {
+        let contention = LockContention::default();
+        assert_eq!(
+            contention.summary(),
+            "lock taken 0 times, waited for 0 times (0.0%), 0ns waited in total"
+        );
+
+        contention.record_uncontended();
+        contention.record_wait(Duration::from_millis(2));
+        contention.record_wait(Duration::from_micros(500));
+        contention.record_uncontended();
+        assert_eq!(
+            contention.summary(),
+            "lock taken 4 times, waited for 2 times (50.0%), 2.5ms waited in total"
+        );
+
+        contention.record_wait(Duration::MAX);
+        assert!(contention.summary().ends_with(&format!(
+            "{:?} waited in total",
+            Duration::from_nanos(u64::MAX)
+        )));
+    }
+}
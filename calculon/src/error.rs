@@ -0,0 +1,136 @@
+use std::fmt;
+
+use crate::expression::InvalidOperand;
+use crate::number::InvalidNumber;
+
+// A failed command, as reported to the client. Every kind of failure has a stable code so that
+// machine clients can tell them apart without parsing the message, which is meant for humans.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    // The arguments are unacceptable for the command, e.g. out of range or the wrong kind.
+    Args(String),
+
+    // The line or one of its operands could not be parsed.
+    Parse(String),
+
+    DivisionByZero,
+
+    // The arguments are fine but the result is undefined for them, e.g. the mean of no samples.
+    Domain(String),
+
+    // Something else is in the way, such as an open transaction or a conflicting change to X.
+    Busy(String),
+
+    // The named register, alias, session or the like does not exist.
+    NotFound(String),
+
+    // The command does not apply to the connection's current state, e.g. COMMIT without BEGIN.
+    State(String),
+
+    // The command did not finish within the server's command timeout and was abandoned.
+    Timeout,
+
+    // No such command, or no such mode for MODE.
+    Unknown(String),
+}
+
+impl CommandError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::Args(_) => "EARGS",
+            CommandError::Parse(_) => "EPARSE",
+            CommandError::DivisionByZero => "EDIVZERO",
+            CommandError::Domain(_) => "EDOMAIN",
+            CommandError::Busy(_) => "EBUSY",
+            CommandError::NotFound(_) => "ENOTFOUND",
+            CommandError::State(_) => "ESTATE",
+            CommandError::Timeout => "ETIMEOUT",
+            CommandError::Unknown(_) => "EUNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::DivisionByZero => write!(f, "division by zero"),
+            CommandError::Timeout => write!(f, "command took too long"),
+            CommandError::Args(message)
+            | CommandError::Parse(message)
+            | CommandError::Domain(message)
+            | CommandError::Busy(message)
+            | CommandError::NotFound(message)
+            | CommandError::State(message)
+            | CommandError::Unknown(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<InvalidNumber> for CommandError {
+    fn from(e: InvalidNumber) -> Self {
+        CommandError::Parse(e.to_string())
+    }
+}
+
+impl From<InvalidOperand> for CommandError {
+    fn from(e: InvalidOperand) -> Self {
+        CommandError::Parse(e.to_string())
+    }
+}
+
+// How a connection wants to see errors, chosen with MODE ERRORS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorStyle {
+    // "ERROR EDIVZERO division by zero"
+    #[default]
+    Codes,
+
+    // "ERROR: division by zero", for humans typing commands by hand.
+    Prose,
+
+    // {"error": "EDIVZERO", "message": "division by zero"}, for a command prefixed with JSON.
+    // Not available through MODE ERRORS, as the rest of the responses would still be text.
+    Json,
+}
+
+impl ErrorStyle {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "CODES" => Some(ErrorStyle::Codes),
+            "PROSE" => Some(ErrorStyle::Prose),
+            _ => None,
+        }
+    }
+
+    pub fn format(self, error: &CommandError) -> String {
+        match self {
+            ErrorStyle::Codes => format!("ERROR {} {error}\r\n", error.code()),
+            ErrorStyle::Prose => format!("ERROR: {error}\r\n"),
+            ErrorStyle::Json => format!("{}\r\n", json_error(error.code(), &error.to_string())),
+        }
+    }
+}
+
+fn json_error(code: &str, message: &str) -> String {
+    format!(
+        "{{\"error\": \"{code}\", \"message\": {}}}",
+        json_string(message)
+    )
+}
+
+// Messages can quote whatever the client sent, so they have to be escaped.
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,294 @@
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::vec;
+
+use crate::number::parse_number;
+
+// An operand that is either a number or worked out from the current X, e.g. the X*0.1 in ADD X*0.1.
+//
+// An expression joins X and numbers with + - * /, without spaces or parentheses. * and / bind more
+// tightly than + and -, and otherwise the operators apply left to right, so X+X*2 is X+(X*2) and
+// X-1-2 is (X-1)-2. Readings that people commonly disagree on are refused rather than guessed at:
+// - a division followed by another * or / in the same product, e.g. X/2*3 (write X*3/2 instead)
+// - a sign anywhere but at the very start, e.g. X*-2 or X--1 (write -2*X or X+1 instead)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    // A sum of products, e.g. X*X/2-1 is +(X*X/2) and -(1).
+    Expression(Vec<Term>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    negative: bool,
+    factors: Vec<Factor>,
+    divisor: Option<Factor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Factor {
+    X,
+    Number(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    X,
+    Number(f64),
+    Plus,
+    Minus,
+    Times,
+    Divide,
+}
+
+// An operand that is neither a number nor an expression that can be understood unambiguously.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidOperand {
+    pub token: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid operand {}: {}", self.token, self.reason)
+    }
+}
+
+impl Error for InvalidOperand {}
+
+impl Operand {
+    // The numbers in an expression follow the same rules as plain number operands, e.g. for
+    // thousands separators.
+    pub fn parse(token: &str, thousands_separators: bool) -> Result<Self, InvalidOperand> {
+        if let Ok(value) = parse_number(token, thousands_separators) {
+            return Ok(Operand::Number(value));
+        }
+
+        let invalid = |reason: String| InvalidOperand {
+            token: token.to_string(),
+            reason,
+        };
+
+        let tokens = tokenize(token, thousands_separators).map_err(invalid)?;
+        let terms = parse_terms(tokens).map_err(invalid)?;
+
+        Ok(Operand::Expression(terms))
+    }
+
+    // Fails if the expression divides by zero.
+    pub fn value(&self, x: f64) -> Result<f64, &'static str> {
+        match self {
+            Operand::Number(value) => Ok(*value),
+            Operand::Expression(terms) => terms
+                .iter()
+                .try_fold(0.0, |sum, term| Ok(sum + term.value(x)?)),
+        }
+    }
+}
+
+impl Term {
+    fn value(&self, x: f64) -> Result<f64, &'static str> {
+        let mut value = self
+            .factors
+            .iter()
+            .map(|factor| factor.value(x))
+            .product::<f64>();
+
+        if let Some(divisor) = self.divisor {
+            let divisor = divisor.value(x);
+
+            if divisor == 0.0 {
+                return Err("division by zero in operand");
+            }
+
+            value /= divisor;
+        }
+
+        Ok(if self.negative { -value } else { value })
+    }
+}
+
+impl Factor {
+    fn value(self, x: f64) -> f64 {
+        match self {
+            Factor::X => x,
+            Factor::Number(value) => value,
+        }
+    }
+}
+
+fn tokenize(text: &str, thousands_separators: bool) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            'X' => Token::X,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Times,
+            '/' => Token::Divide,
+            '0'..='9' | '.' => {
+                let mut end = start + 1;
+
+                while let Some(&(index, c)) = chars.peek() {
+                    // The sign of an exponent, as in 1e-5, belongs to the number.
+                    let is_exponent_sign = matches!(c, '+' | '-')
+                        && matches!(&text[..index].chars().last(), Some('e' | 'E'));
+
+                    if !(c.is_ascii_alphanumeric()
+                        || matches!(c, '.' | ',' | '_')
+                        || is_exponent_sign)
+                        || c == 'X'
+                    {
+                        break;
+                    }
+
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+
+                let number = &text[start..end];
+                let value = parse_number(number, thousands_separators)
+                    .map_err(|_| format!("{number} is not a number"))?;
+
+                Token::Number(value)
+            }
+            _ => return Err(format!("unexpected {c}")),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+// Parses the whole expression as a sum of products.
+fn parse_terms(tokens: Vec<Token>) -> Result<Vec<Term>, String> {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut terms = Vec::new();
+
+    let mut negative = match tokens.peek() {
+        Some(Token::Minus) => {
+            tokens.next();
+            true
+        }
+        Some(Token::Plus) => {
+            tokens.next();
+            false
+        }
+        _ => false,
+    };
+
+    loop {
+        let (factors, divisor) = parse_product(&mut tokens)?;
+
+        terms.push(Term {
+            negative,
+            factors,
+            divisor,
+        });
+
+        negative = match tokens.next() {
+            None => return Ok(terms),
+            Some(Token::Plus) => false,
+            Some(Token::Minus) => true,
+            Some(_) => return Err("missing an operator, e.g. 2*X rather than 2X".to_string()),
+        };
+    }
+}
+
+// Parses factors joined by * and at most one /, which has to come last.
+fn parse_product(
+    tokens: &mut Peekable<vec::IntoIter<Token>>,
+) -> Result<(Vec<Factor>, Option<Factor>), String> {
+    let mut factors = vec![parse_factor(tokens)?];
+    let mut divisor = None;
+
+    while let Some(&operator @ (Token::Times | Token::Divide)) = tokens.peek() {
+        if divisor.is_some() {
+            return Err(
+                "ambiguous after a division, put the division last, e.g. X*3/2 rather than X/2*3"
+                    .to_string(),
+            );
+        }
+
+        tokens.next();
+        let factor = parse_factor(tokens)?;
+
+        match operator {
+            Token::Times => factors.push(factor),
+            _ => divisor = Some(factor),
+        }
+    }
+
+    Ok((factors, divisor))
+}
+
+fn parse_factor(tokens: &mut Peekable<vec::IntoIter<Token>>) -> Result<Factor, String> {
+    match tokens.next() {
+        Some(Token::X) => Ok(Factor::X),
+        Some(Token::Number(value)) => Ok(Factor::Number(value)),
+        Some(Token::Plus | Token::Minus) => {
+            Err("a sign is only allowed at the start, e.g. -2*X rather than X*-2".to_string())
+        }
+        Some(Token::Times | Token::Divide) => {
+            Err("missing X or a number before * or /".to_string())
+        }
+        None => Err("missing X or a number at the end".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(token: &str, x: f64) -> Result<f64, String> {
+        Operand::parse(token, false)
+            .map_err(|e| e.reason)?
+            .value(x)
+            .map_err(str::to_string)
+    }
+
+    #[test]
+    fn products_bind_more_tightly_than_sums() {
+        assert_eq!(Operand::parse("2.5", false), Ok(Operand::Number(2.5)));
+        assert_eq!(value("X*0.1", 50.0), Ok(5.0));
+        assert_eq!(value("X+X*2", 3.0), Ok(9.0));
+        assert_eq!(value("X-1-2", 10.0), Ok(7.0));
+        assert_eq!(value("-2*X+1", 4.0), Ok(-7.0));
+        assert_eq!(value("X*3/2", 4.0), Ok(6.0));
+        assert_eq!(value("X*1e-1", 30.0), Ok(3.0));
+
+        assert_eq!(
+            Operand::parse("X*1,000", true).unwrap().value(2.0),
+            Ok(2000.0)
+        );
+    }
+
+    #[test]
+    fn ambiguous_expressions_are_refused() {
+        for token in [
+            "X/2*3", "X/2/3", "X*-2", "X--1", "2X", "X*", "*X", "X+Y", "",
+        ] {
+            assert!(Operand::parse(token, false).is_err(), "{token}");
+        }
+
+        assert_eq!(
+            value("X/2*3", 1.0),
+            Err(
+                "ambiguous after a division, put the division last, e.g. X*3/2 rather than X/2*3"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn dividing_by_zero_fails_when_evaluated() {
+        let operand = Operand::parse("1/X", false).unwrap();
+
+        assert_eq!(operand.value(4.0), Ok(0.25));
+        assert_eq!(operand.value(0.0), Err("division by zero in operand"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+// How commands and responses are delimited on a TCP connection. Commands are executed and
+// responses produced the same way whatever the framing, so adding a framing (e.g. length-prefixed
+// or JSON) only means implementing this trait.
+//
+// The framer is cloned into the connection's writer task, so reading and writing can happen
+// at the same time.
+pub trait Framer: Clone + Send + Sync + 'static {
+    // Reads the next command. Returns None once the client has closed the connection.
+    fn read_command<R>(
+        &self,
+        reader: &mut R,
+    ) -> impl Future<Output = io::Result<Option<String>>> + Send
+    where
+        R: AsyncBufRead + Unpin + Send;
+
+    // Writes a response. Responses come as one or more CRLF-terminated lines, which framings other
+    // than the line protocol have to convert to their own format.
+    fn write_response<W>(
+        &self,
+        writer: &mut W,
+        response: &str,
+    ) -> impl Future<Output = io::Result<()>> + Send
+    where
+        W: AsyncWrite + Unpin + Send;
+}
+
+// The original protocol: every command is a line, and responses are sent exactly as they are.
+// Lines may end with LF or CRLF.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LineFramer;
+
+impl Framer for LineFramer {
+    async fn read_command<R>(&self, reader: &mut R) -> io::Result<Option<String>>
+    where
+        R: AsyncBufRead + Unpin + Send,
+    {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+
+    async fn write_response<W>(&self, writer: &mut W, response: &str) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        writer.write_all(response.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &[u8] = b"ADD 1\r\nSHOW\n\n  SUBTRACT 2  \r\n\r\nbad\rline\r\nFROBNICATE\nSHOW";
+
+    async fn read_all(mut reader: &[u8]) -> Vec<String> {
+        let mut commands = Vec::new();
+
+        while let Some(command) = LineFramer.read_command(&mut reader).await.unwrap() {
+            commands.push(command);
+        }
+
+        commands
+    }
+
+    #[tokio::test]
+    async fn lines_are_split_exactly_as_before() {
+        // Before the Framer trait, commands were read with tokio's lines().
+        let mut lines = SCRIPT.lines();
+        let mut expected = Vec::new();
+
+        while let Some(line) = lines.next_line().await.unwrap() {
+            expected.push(line);
+        }
+
+        assert_eq!(read_all(SCRIPT).await, expected);
+        assert_eq!(
+            expected,
+            [
+                "ADD 1",
+                "SHOW",
+                "",
+                "  SUBTRACT 2  ",
+                "",
+                "bad\rline",
+                "FROBNICATE",
+                "SHOW"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_are_written_unchanged() {
+        let mut written = Vec::new();
+
+        for response in ["X = 1\r\n", "", "a\nb\r\n"] {
+            LineFramer
+                .write_response(&mut written, response)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(written, b"X = 1\r\na\nb\r\n");
+    }
+}
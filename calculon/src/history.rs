@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+// The value of X immediately before and after a command modified it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub previous: f64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    // The command as the client sent it (after alias expansion), e.g. "ADD 5".
+    pub command: String,
+    pub change: Change,
+}
+
+// Used for connections that do not come from the TCP listener, which knows the configured size.
+const DEFAULT_CAPACITY: usize = 1000;
+
+// The most recent modifications of X made by one connection, oldest first. Once the capacity is
+// reached, recording a new modification forgets the oldest one, so long-lived connections do not
+// grow without bound.
+#[derive(Debug, Clone)]
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+
+    // Modifications recorded or undone so far, including those since forgotten.
+    modifications: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            modifications: 0,
+        }
+    }
+
+    pub fn record(&mut self, command: &str, change: Change) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(HistoryEntry {
+            command: command.to_string(),
+            change,
+        });
+        self.modifications += 1;
+    }
+
+    // Forgets the most recent modification and returns it, or None if there is nothing left.
+    // Undoing it is a modification of X in its own right.
+    pub fn undo(&mut self) -> Option<HistoryEntry> {
+        let entry = self.entries.pop_back()?;
+        self.modifications += 1;
+
+        Some(entry)
+    }
+
+    // Grows by one with every modification of X, so comparing it before and after a command tells
+    // whether the command modified X.
+    pub fn modifications(&self) -> u64 {
+        self.modifications
+    }
+
+    pub fn last(&self) -> Option<&HistoryEntry> {
+        self.entries.back()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    // The values X had after each of the most recent `count` modifications, oldest first.
+    pub fn recent_values(&self, count: usize) -> Vec<f64> {
+        let skip = self.entries.len().saturating_sub(count);
+
+        self.entries
+            .iter()
+            .skip(skip)
+            .map(|entry| entry.change.value)
+            .collect()
+    }
+}
+
+const SPARKLINE_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Renders the values as one bar each, scaled between the smallest and largest value.
+// If all the values are the same, every bar is drawn at half height.
+pub fn sparkline(values: &[f64]) -> String {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = max - min;
+
+    values
+        .iter()
+        .map(|value| {
+            // NaN and infinities have no sensible height, so they get the lowest bar.
+            let scaled = if range > 0.0 && range.is_finite() {
+                (value - min) / range
+            } else if value.is_finite() {
+                0.5
+            } else {
+                0.0
+            };
+
+            let index = (scaled * (SPARKLINE_BARS.len() - 1) as f64).round() as usize;
+            SPARKLINE_BARS[index.min(SPARKLINE_BARS.len() - 1)]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(value: f64) -> Change {
+        Change {
+            previous: value - 1.0,
+            value,
+        }
+    }
+
+    #[test]
+    fn the_oldest_entry_is_forgotten_at_capacity() {
+        let mut history = History::new(2);
+
+        for value in 1..=3 {
+            history.record(&format!("ADD {value}"), change(value.into()));
+        }
+
+        let commands: Vec<_> = history
+            .entries()
+            .map(|entry| entry.command.as_str())
+            .collect();
+        assert_eq!(commands, ["ADD 2", "ADD 3"]);
+        assert_eq!(history.recent_values(5), [2.0, 3.0]);
+
+        assert_eq!(history.undo().unwrap().command, "ADD 3");
+        assert_eq!(history.undo().unwrap().command, "ADD 2");
+        assert_eq!(history.undo(), None);
+    }
+}
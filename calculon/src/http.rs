@@ -0,0 +1,252 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::operation::Operation;
+use crate::priority::Priority;
+use crate::{apply, show, Server};
+
+// Requests with bodies larger than this are rejected. Operands are tiny, so this is plenty.
+const MAX_BODY_SIZE: usize = 4096;
+
+// How long a client has to send its whole request, so that a slow or idle client cannot keep its
+// connection, and the task serving it, around forever.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+// A minimal HTTP/1.1 facade over the same operations and the same X as the TCP protocol:
+//
+// GET /x                                  -> {"x": 12}
+// POST /add with body {"operand": 5}      -> {"x": 17}
+// POST /subtract, /power, /percent, /increase and /decrease work the same way as /add.
+//
+// Every connection serves exactly one request and is then closed. This is deliberately not a
+// general purpose HTTP server - just enough for simple web integrations to get at X.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept HTTP connection; error = {}", e);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = process_request(stream, peer, server).await {
+                eprintln!("Failed to process HTTP request; error = {}", e);
+            }
+        });
+    }
+}
+
+async fn process_request(
+    stream: TcpStream,
+    peer: SocketAddr,
+    server: Arc<Server>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(stream);
+
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut reader));
+
+    let (status, body) = match request.await {
+        Ok(request) => match request? {
+            Some(request) => {
+                // Every request comes on a connection of its own from a new port, so the shard is
+                // picked by the peer's IP address alone, for a client to keep seeing the same X
+                // from one request to the next.
+                let shard = server.shards.index_for(&peer.ip());
+
+                // Like a command, a request waits for a serializable transaction for no longer
+                // than the command timeout.
+                let entered = server.enter_shard(shard);
+
+                match tokio::time::timeout(server.config.command_timeout, entered).await {
+                    Ok(_entered) => handle(
+                        &request.method,
+                        &request.path,
+                        &request.body,
+                        shard,
+                        &server,
+                    ),
+                    Err(_) => (
+                        "503 Service Unavailable",
+                        error_body("X is held by a transaction"),
+                    ),
+                }
+            }
+            None => (
+                "413 Payload Too Large",
+                error_body("request body too large"),
+            ),
+        },
+        Err(_) => {
+            eprintln!("Warning: HTTP client took too long to send its request");
+            ("408 Request Timeout", error_body("request took too long"))
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+// Reads the request line, the headers and the body. Returns None if the body is too large to read.
+async fn read_request(
+    reader: &mut BufReader<TcpStream>,
+) -> Result<Option<Request>, Box<dyn Error + Send + Sync>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut request_parts = request_line.split_whitespace();
+    let method = request_parts.next().unwrap_or_default().to_string();
+    let path = request_parts.next().unwrap_or_default().to_string();
+
+    // We only care about the body length, all other headers are skipped.
+    let mut content_length = 0;
+
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).await?;
+
+        let header = header.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>()?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Some(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }))
+}
+
+fn handle(
+    method: &str,
+    path: &str,
+    body: &str,
+    shard: usize,
+    server: &Server,
+) -> (&'static str, String) {
+    let global_state = &server.shards.get(shard).with_priority(Priority::Normal);
+
+    let operation: fn(f64) -> Operation = match (method, path) {
+        ("GET", "/x") => return ("200 OK", x_body(show(global_state))),
+        ("POST", "/add") => Operation::Add,
+        ("POST", "/subtract") => Operation::Subtract,
+        ("POST", "/power") => Operation::Power,
+        ("POST", "/percent") => Operation::Percent,
+        ("POST", "/increase") => Operation::Increase,
+        ("POST", "/decrease") => Operation::Decrease,
+        (_, "/x" | "/add" | "/subtract" | "/power" | "/percent" | "/increase" | "/decrease") => {
+            return ("405 Method Not Allowed", error_body("method not allowed"))
+        }
+        _ => return ("404 Not Found", error_body("not found")),
+    };
+
+    let Some(operand) = parse_operand(body) else {
+        return (
+            "400 Bad Request",
+            error_body("expected a body like {\"operand\": 5}"),
+        );
+    };
+
+    match apply(operation(operand), global_state) {
+        Ok(change) => ("200 OK", x_body(change.value)),
+        Err(e) => ("400 Bad Request", error_body(e)),
+    }
+}
+
+// Extracts the number from a {"operand": 5} body. Anything else in the body is ignored.
+fn parse_operand(body: &str) -> Option<f64> {
+    let after_key = &body[body.find("\"operand\"")? + "\"operand\"".len()..];
+    let after_colon = after_key.trim_start().strip_prefix(':')?.trim_start();
+
+    let number_length = after_colon
+        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(after_colon.len());
+
+    after_colon[..number_length].parse::<f64>().ok()
+}
+
+// JSON has no representation for NaN or infinity, so those become null.
+pub fn x_body(x: f64) -> String {
+    if x.is_finite() {
+        format!("{{\"x\": {x}}}")
+    } else {
+        "{\"x\": null}".to_string()
+    }
+}
+
+fn error_body(message: &str) -> String {
+    format!("{{\"error\": \"{message}\"}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn operand_is_picked_out_of_the_body() {
+        assert_eq!(parse_operand("{\"operand\": 5}"), Some(5.0));
+        assert_eq!(
+            parse_operand("{\"other\": 1, \"operand\":-2.5e1}"),
+            Some(-25.0)
+        );
+        assert_eq!(parse_operand("{\"operand\": \"5\"}"), None);
+        assert_eq!(parse_operand("{}"), None);
+    }
+
+    #[test]
+    fn requests_share_x() {
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+        let add = handle("POST", "/add", "{\"operand\": 5}", 0, &server);
+        assert_eq!(add, ("200 OK", "{\"x\": 5}".to_string()));
+
+        let x = handle("GET", "/x", "", 0, &server);
+        assert_eq!(x, ("200 OK", "{\"x\": 5}".to_string()));
+
+        assert_eq!(
+            handle("GET", "/add", "", 0, &server).0,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(handle("GET", "/nope", "", 0, &server).0, "404 Not Found");
+        assert_eq!(handle("POST", "/add", "5", 0, &server).0, "400 Bad Request");
+    }
+}
@@ -1,146 +1,3799 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{split, BufReader, WriteHalf};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch, OwnedRwLockWriteGuard, RwLock as AsyncRwLock, RwLockReadGuard};
 
-// We are writing a calculation system. You connect via TCP and send commands to modify some global state.
+use alias::{expand_aliases, validate_alias};
+use config::Config;
+use error::{CommandError, ErrorStyle};
+use expression::{InvalidOperand, Operand};
+use framing::{Framer, LineFramer};
+use history::{sparkline, Change, History, HistoryEntry};
+use mirror::XMirror;
+use number::{parse_number, InvalidNumber};
+use operation::Operation;
+use priority::{Prioritized, Priority};
+use session::SessionStore;
+use shard::Shards;
+use snapshot::Snapshot;
+use suggest::suggest_command;
+use tokenize::tokenize;
+use transaction::{Isolation, Transaction};
+use transcript::Transcript;
+
+mod alias;
+mod config;
+#[cfg(feature = "lock-stats")]
+mod contention;
+mod error;
+mod expression;
+mod framing;
+mod history;
+mod http;
+mod mirror;
+mod number;
+mod operation;
+mod priority;
+mod script;
+mod session;
+mod shard;
+mod snapshot;
+mod suggest;
+mod tokenize;
+mod transaction;
+mod transcript;
+mod udp;
+
+// We are writing a calculation system. You connect via TCP (port 4673 unless started with --port) and
+// send commands to modify some global state.
+// Commands can also be sent as UDP datagrams if the server is started with --udp-port, see udp.rs.
+// There is also a small HTTP facade over the arithmetic if started with --http-port, see http.rs.
+// A file of commands given with --script is run against the global state at startup, see script.rs.
+// With --shards, X and the registers are split into independent shards, one per group of connections, see shard.rs.
+// With --transcript, every command line received over TCP is appended to a file, see transcript.rs.
+// MODE FASTREAD lets SHOW read a copy of X without taking the lock, see mirror.rs.
 // There is a global variable X and there are commands to modify it.
 // The commands are:
-// ADD 123
+// ADD 123 - also accepts several operands, e.g. ADD 1 2 3, which are all added or none are
 // SUBTRACT 123
-// POWER 2.5 - raise X to power
+// POWER 2.5 - raise X to power; a negative X can only be raised to a whole number power
+// The operands of the arithmetic commands can also be worked out from X, e.g. ADD X*0.1, see expression.rs.
+// FMA 2 3 - set X to X * 2 + 3, rounded only once at the end
+// DIVMOD 7 - divide X by operand, keeping the quotient in X and reporting the remainder
+// PERCENT 15 - set X to 15% of X
+// INCREASE 15 / DECREASE 15 - change X by 15%
+// INCREMENT / DECREMENT - change X by 1
+// ABS - replaces X with its absolute value
+// SAMPLE 5 - adds a value to this connection's sample set
+// MEAN - sets X to the mean of the samples
+// VARIANCE / STDDEV - sets X to the population variance or standard deviation of the samples
+// PRODUCT - sets X to the product of the samples; an error without samples, as with MEAN, rather than 1
+// COUNT / CLEAR - shows the number of samples or removes them all
+// SET 5 - sets X to 5 regardless of its current value
+// PREVIEW ADD 5 - shows what X would become after an arithmetic command, without changing X
 // SHOW - displays value of X
+// BASE 16 - makes SHOW display a whole number X in base 16, e.g. 0xff; also 2, 8 and 10
+// RAW - displays X exactly, as the shortest decimal that round-trips and as the bits of the f64
+// CONNECTIONS - displays the number of clients connected over TCP
+// STATS - displays how often commands had to wait for the lock on X; needs the lock-stats feature
+// HISTORY - lists the modifications of X made by this connection
+// DELTA - displays how much the most recent modification listed by HISTORY changed X
+// LAST - displays the most recent modification listed by HISTORY and the value it left X at
+// UNDO - restores X to what it was before the most recent modification listed by HISTORY
+// GRAPH - draws a sparkline of the values X has had after this connection's modifications
+// STORE r1 / RECALL r1 - copies X to or from the named register r1
+// SHOW ALL - displays all named registers
+// EXPORT - returns X and all registers as a single base64 token; IMPORT token replaces them with it
+// Neither is available with more than one shard, as a snapshot only holds a single X and registers.
+// HELP - lists the commands with examples
+// NOP - does nothing but reply OK, so a script can tell when everything before it has been handled
+// NOP abc - replies OK abc, so a client can tell which NOP the OK belongs to, see src/bin/calculon-client
+// SESSION - returns a token that can be used to RESUME this connection's state after a disconnect
+// RESUME abc123 - takes over the state of a disconnected session
+// DELAY 100 - waits 100 milliseconds before replying; only available with --enable-delay
+// MODE PRECISION 2 - shows 2 decimal places in responses on this connection; MODE PRECISION OFF reverts
+// MODE CURRENCY $ - shows values in responses as currency, e.g. $1,234.56; MODE CURRENCY OFF reverts
+// MODE ERRORS PROSE - shows errors as "ERROR: message" rather than "ERROR ECODE message"; CODES reverts
+// MODE SEPARATORS ON - accepts operands with thousands separators, e.g. ADD 1,000 or ADD 1_000; OFF reverts
+// MODE AUTOSHOW 2 - also shows X after every 2nd modification of X on this connection; 0 disables
+// MODE FASTREAD ON - makes SHOW read X without waiting for the lock, possibly a hair stale; OFF reverts
+// MODE RESET - puts all of this connection's MODE, BASE and PRIORITY settings back to their defaults
+// BEGIN / COMMIT / ROLLBACK - groups arithmetic commands into a transaction applied to X all at once,
+// with the commands of other connections waiting until it ends, see transaction.rs
+// MODE ISOLATION OPTIMISTIC - lets others carry on during a transaction, making COMMIT fail if X was
+// changed since BEGIN
+// PRIORITY HIGH - lets this connection's commands go ahead of others waiting for X; PRIORITY NORMAL reverts
+// ALIAS inc=ADD 1 - makes "inc" expand to "ADD 1" on this connection; UNALIAS inc removes it
+// ALIASES - lists this connection's aliases
+// JSON ADD 5 - runs a single command and replies with its outcome as JSON, e.g. {"x": 12}; refused
+// for commands that report more than X, such as HELP or DIVMOD
+// TOTAL - displays the sum of X over all shards, read as one consistent snapshot
+// VERSION - displays the server version, protocol version and features, e.g. VERSION 0.1.0 protocol=1 features=json,...
+// SHUTDOWN abc123 - stops the server once the connections are closed; needs the token given with --admin-token
+
+// Number of values drawn by GRAPH if the client does not specify it.
+const DEFAULT_GRAPH_WIDTH: usize = 40;
+
+// Upper limit for DELAY, so a client cannot park a connection task indefinitely.
+const MAX_DELAY_MILLIS: u64 = 10_000;
+
+// Highest number of decimal places accepted by MODE PRECISION. Beyond this an f64 has no more
+// significant digits to show for typical values.
+const MAX_PRECISION: usize = 15;
+
+// Responses waiting for a connection's writer task. Once full, the connection stops reading commands
+// until the client catches up on reading responses.
+const RESPONSE_QUEUE_LENGTH: usize = 64;
+
+// Environment variables starting with this preload a register at startup, e.g. CALCULON_REG_TAXRATE=0.08
+// creates the register TAXRATE with the value 0.08.
+const REGISTER_ENV_PREFIX: &str = "CALCULON_REG_";
+
+// Commands that modify shared state but cannot be part of a transaction.
+const NON_TRANSACTIONAL_COMMANDS: &[&str] = &[
+    "DIVMOD", "MEAN", "VARIANCE", "STDDEV", "PRODUCT", "STORE", "RECALL", "RESUME", "UNDO",
+    "IMPORT",
+];
+
+// Version of the command protocol, as reported by VERSION. Bumped whenever an existing command
+// changes in a way that clients can notice. New commands are announced as features instead.
+const PROTOCOL_VERSION: u32 = 1;
+
+// Groups of commands that every server has, as reported by VERSION.
+const BASE_FEATURES: &[&str] = &[
+    "json",
+    "transactions",
+    "registers",
+    "snapshots",
+    "samples",
+    "history",
+    "sessions",
+    "aliases",
+    "expressions",
+    "priority",
+    "fastread",
+    "shards",
+];
+
+// Usage example and description of every command, as listed by HELP.
+// The greeting is made up of the usage examples alone.
+const COMMANDS: &[(&str, &str)] = &[
+    ("ADD 1.23", "X += 1.23 (ADD 1 2 3 adds all the operands)"),
+    ("SUBTRACT 1.23", "X -= 1.23"),
+    (
+        "POWER 2",
+        "X ^= 2 (a negative X only allows whole number powers)",
+    ),
+    ("FMA 2 3", "X = X * 2 + 3, with a single rounding"),
+    (
+        "DIVMOD 1.23",
+        "X /= 1.23, keeping the quotient in X and reporting the remainder",
+    ),
+    ("PERCENT 15", "set X to 15% of X"),
+    ("INCREASE 15", "increase X by 15%"),
+    ("DECREASE 15", "decrease X by 15%"),
+    ("INCREMENT", "X += 1"),
+    ("DECREMENT", "X -= 1"),
+    ("ABS", "set X to its absolute value"),
+    ("SAMPLE 1.23", "add 1.23 to the sample set"),
+    ("MEAN", "set X to the mean of the sample set"),
+    (
+        "VARIANCE",
+        "set X to the population variance of the sample set",
+    ),
+    (
+        "STDDEV",
+        "set X to the population standard deviation of the sample set",
+    ),
+    (
+        "PRODUCT",
+        "set X to the product of the sample set (an error if there are no samples)",
+    ),
+    ("COUNT", "display the number of samples"),
+    ("CLEAR", "remove all samples"),
+    ("SET 1.23", "X = 1.23"),
+    (
+        "PREVIEW ADD 5",
+        "display what X would be after an arithmetic command, without changing it",
+    ),
+    ("SHOW", "display X"),
+    (
+        "BASE 16",
+        "make SHOW display whole numbers in base 2, 8, 10 or 16",
+    ),
+    (
+        "RAW",
+        "display X at full precision and as the hex bits of the f64",
+    ),
+    (
+        "CONNECTIONS",
+        "display the number of clients connected over TCP",
+    ),
+    (
+        "STATS",
+        "display how often and how long commands had to wait for the lock on X",
+    ),
+    ("HISTORY", "list this connection's recent changes to X"),
+    (
+        "DELTA",
+        "display the change in X made by this connection's most recent change",
+    ),
+    (
+        "LAST",
+        "display this connection's most recent change to X and the resulting value",
+    ),
+    ("UNDO", "revert this connection's most recent change to X"),
+    (
+        "GRAPH 20",
+        "draw the last 20 (default 40) values of X after this connection's changes",
+    ),
+    ("STORE r1", "store X in the register r1"),
+    ("RECALL r1", "set X to the value of the register r1"),
+    ("SHOW ALL", "display all registers"),
+    ("EXPORT", "return X and all registers as a snapshot token"),
+    (
+        "IMPORT abc123",
+        "replace X and all registers with those in a snapshot token from EXPORT",
+    ),
+    ("HELP", "display this list"),
+    ("NOP", "do nothing and reply OK"),
+    ("NOP abc", "do nothing and reply OK abc"),
+    (
+        "SESSION",
+        "get a token for resuming this connection's state later",
+    ),
+    (
+        "RESUME abc123",
+        "restore the state of a disconnected session",
+    ),
+    (
+        "MODE PRECISION 2",
+        "show 2 (0-15) decimal places in responses, or OFF for default formatting",
+    ),
+    (
+        "MODE CURRENCY $",
+        "show values in responses as currency like $1,234.56, or OFF for number formatting",
+    ),
+    (
+        "MODE ERRORS PROSE",
+        "show errors as plain prose instead of with an error code, or CODES to revert",
+    ),
+    (
+        "MODE SEPARATORS ON",
+        "accept operands like 1,000 or 1_000, or OFF to stop",
+    ),
+    (
+        "MODE AUTOSHOW 2",
+        "also show X after every 2nd modification of X, or 0 to stop",
+    ),
+    (
+        "MODE FASTREAD ON",
+        "make SHOW read X without waiting for the lock, possibly missing a change in progress, or OFF to stop",
+    ),
+    (
+        "MODE RESET",
+        "put all MODE, BASE and PRIORITY settings back to their defaults",
+    ),
+    (
+        "BEGIN",
+        "start a transaction; arithmetic is applied to a private copy of X until COMMIT",
+    ),
+    ("COMMIT", "apply the transaction to X"),
+    ("ROLLBACK", "discard the transaction"),
+    (
+        "MODE ISOLATION OPTIMISTIC",
+        "let others change X during a transaction, making COMMIT fail if they did (default SERIALIZABLE makes them wait)",
+    ),
+    (
+        "ALIAS inc=ADD 1",
+        "make \"inc\" expand to \"ADD 1\" on this connection",
+    ),
+    ("UNALIAS inc", "remove the alias \"inc\""),
+    ("ALIASES", "list this connection's aliases"),
+    (
+        "JSON ADD 5",
+        "run one command and reply with the resulting X (or the error) as JSON; not for commands reporting more than X",
+    ),
+    (
+        "PRIORITY HIGH",
+        "let this connection go ahead of others waiting for X, or NORMAL to revert",
+    ),
+    ("TOTAL", "display the sum of X over all shards"),
+    (
+        "VERSION",
+        "display the server and protocol versions and the features this server has",
+    ),
+    (
+        "SHUTDOWN abc123",
+        "stop the server, closing all connections; needs the token from --admin-token",
+    ),
+];
+
+// The features reported by VERSION: those of every server, then those of the Cargo features this
+// one was built with, then those enabled on its command line.
+fn features(config: &Config) -> Vec<&'static str> {
+    let mut features = BASE_FEATURES.to_vec();
+
+    if cfg!(feature = "lock-stats") {
+        features.push("lock-stats");
+    }
+
+    if config.enable_delay {
+        features.push("delay");
+    }
+
+    if config.admin_token.is_some() {
+        features.push("shutdown");
+    }
+
+    features
+}
+
+// The names of all built-in commands, without example arguments.
+fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMANDS
+        .iter()
+        .filter_map(|(usage, _)| usage.split_whitespace().next())
+}
+
+// Commands whose effect lives in the connection state beyond the command itself, which makes them
+// useless where that state is thrown away after every command, as for UDP datagrams. RESUME is worse
+// than useless there, as it takes the session out of the store along with its state.
+const CONNECTION_COMMANDS: &[&str] = &["SESSION", "RESUME", "BEGIN", "COMMIT", "ROLLBACK"];
+
+// Commands whose reply is about more than X, e.g. the DIVMOD remainder, or about something else
+// entirely. The reply to a command with the JSON prefix only holds X, so they are refused with it.
+const NON_JSON_COMMANDS: &[&str] = &[
+    "DIVMOD",
+    "SAMPLE",
+    "COUNT",
+    "PREVIEW",
+    "RAW",
+    "CONNECTIONS",
+    "STATS",
+    "HISTORY",
+    "DELTA",
+    "LAST",
+    "GRAPH",
+    "SHOW ALL",
+    "EXPORT",
+    "HELP",
+    "NOP",
+    "SESSION",
+    "ALIASES",
+    "TOTAL",
+    "VERSION",
+];
+
+// Shown in the log and transcript in place of the argument of SHUTDOWN.
+const REDACTED: &str = "<redacted>";
+
+// In front of any single command, e.g. JSON ADD 5, for that one response to be JSON.
+const JSON_PREFIX: &str = "JSON ";
 
 #[derive(Debug, Default)]
 struct GlobalState {
+    // Only ever changed with set_x.
     x: f64,
+
+    // Shared with the server, which reads it for MODE FASTREAD without taking the lock.
+    x_mirror: Arc<XMirror>,
+
+    // Named values that X can be stored to and recalled from. Sorted by name for stable listings.
+    registers: BTreeMap<String, f64>,
+}
+
+impl GlobalState {
+    // Keeps the mirror in step with X. The caller holds the write lock, so writes to the mirror
+    // happen in the same order as those to X.
+    fn set_x(&mut self, x: f64) {
+        self.x = x;
+        self.x_mirror.store(x);
+    }
+}
+
+// Everything that is shared between all connections, whichever transport they arrive on.
+#[derive(Debug)]
+struct Server {
+    // Just the one shard unless started with --shards.
+    shards: Shards<GlobalState>,
+    // The mirror of X in each shard, in shard order.
+    x_mirrors: Vec<Arc<XMirror>>,
+    // Kept by a serializable transaction for as long as it is open, in shard order. Every command
+    // on the shard has to get in first, see enter_shard and hold_shard.
+    shard_gates: Vec<Arc<AsyncRwLock<()>>>,
+    sessions: SessionStore,
+    config: Config,
+
+    // Number of TCP connections currently open, which can be watched for changes.
+    connections: watch::Sender<usize>,
+
+    transcript: Option<Transcript>,
+
+    // Set once SHUTDOWN has been accepted, which stops the server accepting TCP connections and
+    // has the open ones close after their current command.
+    shutdown: watch::Sender<bool>,
+}
+
+impl Server {
+    // Every shard starts out with X = 0 and the given registers.
+    fn new(
+        config: Config,
+        registers: BTreeMap<String, f64>,
+        transcript: Option<Transcript>,
+    ) -> Self {
+        let mut x_mirrors = Vec::new();
+
+        let shards = Shards::new(config.shards, || {
+            let x_mirror = Arc::new(XMirror::new(0.0));
+            x_mirrors.push(x_mirror.clone());
+
+            GlobalState {
+                x: 0.0,
+                x_mirror,
+                registers: registers.clone(),
+            }
+        });
+
+        Self {
+            shard_gates: (0..shards.count())
+                .map(|_| Arc::new(AsyncRwLock::new(())))
+                .collect(),
+            shards,
+            x_mirrors,
+            sessions: SessionStore::new(config.session_ttl),
+            config,
+            connections: watch::channel(0).0,
+            transcript,
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    // The shard the connection works on, at the connection's priority.
+    fn global_state(&self, connection_state: &ConnectionState) -> Prioritized<'_, GlobalState> {
+        self.shards
+            .get(connection_state.shard)
+            .with_priority(connection_state.settings.priority)
+    }
+
+    // X of the shard the connection works on, read without taking the lock.
+    fn fast_read_x(&self, connection_state: &ConnectionState) -> f64 {
+        self.x_mirrors[connection_state.shard].load()
+    }
+
+    // Waits for a serializable transaction that holds the shard to end, and keeps a new one from
+    // taking the shard until the guard is dropped. Commands on the same shard still run side by side.
+    async fn enter_shard(&self, shard: usize) -> RwLockReadGuard<'_, ()> {
+        self.shard_gates[shard].read().await
+    }
+
+    // Waits for the commands on the shard to finish, then keeps everyone else out of the shard
+    // until the guard is dropped.
+    async fn hold_shard(&self, shard: usize) -> OwnedRwLockWriteGuard<()> {
+        self.shard_gates[shard].clone().write_owned().await
+    }
+}
+
+// Counts a TCP connection for as long as it is alive. The count is decremented on drop, so it
+// stays correct however the connection task ends, including errors and panics.
+struct ConnectionGuard<'a> {
+    server: &'a Server,
+    peer: SocketAddr,
+}
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(server: &'a Server, peer: SocketAddr) -> Self {
+        let mut connections = 0;
+        server.connections.send_modify(|count| {
+            *count += 1;
+            connections = *count;
+        });
+        println!("Client {peer} connected, {connections} connections open");
+
+        Self { server, peer }
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut connections = 0;
+        self.server.connections.send_modify(|count| {
+            *count -= 1;
+            connections = *count;
+        });
+        println!(
+            "Client {} disconnected, {connections} connections open",
+            self.peer
+        );
+    }
+}
+
+// How a connection wants its commands handled, as chosen with MODE, BASE and PRIORITY. The defaults
+// are what a new connection starts out with, which MODE RESET goes back to.
+#[derive(Debug, Default, Clone)]
+struct ConnectionSettings {
+    // Number of decimal places in responses. None means Rust's default f64 formatting.
+    precision: Option<usize>,
+
+    // Currency symbol to show values with. Takes priority over the precision.
+    currency: Option<String>,
+
+    // Base other than 10 that SHOW displays whole numbers in.
+    base: Option<u32>,
+
+    isolation: Isolation,
+
+    error_style: ErrorStyle,
+
+    // Whether operands may group their digits with thousands separators. Off by default, as a
+    // comma is the decimal point in many locales and 1,5 must not be mistaken for 15.
+    thousands_separators: bool,
+
+    // After every this many modifications of X by this connection, the response also shows X.
+    // 0 disables it.
+    autoshow_every: u64,
+
+    // How urgently this connection's commands take their turn at the shared state.
+    priority: Priority,
+
+    // Whether SHOW reads the mirror of X rather than taking the lock.
+    fast_read: bool,
+}
+
+// State that belongs to a single connection rather than being shared by everyone.
+// It survives a disconnect if the client asked for a SESSION token.
+#[derive(Debug, Default)]
+struct ConnectionState {
+    session_token: Option<String>,
+    samples: Vec<f64>,
+    aliases: HashMap<String, String>,
+
+    settings: ConnectionSettings,
+
+    transaction: Option<Transaction>,
+    modifications_since_autoshow: u64,
+
+    // Set when the state only lives for a single command, as for a UDP datagram.
+    connectionless: bool,
+
+    // Index of the shard of the global state that this connection works on.
+    shard: usize,
+
+    // Where the commands come from, for the log. None for the startup script.
+    peer: Option<SocketAddr>,
+
+    history: History,
+}
+
+impl ConnectionState {
+    // All numeric values in responses go through here, so they respect the connection's MODE settings.
+    fn format_number(&self, value: f64) -> String {
+        if let Some(symbol) = &self.settings.currency {
+            return format_currency(value, symbol);
+        }
+
+        match self.settings.precision {
+            Some(precision) => format!("{value:.precision$}"),
+            None => value.to_string(),
+        }
+    }
+
+    // All operands go through here, so they respect MODE SEPARATORS.
+    fn parse_number(&self, token: &str) -> Result<f64, InvalidNumber> {
+        parse_number(token, self.settings.thousands_separators)
+    }
+
+    // Like parse_number, for the operands of arithmetic commands, which may also refer to X.
+    fn parse_operand(&self, token: &str) -> Result<Operand, InvalidOperand> {
+        Operand::parse(token, self.settings.thousands_separators)
+    }
+
+    // X as SHOW displays it, respecting BASE as well as the MODE settings.
+    fn format_x(&self, value: f64) -> String {
+        let Some(base) = self.settings.base else {
+            return format!("X = {}\r\n", self.format_number(value));
+        };
+
+        match format_in_base(value, base) {
+            Some(digits) => format!("X = {digits}\r\n"),
+            None => format!(
+                "X = {} (not a 64-bit whole number, shown in base 10)\r\n",
+                self.format_number(value)
+            ),
+        }
+    }
+
+    // All error responses go through here, so they respect MODE ERRORS.
+    fn format_error(&self, error: &CommandError) -> String {
+        self.settings.error_style.format(error)
+    }
+}
+
+// A whole number in base 2, 8 or 16 with the usual prefix, e.g. -0x1f. Returns None if the value is
+// not a whole number or does not fit in an i64, as there is no exact way to show it then.
+fn format_in_base(value: f64, base: u32) -> Option<String> {
+    // i64::MAX itself is not representable as an f64, so the upper bound is exclusive.
+    if value.fract() != 0.0 || !(-(2f64.powi(63))..2f64.powi(63)).contains(&value) {
+        return None;
+    }
+
+    let value = value as i64;
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+
+    match base {
+        2 => Some(format!("{sign}0b{magnitude:b}")),
+        8 => Some(format!("{sign}0o{magnitude:o}")),
+        16 => Some(format!("{sign}0x{magnitude:x}")),
+        _ => None,
+    }
+}
+
+// Two decimal places with the whole part in groups of three, e.g. -$1,234.56.
+// NaN and infinities are not amounts of money, so they are shown as they are.
+fn format_currency(value: f64, symbol: &str) -> String {
+    if !value.is_finite() {
+        return value.to_string();
+    }
+
+    let digits = format!("{:.2}", value.abs());
+    let (whole, fraction) = digits
+        .split_once('.')
+        .expect("two decimal places were requested");
+
+    let mut grouped = String::new();
+
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+
+        grouped.push(digit);
+    }
+
+    // Values that round to zero are shown without a minus sign.
+    let is_negative = value < 0.0 && digits.chars().any(|c| matches!(c, '1'..='9'));
+    let sign = if is_negative { "-" } else { "" };
+
+    format!("{sign}{symbol}{grouped}.{fraction}")
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let global_state = Arc::new(Mutex::new(GlobalState::default()));
-    let listener = TcpListener::bind("127.0.0.1:4673").await?;
+    let config = Config::from_args()?;
+
+    let registers = registers_from_env(env::vars_os().map(|(name, value)| {
+        (
+            name.to_string_lossy().into_owned(),
+            value.to_string_lossy().into_owned(),
+        )
+    }));
+
+    let transcript = match &config.transcript {
+        Some(path) => Some(
+            Transcript::open(path)
+                .await
+                .map_err(|e| format!("Failed to open transcript {}: {e}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let server = Arc::new(Server::new(config, registers, transcript));
+
+    if let Some(script) = &server.config.script {
+        script::run(script, &server, server.config.strict_script).await?;
+    }
+
+    if let Some(idle_grace) = server.config.idle_grace {
+        tokio::spawn(watch_idle(server.clone(), idle_grace));
+    }
+
+    if let Some(udp_port) = server.config.udp_port {
+        let socket = UdpSocket::bind(("127.0.0.1", udp_port)).await?;
+        tokio::spawn(udp::serve(socket, server.clone()));
+    }
+
+    if let Some(http_port) = server.config.http_port {
+        let listener = TcpListener::bind(("127.0.0.1", http_port)).await?;
+        tokio::spawn(http::serve(listener, server.clone()));
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", server.config.port)).await?;
+    let mut shutdown = server.shutdown.subscribe();
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let global_state = global_state.clone();
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // The sender lives in the server, which we hold on to, so waiting cannot fail.
+            _ = shutdown.wait_for(|&requested| requested) => break,
+        };
+
+        let server = server.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = process_request(stream, global_state).await {
-                eprintln!("Failed to process request; error = {}", e);
+            if let Err(e) = process_request(stream, peer, server, LineFramer).await {
+                eprintln!("Failed to process request from {peer}; error = {}", e);
             }
         });
     }
+
+    shut_down(listener, &server).await;
+    Ok(())
+}
+
+// Stops accepting TCP connections, waits for the open ones to finish their current command and
+// close, then writes out the rest of the transcript. UDP and HTTP requests still being handled are
+// abandoned, as those transports have no connections to close.
+async fn shut_down(listener: TcpListener, server: &Server) {
+    drop(listener);
+
+    let mut connections = server.connections.subscribe();
+    let open = *connections.borrow();
+    println!("Shutting down, waiting for {open} connections to close");
+    _ = connections.wait_for(|&count| count == 0).await;
+
+    if let Some(transcript) = &server.transcript {
+        transcript.flush().await;
+    }
+
+    println!("Server shut down");
+}
+
+// Logs when the last TCP client has been gone for the grace period without anyone connecting,
+// once per quiet period. This is where work best done while nobody is connected belongs, such as
+// persisting or compacting state.
+async fn watch_idle(server: Arc<Server>, grace: Duration) {
+    // The sender lives in the server, which we hold on to, so waiting cannot fail.
+    let mut connections = server.connections.subscribe();
+
+    loop {
+        wait_until_idle(&mut connections, grace).await;
+        println!("Server idle, no clients connected for {grace:?}");
+    }
+}
+
+// Returns once the connection count has gone from above zero to zero and stayed there for the
+// grace period. The server only counts as idle once it has had clients, not right after starting up.
+async fn wait_until_idle(connections: &mut watch::Receiver<usize>, grace: Duration) {
+    loop {
+        _ = connections.wait_for(|&count| count > 0).await;
+        _ = connections.wait_for(|&count| count == 0).await;
+
+        let reconnected = tokio::time::timeout(grace, connections.wait_for(|&count| count > 0))
+            .await
+            .is_ok();
+
+        if !reconnected {
+            return;
+        }
+    }
 }
 
-async fn process_request(
+async fn process_request<F: Framer>(
     stream: TcpStream,
-    global_state: Arc<Mutex<GlobalState>>,
+    peer: SocketAddr,
+    server: Arc<Server>,
+    framer: F,
 ) -> Result<(), Box<dyn Error>> {
-    let (read_stream, mut write_stream) = split(stream);
+    let _connection = ConnectionGuard::new(&server, peer);
 
-    let reader = BufReader::new(read_stream);
-    let mut lines = reader.lines();
+    let mut connection_state = ConnectionState {
+        history: History::new(server.config.history_size),
+        shard: server.shards.index_for(&peer),
+        peer: Some(peer),
+        ..Default::default()
+    };
 
-    write_stream
-        .write_all("ADD 1.23/SUBTRACT 1.23/POWER 1.23/SHOW\r\n".as_bytes())
-        .await?;
+    let result = process_commands(stream, peer, &server, &mut connection_state, framer).await;
 
-    while let Some(line) = lines.next_line().await? {
-        println!("Received line: {}", line);
+    // A serializable transaction would keep the shard held for as long as the session is stored.
+    if connection_state
+        .transaction
+        .as_ref()
+        .is_some_and(Transaction::holds_shard)
+    {
+        connection_state.transaction = None;
+    }
 
-        let words: Vec<_> = line.split_whitespace().collect();
+    // Whichever way the connection ended, the session can be picked up again if it has a token.
+    if let Some(token) = connection_state.session_token.clone() {
+        server.sessions.save(token, connection_state);
+    }
 
-        if words.is_empty() {
-            continue;
-        }
+    result
+}
 
-        match words[0] {
-            "ADD" => {
-                if words.len() != 2 {
-                    eprintln!("ADD command requires exactly one argument.");
-                    continue;
-                }
+async fn process_commands<F: Framer>(
+    stream: TcpStream,
+    peer: SocketAddr,
+    server: &Server,
+    connection_state: &mut ConnectionState,
+    framer: F,
+) -> Result<(), Box<dyn Error>> {
+    let (read_stream, write_stream) = split(stream);
 
-                let operand = words[1].parse::<f64>()?;
-                let new_value = add(operand, &global_state);
-                write_stream
-                    .write_all(format!("X += {operand} = {new_value}\r\n").as_bytes())
-                    .await?;
-            }
-            "SUBTRACT" => {
-                if words.len() != 2 {
-                    eprintln!("SUBTRACT command requires exactly one argument.");
-                    continue;
-                }
+    let mut reader = BufReader::new(read_stream);
 
-                let operand = words[1].parse::<f64>()?;
-                let new_value = subtract(operand, &global_state);
-                write_stream
-                    .write_all(format!("X -= {operand} = {new_value}\r\n").as_bytes())
-                    .await?;
-            }
-            "POWER" => {
-                if words.len() != 2 {
-                    eprintln!("POWER command requires exactly one argument.");
-                    continue;
-                }
+    // Everything written to the client goes through a single writer task, so responses from
+    // different sources can never interleave in the middle of a line.
+    let (responses_tx, responses_rx) = mpsc::channel::<String>(RESPONSE_QUEUE_LENGTH);
+    let writer = tokio::spawn(write_responses(framer.clone(), write_stream, responses_rx));
+
+    let usage: Vec<_> = COMMANDS.iter().map(|(usage, _)| *usage).collect();
+    let mut writer_alive = responses_tx
+        .send(format!("{}\r\n", usage.join("/")))
+        .await
+        .is_ok();
 
-                let operand = words[1].parse::<f64>()?;
-                let new_value = power(operand, &global_state);
-                write_stream
-                    .write_all(format!("X ^= {operand} = {new_value}\r\n").as_bytes())
-                    .await?;
+    let mut shutdown = server.shutdown.subscribe();
+
+    while writer_alive {
+        let read = tokio::select! {
+            read = framer.read_command(&mut reader) => read,
+            // A command that was only partly received is dropped along with the connection.
+            _ = shutdown.wait_for(|&requested| requested) => {
+                println!("Closing the connection to client {peer} for the shutdown");
+                break;
             }
-            "SHOW" => {
-                if words.len() != 1 {
-                    eprintln!("SHOW command requires exactly zero arguments.");
-                    continue;
-                }
+        };
 
-                let value = show(&global_state);
-                write_stream
-                    .write_all(format!("X = {value}\r\n").as_bytes())
-                    .await?;
+        let line = match read {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                println!("Client {peer} closed the connection");
+                break;
             }
-            _ => {
-                write_stream
-                    .write_all(format!("Unknown command: {}\r\n", words[0]).as_bytes())
-                    .await?;
+            // The connection is unusable, but that is the network's fault rather than ours.
+            Err(e) => {
+                eprintln!("Warning: reading from client {peer} failed: {e}");
+                break;
             }
+        };
+
+        let redacted = redact(&line);
+        println!("Received line: {}", redacted);
+
+        if let Some(transcript) = &server.transcript {
+            transcript.record(peer, &redacted);
+        }
+
+        let response = execute_command(&line, server, connection_state).await;
+
+        if !response.is_empty() {
+            // If the writer is gone, it failed to write and will tell us why when we join it.
+            writer_alive = responses_tx.send(response).await.is_ok();
         }
     }
 
+    // Closing the channel lets the writer finish sending whatever is still queued, then exit.
+    drop(responses_tx);
+    writer.await??;
+
+    Ok(())
+}
+
+async fn write_responses<F: Framer>(
+    framer: F,
+    mut write_stream: WriteHalf<TcpStream>,
+    mut responses_rx: mpsc::Receiver<String>,
+) -> std::io::Result<()> {
+    while let Some(response) = responses_rx.recv().await {
+        framer.write_response(&mut write_stream, &response).await?;
+    }
+
     Ok(())
 }
 
-fn add(value: f64, global_state: &Arc<Mutex<GlobalState>>) -> f64 {
-    let mut guarded_state = global_state.as_ref().lock().unwrap();
-    let new_value = guarded_state.x + value;
-    guarded_state.x = new_value;
+// Runs a single command line and returns the response to send back, which is empty for lines that
+// deserve no response. Shared by all transports, each of which owns the connection state it passes in.
+async fn execute_command(
+    line: &str,
+    server: &Server,
+    connection_state: &mut ConnectionState,
+) -> String {
+    match run_command(line, server, connection_state).await {
+        Ok(response) => response,
+        // Only this one response is JSON, the connection's responses stay text otherwise.
+        Err(e) if line.starts_with(JSON_PREFIX) => ErrorStyle::Json.format(&e),
+        Err(e) => connection_state.format_error(&e),
+    }
+}
+
+// Like execute_command, but a failed command is returned as the error rather than as the reply
+// reporting it, for callers that need to tell the two apart, such as scripts.
+async fn run_command(
+    line: &str,
+    server: &Server,
+    connection_state: &mut ConnectionState,
+) -> Result<String, CommandError> {
+    // A JSON prefix is checked for before anything else, so it works in front of aliases too.
+    let Some(command) = line.strip_prefix(JSON_PREFIX) else {
+        return execute_line(line, false, server, connection_state).await;
+    };
+
+    let response = execute_line(command, true, server, connection_state).await?;
 
-    new_value
+    Ok(json_response(&response, server, connection_state))
 }
 
-fn subtract(value: f64, global_state: &Arc<Mutex<GlobalState>>) -> f64 {
-    let mut guarded_state = global_state.as_ref().lock().unwrap();
-    let new_value = guarded_state.x - value;
-    guarded_state.x = new_value;
+// The outcome of a command run with the JSON prefix: the X the connection sees after it. Errors
+// never get here, see execute_command.
+fn json_response(response: &str, server: &Server, connection_state: &ConnectionState) -> String {
+    // No response stays no response.
+    if response.is_empty() {
+        return String::new();
+    }
+
+    // Inside a transaction, commands apply to the transaction's private X.
+    let x = match &connection_state.transaction {
+        Some(transaction) => transaction.x,
+        None => show(&server.global_state(connection_state)),
+    };
 
-    new_value
+    format!("{}\r\n", http::x_body(x))
 }
 
-fn power(value: f64, global_state: &Arc<Mutex<GlobalState>>) -> f64 {
-    let mut guarded_state = global_state.as_ref().lock().unwrap();
-    let new_value = guarded_state.x.powf(value);
-    guarded_state.x = new_value;
+// With `json`, commands whose reply would not fit into the JSON one are refused without being run.
+async fn execute_line(
+    line: &str,
+    json: bool,
+    server: &Server,
+    connection_state: &mut ConnectionState,
+) -> Result<String, CommandError> {
+    let line = expand_aliases(line, &connection_state.aliases);
+    let words = tokenize(&line).map_err(CommandError::Parse)?;
+
+    if words.is_empty() {
+        return Ok(String::new());
+    }
+
+    let command = match words.as_slice() {
+        ["SHOW", "ALL"] => "SHOW ALL",
+        _ => words[0],
+    };
+
+    if json && NON_JSON_COMMANDS.contains(&command) {
+        return Err(CommandError::Args(format!(
+            "{command} cannot be used with JSON, as its reply is about more than X"
+        )));
+    }
+
+    // Commands are expected to be near-instant. Anything slow points to lock contention or
+    // something blocking the async runtime, such as the std lock on X being held for too long.
+    let started = Instant::now();
+    let result = tokio::time::timeout(
+        server.config.command_timeout,
+        dispatch_command(&words, server, connection_state),
+    )
+    .await;
 
-    new_value
+    if let Some(warning) = slow_command_warning(words[0], started.elapsed(), &server.config) {
+        eprintln!("{warning}");
+    }
+
+    // Abandoning a command can only happen where it awaits, and commands only await before they
+    // touch any state (e.g. DELAY), so X is left as it was. Commands that never await cannot be
+    // interrupted at all; they have to keep their work bounded by validating their input instead.
+    result.unwrap_or(Err(CommandError::Timeout))
 }
 
-fn show(global_state: &Arc<Mutex<GlobalState>>) -> f64 {
-    let guarded_state = global_state.as_ref().lock().unwrap();
-    guarded_state.x
+// The warning to log for a command that took longer to handle than the configured threshold.
+fn slow_command_warning(command: &str, elapsed: Duration, config: &Config) -> Option<String> {
+    (elapsed > config.slow_command_threshold)
+        .then(|| format!("Slow command: {command} took {elapsed:?}"))
+}
+
+// Failures are returned as errors, which the caller formats in the connection's error style.
+async fn dispatch_command(
+    words: &[&str],
+    server: &Server,
+    connection_state: &mut ConnectionState,
+) -> Result<String, CommandError> {
+    // The connection's own serializable transaction already holds the shard. Starting one has to
+    // wait for the shard, which it cannot do while having entered it.
+    let holds_shard = match &connection_state.transaction {
+        Some(transaction) => transaction.holds_shard(),
+        None => {
+            words[0] == "BEGIN" && connection_state.settings.isolation == Isolation::Serializable
+        }
+    };
+
+    let _entered = if holds_shard {
+        None
+    } else {
+        Some(server.enter_shard(connection_state.shard).await)
+    };
+
+    let global_state = &server.global_state(connection_state);
+
+    // Inside a transaction, only the arithmetic operations are allowed to touch X,
+    // as only they can be applied to the transaction's private copy of X.
+    if connection_state.transaction.is_some() && NON_TRANSACTIONAL_COMMANDS.contains(&words[0]) {
+        return Err(CommandError::Busy(format!(
+            "{} cannot be used in a transaction",
+            words[0]
+        )));
+    }
+
+    if connection_state.connectionless && CONNECTION_COMMANDS.contains(&words[0]) {
+        return Err(CommandError::State(format!(
+            "{} needs a connection, it cannot be sent as a datagram",
+            words[0]
+        )));
+    }
+
+    let modifications = connection_state.history.modifications();
+
+    let mut response = match words[0] {
+        "ADD" => {
+            if words.len() < 2 {
+                return Err(CommandError::Args(
+                    "ADD command requires at least one argument".to_string(),
+                ));
+            }
+
+            // Every operand is parsed before X is touched, so a bad one leaves X as it was.
+            let operands = parse_operands(&words[1..], connection_state)?;
+
+            let add = |x| {
+                let operand: Result<f64, _> = operands.iter().map(|operand| operand.value(x)).sum();
+                operand.map(Operation::Add)
+            };
+
+            run_operation_on(add, global_state, connection_state)?
+        }
+        "SUBTRACT" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "SUBTRACT command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let subtract = |x| Ok(Operation::Subtract(operand.value(x)?));
+            run_operation_on(subtract, global_state, connection_state)?
+        }
+        "POWER" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "POWER command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let power = |x| Ok(Operation::Power(operand.value(x)?));
+            run_operation_on(power, global_state, connection_state)?
+        }
+        "FMA" => {
+            if words.len() != 3 {
+                return Err(CommandError::Args(
+                    "FMA command requires exactly two arguments".to_string(),
+                ));
+            }
+
+            let factor = connection_state.parse_operand(words[1])?;
+            let addend = connection_state.parse_operand(words[2])?;
+
+            let fma = |x| {
+                Ok(Operation::Fma {
+                    factor: factor.value(x)?,
+                    addend: addend.value(x)?,
+                })
+            };
+
+            run_operation_on(fma, global_state, connection_state)?
+        }
+        "DIVMOD" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "DIVMOD command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_number(words[1])?;
+
+            if operand == 0.0 {
+                return Err(CommandError::DivisionByZero);
+            }
+
+            let (change, remainder) = divmod(operand, global_state);
+            connection_state.history.record(&words.join(" "), change);
+
+            let quotient = connection_state.format_number(change.value);
+            let remainder = connection_state.format_number(remainder);
+            format!("X /= {operand}: quotient={quotient} remainder={remainder}\r\n")
+        }
+        "PERCENT" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "PERCENT command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let percent = |x| Ok(Operation::Percent(operand.value(x)?));
+            run_operation_on(percent, global_state, connection_state)?
+        }
+        "INCREASE" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "INCREASE command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let increase = |x| Ok(Operation::Increase(operand.value(x)?));
+            run_operation_on(increase, global_state, connection_state)?
+        }
+        "DECREASE" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "DECREASE command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let decrease = |x| Ok(Operation::Decrease(operand.value(x)?));
+            run_operation_on(decrease, global_state, connection_state)?
+        }
+        "INCREMENT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "INCREMENT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            run_operation(Operation::Add(1.0), global_state, connection_state)?
+        }
+        "DECREMENT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "DECREMENT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            run_operation(Operation::Subtract(1.0), global_state, connection_state)?
+        }
+        "ABS" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "ABS command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            run_operation(Operation::Abs, global_state, connection_state)?
+        }
+        "SET" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "SET command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_operand(words[1])?;
+            let set = |x| Ok(Operation::Set(operand.value(x)?));
+            run_operation_on(set, global_state, connection_state)?
+        }
+        "PREVIEW" => {
+            if words.len() < 2 {
+                return Err(CommandError::Args(
+                    "PREVIEW command requires a command to preview".to_string(),
+                ));
+            }
+
+            // Inside a transaction, the command would apply to the transaction's private X.
+            let x = match &connection_state.transaction {
+                Some(transaction) => transaction.x,
+                None => show(global_state),
+            };
+
+            let thousands_separators = connection_state.settings.thousands_separators;
+
+            let operation = Operation::parse(&words[1..], thousands_separators, x)?;
+
+            match operation.apply(x) {
+                Ok(value) => format!(
+                    "PREVIEW: X would be {}\r\n",
+                    connection_state.format_number(value)
+                ),
+                Err(e) => return Err(CommandError::Domain(e.to_string())),
+            }
+        }
+        "SAMPLE" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "SAMPLE command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let operand = connection_state.parse_number(words[1])?;
+            connection_state.samples.push(operand);
+            format!("SAMPLES = {}\r\n", connection_state.samples.len())
+        }
+        "MEAN" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "MEAN command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.samples.is_empty() {
+                return Err(CommandError::Domain("no samples".to_string()));
+            }
+
+            let change = replace_x(sample_mean(&connection_state.samples), global_state);
+            connection_state.history.record(&words.join(" "), change);
+
+            let new_value = connection_state.format_number(change.value);
+            format!("X = mean = {new_value}\r\n")
+        }
+        "VARIANCE" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "VARIANCE command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.samples.is_empty() {
+                return Err(CommandError::Domain("no samples".to_string()));
+            }
+
+            let change = replace_x(population_variance(&connection_state.samples), global_state);
+            connection_state.history.record(&words.join(" "), change);
+
+            let new_value = connection_state.format_number(change.value);
+            format!("X = variance = {new_value}\r\n")
+        }
+        "STDDEV" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "STDDEV command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.samples.is_empty() {
+                return Err(CommandError::Domain("no samples".to_string()));
+            }
+
+            let change = replace_x(
+                population_variance(&connection_state.samples).sqrt(),
+                global_state,
+            );
+            connection_state.history.record(&words.join(" "), change);
+
+            let new_value = connection_state.format_number(change.value);
+            format!("X = stddev = {new_value}\r\n")
+        }
+        "PRODUCT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "PRODUCT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            // Refused rather than taken to be 1, so a connection that forgot to SAMPLE does not
+            // silently set X to 1. The same as MEAN and the others.
+            if connection_state.samples.is_empty() {
+                return Err(CommandError::Domain("no samples".to_string()));
+            }
+
+            let product = connection_state.samples.iter().product::<f64>();
+
+            if !product.is_finite() {
+                return Err(CommandError::Domain(
+                    "product of the samples is too large".to_string(),
+                ));
+            }
+
+            let change = replace_x(product, global_state);
+            connection_state.history.record(&words.join(" "), change);
+
+            let new_value = connection_state.format_number(change.value);
+            format!("X = product = {new_value}\r\n")
+        }
+        "COUNT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "COUNT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            format!("SAMPLES = {}\r\n", connection_state.samples.len())
+        }
+        "CLEAR" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "CLEAR command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            connection_state.samples.clear();
+            "SAMPLES = 0\r\n".to_string()
+        }
+        "SHOW" if words.len() == 2 && words[1] == "ALL" => {
+            let registers = show_all(global_state);
+
+            if registers.is_empty() {
+                return Ok("No registers defined\r\n".to_string());
+            }
+
+            registers
+                .iter()
+                .map(|(name, value)| {
+                    format!("{name} = {}\r\n", connection_state.format_number(*value))
+                })
+                .collect()
+        }
+        "SHOW" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "SHOW command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let x = if connection_state.settings.fast_read {
+                server.fast_read_x(connection_state)
+            } else {
+                show(global_state)
+            };
+
+            connection_state.format_x(x)
+        }
+        "BASE" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "BASE command requires exactly one argument".to_string(),
+                ));
+            }
+
+            connection_state.settings.base = match words[1] {
+                "10" => None,
+                "2" | "8" | "16" => words[1].parse::<u32>().ok(),
+                _ => {
+                    let error = format!("unsupported base {}, use 2, 8, 10 or 16", words[1]);
+                    return Err(CommandError::Args(error));
+                }
+            };
+
+            "OK\r\n".to_string()
+        }
+        "HISTORY" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "HISTORY command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let mut response = String::new();
+
+            for entry in connection_state.history.entries() {
+                response.push_str(&format!(
+                    "{}: {} -> {}\r\n",
+                    entry.command,
+                    connection_state.format_number(entry.change.previous),
+                    connection_state.format_number(entry.change.value)
+                ));
+            }
+
+            response.push_str("END\r\n");
+            response
+        }
+        "DELTA" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "DELTA command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            // Before the first modification there is no change to report, rather than pretending
+            // the connection started out from X = 0 when another client may have changed it.
+            let Some(entry) = connection_state.history.last() else {
+                return Ok("DELTA: no changes yet\r\n".to_string());
+            };
+
+            let delta = entry.change.value - entry.change.previous;
+            format!(
+                "DELTA {}: {}\r\n",
+                entry.command,
+                connection_state.format_number(delta)
+            )
+        }
+        "LAST" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "LAST command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let Some(entry) = connection_state.history.last() else {
+                return Ok("LAST: none\r\n".to_string());
+            };
+
+            format!(
+                "LAST: {} -> {}\r\n",
+                entry.command,
+                connection_state.format_number(entry.change.value)
+            )
+        }
+        "UNDO" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "UNDO command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let Some(entry) = connection_state.history.undo() else {
+                return Err(CommandError::State("history exhausted".to_string()));
+            };
+
+            let new_value = connection_state.format_number(undo(&entry, global_state));
+            format!("UNDO {}: X = {new_value}\r\n", entry.command)
+        }
+        "GRAPH" => {
+            if words.len() > 2 {
+                return Err(CommandError::Args(
+                    "GRAPH command requires at most one argument".to_string(),
+                ));
+            }
+
+            let width = match words.get(1) {
+                Some(width) => parse_count(width, "width")?,
+                None => DEFAULT_GRAPH_WIDTH,
+            };
+
+            let values = connection_state.history.recent_values(width);
+
+            if values.is_empty() {
+                return Ok("GRAPH: no history\r\n".to_string());
+            }
+
+            let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+            format!(
+                "GRAPH: {} (min {}, max {})\r\n",
+                sparkline(&values),
+                connection_state.format_number(min),
+                connection_state.format_number(max)
+            )
+        }
+        "STORE" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "STORE command requires exactly one argument".to_string(),
+                ));
+            }
+
+            if !is_valid_register_name(words[1]) {
+                return Err(CommandError::Args(format!(
+                    "invalid register name {}",
+                    words[1]
+                )));
+            }
+
+            let value = connection_state.format_number(store(words[1], global_state));
+            format!("{} = {value}\r\n", words[1])
+        }
+        "RECALL" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "RECALL command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let Some(change) = recall(words[1], global_state) else {
+                return Err(CommandError::NotFound(format!(
+                    "no such register {}",
+                    words[1]
+                )));
+            };
+
+            connection_state.history.record(&words.join(" "), change);
+
+            let new_value = connection_state.format_number(change.value);
+            format!("X = {} = {new_value}\r\n", words[1])
+        }
+        "EXPORT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "EXPORT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            require_single_shard("EXPORT", server)?;
+
+            format!("EXPORT {}\r\n", export(global_state).encode())
+        }
+        "IMPORT" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "IMPORT command requires exactly one argument".to_string(),
+                ));
+            }
+
+            require_single_shard("IMPORT", server)?;
+
+            let snapshot = Snapshot::decode(words[1])
+                .map_err(|e| CommandError::Parse(format!("invalid snapshot: {e}")))?;
+
+            let register_count = snapshot.registers.len();
+            let change = import(snapshot, global_state);
+            connection_state.history.record("IMPORT", change);
+
+            format!(
+                "IMPORT: X = {}, {register_count} registers\r\n",
+                connection_state.format_number(change.value)
+            )
+        }
+        "RAW" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "RAW command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            // Deliberately not formatted per the connection's MODE, which is the whole point.
+            let value = show(global_state);
+            format!("X = {value:?} (bits 0x{:016x})\r\n", value.to_bits())
+        }
+        "CONNECTIONS" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "CONNECTIONS command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let connections = *server.connections.borrow();
+            format!("CONNECTIONS = {connections}\r\n")
+        }
+        "STATS" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "STATS command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            lock_stats(server)?
+        }
+        "SESSION" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "SESSION command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let token = connection_state
+                .session_token
+                .get_or_insert_with(SessionStore::new_token);
+            format!("SESSION {token}\r\n")
+        }
+        "RESUME" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "RESUME command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let Some(resumed_state) = server.sessions.resume(words[1]) else {
+                return Err(CommandError::NotFound(
+                    "unknown or expired session".to_string(),
+                ));
+            };
+
+            // Whatever this connection had before is discarded in favor of the resumed session.
+            // Returning right away, as the resumed history cannot be compared with the old one.
+            // Only the peer stays, as it is still this connection's.
+            *connection_state = ConnectionState {
+                peer: connection_state.peer,
+                ..resumed_state
+            };
+            return Ok(format!("RESUMED {}\r\n", words[1]));
+        }
+        "MODE" => {
+            if words.len() < 2 {
+                return Err(CommandError::Args(
+                    "MODE command requires a setting name".to_string(),
+                ));
+            }
+
+            match words[1] {
+                "PRECISION" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE PRECISION command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    if words[2] == "OFF" {
+                        connection_state.settings.precision = None;
+                    } else {
+                        let precision = parse_count(words[2], "precision")?;
+
+                        if precision > MAX_PRECISION {
+                            let error = format!("precision cannot exceed {MAX_PRECISION}");
+                            return Err(CommandError::Args(error));
+                        }
+
+                        connection_state.settings.precision = Some(precision);
+                    }
+
+                    "OK\r\n".to_string()
+                }
+                "CURRENCY" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE CURRENCY command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    connection_state.settings.currency = match words[2] {
+                        "OFF" => None,
+                        symbol => Some(symbol.to_string()),
+                    };
+
+                    "OK\r\n".to_string()
+                }
+                "ISOLATION" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE ISOLATION command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    let Some(isolation) = Isolation::parse(words[2]) else {
+                        let error = format!("unknown isolation level {}", words[2]);
+                        return Err(CommandError::Args(error));
+                    };
+
+                    // An open transaction keeps the level it was started with.
+                    connection_state.settings.isolation = isolation;
+                    "OK\r\n".to_string()
+                }
+                "ERRORS" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE ERRORS command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    let Some(error_style) = ErrorStyle::parse(words[2]) else {
+                        let error = format!("unknown error style {}", words[2]);
+                        return Err(CommandError::Args(error));
+                    };
+
+                    connection_state.settings.error_style = error_style;
+                    "OK\r\n".to_string()
+                }
+                "SEPARATORS" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE SEPARATORS command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    connection_state.settings.thousands_separators = match words[2] {
+                        "ON" => true,
+                        "OFF" => false,
+                        _ => {
+                            let error = format!("expected ON or OFF, not {}", words[2]);
+                            return Err(CommandError::Args(error));
+                        }
+                    };
+
+                    "OK\r\n".to_string()
+                }
+                "FASTREAD" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE FASTREAD command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    connection_state.settings.fast_read = match words[2] {
+                        "ON" => true,
+                        "OFF" => false,
+                        _ => {
+                            let error = format!("expected ON or OFF, not {}", words[2]);
+                            return Err(CommandError::Args(error));
+                        }
+                    };
+
+                    "OK\r\n".to_string()
+                }
+                "AUTOSHOW" => {
+                    if words.len() != 3 {
+                        return Err(CommandError::Args(
+                            "MODE AUTOSHOW command requires exactly one argument".to_string(),
+                        ));
+                    }
+
+                    connection_state.settings.autoshow_every = parse_count(words[2], "count")?;
+                    connection_state.modifications_since_autoshow = 0;
+                    "OK\r\n".to_string()
+                }
+                "RESET" => {
+                    if words.len() != 2 {
+                        return Err(CommandError::Args(
+                            "MODE RESET command requires exactly zero arguments".to_string(),
+                        ));
+                    }
+
+                    // Aliases, samples and history are the connection's data rather than
+                    // settings, so they are kept. An open transaction keeps its isolation level.
+                    connection_state.settings = ConnectionSettings::default();
+                    connection_state.modifications_since_autoshow = 0;
+                    "OK\r\n".to_string()
+                }
+                _ => return Err(CommandError::Unknown(format!("unknown mode {}", words[1]))),
+            }
+        }
+        "BEGIN" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "BEGIN command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.transaction.is_some() {
+                return Err(CommandError::Busy(
+                    "transaction already in progress".to_string(),
+                ));
+            }
+
+            let isolation = connection_state.settings.isolation;
+
+            let shard = match isolation {
+                Isolation::Serializable => Some(server.hold_shard(connection_state.shard).await),
+                Isolation::Optimistic => None,
+            };
+
+            let snapshot = show(global_state);
+            connection_state.transaction = Some(Transaction::begin(isolation, snapshot, shard));
+            "BEGIN\r\n".to_string()
+        }
+        "COMMIT" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "COMMIT command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let Some(transaction) = connection_state.transaction.take() else {
+                return Err(CommandError::State(
+                    "no transaction in progress".to_string(),
+                ));
+            };
+
+            match commit(&transaction, global_state) {
+                Ok(change) => {
+                    connection_state.history.record("COMMIT", change);
+
+                    format!(
+                        "COMMIT: X = {}\r\n",
+                        connection_state.format_number(change.value)
+                    )
+                }
+                Err(e) => return Err(CommandError::Busy(format!("{e}, transaction rolled back"))),
+            }
+        }
+        "ROLLBACK" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "ROLLBACK command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.transaction.take().is_none() {
+                return Err(CommandError::State(
+                    "no transaction in progress".to_string(),
+                ));
+            }
+
+            "ROLLBACK\r\n".to_string()
+        }
+        "ALIAS" => {
+            // The expansion may contain spaces, so everything after the command name is the definition.
+            let definition = words[1..].join(" ");
+
+            let Some((name, expansion)) = definition.split_once('=') else {
+                return Err(CommandError::Args(
+                    "ALIAS command requires an argument in the form name=COMMAND".to_string(),
+                ));
+            };
+
+            let (name, expansion) = (name.trim(), expansion.trim());
+
+            if expansion.is_empty() {
+                return Err(CommandError::Args(
+                    "ALIAS command requires a non-empty expansion".to_string(),
+                ));
+            }
+
+            if let Err(e) = validate_alias(name, expansion, &connection_state.aliases) {
+                return Err(CommandError::Args(e));
+            }
+
+            connection_state
+                .aliases
+                .insert(name.to_string(), expansion.to_string());
+            format!("ALIAS {name}={expansion}\r\n")
+        }
+        "ALIASES" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "ALIASES command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            if connection_state.aliases.is_empty() {
+                return Ok("No aliases defined\r\n".to_string());
+            }
+
+            let mut aliases: Vec<_> = connection_state.aliases.iter().collect();
+            aliases.sort();
+
+            aliases
+                .iter()
+                .map(|(name, expansion)| format!("ALIAS {name}={expansion}\r\n"))
+                .collect()
+        }
+        "UNALIAS" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "UNALIAS command requires exactly one argument".to_string(),
+                ));
+            }
+
+            if connection_state.aliases.remove(words[1]).is_none() {
+                return Err(CommandError::NotFound(format!(
+                    "no such alias {}",
+                    words[1]
+                )));
+            }
+
+            format!("UNALIAS {}\r\n", words[1])
+        }
+        "DELAY" if server.config.enable_delay => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "DELAY command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let millis = parse_count(words[1], "delay")?;
+
+            if millis > MAX_DELAY_MILLIS {
+                return Err(CommandError::Args(format!(
+                    "delay cannot exceed {MAX_DELAY_MILLIS} ms"
+                )));
+            }
+
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            "OK\r\n".to_string()
+        }
+        "PRIORITY" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "PRIORITY command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let Some(priority) = Priority::parse(words[1]) else {
+                let error = format!("unknown priority {}", words[1]);
+                return Err(CommandError::Args(error));
+            };
+
+            connection_state.settings.priority = priority;
+            "OK\r\n".to_string()
+        }
+        "TOTAL" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "TOTAL command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            let total = total(&server.shards, connection_state.settings.priority);
+            format!("TOTAL = {}\r\n", connection_state.format_number(total))
+        }
+        "VERSION" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "VERSION command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            format!(
+                "VERSION {} protocol={PROTOCOL_VERSION} features={}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                features(&server.config).join(",")
+            )
+        }
+        "SHUTDOWN" => {
+            if words.len() != 2 {
+                return Err(CommandError::Args(
+                    "SHUTDOWN command requires exactly one argument".to_string(),
+                ));
+            }
+
+            let requester = match connection_state.peer {
+                Some(peer) => format!("client {peer}"),
+                None => "the startup script".to_string(),
+            };
+
+            let Some(admin_token) = &server.config.admin_token else {
+                eprintln!("Warning: {requester} asked for a SHUTDOWN, which is disabled");
+                return Err(CommandError::State(
+                    "SHUTDOWN is disabled, the server was started without --admin-token"
+                        .to_string(),
+                ));
+            };
+
+            if !tokens_match(words[1], admin_token) {
+                eprintln!("Warning: {requester} asked for a SHUTDOWN with the wrong admin token");
+                return Err(CommandError::Args("wrong admin token".to_string()));
+            }
+
+            println!("Shutdown requested by {requester}");
+            server.shutdown.send_replace(true);
+            "SHUTTING DOWN\r\n".to_string()
+        }
+        // Only reached without a command to run, or when nested: JSON JSON SHOW.
+        "JSON" => {
+            return Err(CommandError::Args(
+                "JSON command requires a command to run".to_string(),
+            ));
+        }
+        "NOP" => match words {
+            [_] => "OK\r\n".to_string(),
+            [_, tag] => format!("OK {tag}\r\n"),
+            _ => {
+                return Err(CommandError::Args(
+                    "NOP command requires at most one argument".to_string(),
+                ));
+            }
+        },
+        "HELP" => {
+            if words.len() != 1 {
+                return Err(CommandError::Args(
+                    "HELP command requires exactly zero arguments".to_string(),
+                ));
+            }
+
+            COMMANDS
+                .iter()
+                .map(|(usage, description)| format!("{usage} - {description}\r\n"))
+                .collect()
+        }
+        _ => {
+            let message = match suggest_command(words[0]) {
+                Some(suggestion) => {
+                    format!("unknown command {} (did you mean {suggestion}?)", words[0])
+                }
+                None => format!("unknown command {}", words[0]),
+            };
+
+            return Err(CommandError::Unknown(message));
+        }
+    };
+
+    if connection_state.settings.autoshow_every > 0
+        && connection_state.history.modifications() != modifications
+    {
+        connection_state.modifications_since_autoshow += 1;
+
+        if connection_state.modifications_since_autoshow == connection_state.settings.autoshow_every
+        {
+            connection_state.modifications_since_autoshow = 0;
+            response.push_str(&connection_state.format_x(show(global_state)));
+        }
+    }
+
+    Ok(response)
+}
+
+// With several shards, every shard's lock is listed separately, e.g.
+// "STATS: shard 0: lock taken 3 times, ...; shard 1: lock taken 5 times, ...".
+#[cfg(feature = "lock-stats")]
+fn lock_stats(server: &Server) -> Result<String, CommandError> {
+    if server.shards.count() == 1 {
+        return Ok(format!(
+            "STATS: {}\r\n",
+            server.shards.get(0).contention().summary()
+        ));
+    }
+
+    let summaries: Vec<_> = server
+        .shards
+        .iter()
+        .enumerate()
+        .map(|(index, shard)| format!("shard {index}: {}", shard.contention().summary()))
+        .collect();
+
+    Ok(format!("STATS: {}\r\n", summaries.join("; ")))
+}
+
+#[cfg(not(feature = "lock-stats"))]
+fn lock_stats(_server: &Server) -> Result<String, CommandError> {
+    Err(CommandError::State(
+        "built without the lock-stats feature".to_string(),
+    ))
+}
+
+// The line as it may be logged or recorded in the transcript, with whatever follows SHUTDOWN
+// replaced, so that the admin token never leaks. This also covers SHUTDOWN after a JSON prefix or
+// in the definition of an ALIAS.
+fn redact(line: &str) -> Cow<'_, str> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let Some(position) = words
+        .iter()
+        .position(|word| word.rsplit('=').next() == Some("SHUTDOWN"))
+    else {
+        return Cow::Borrowed(line);
+    };
+
+    if position + 1 == words.len() {
+        return Cow::Borrowed(line);
+    }
+
+    Cow::Owned(format!("{} {REDACTED}", words[..=position].join(" ")))
+}
+
+// Takes the same time however much of the given token is right, so that response times do not
+// help to guess the admin token one character at a time.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+
+    given
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+// For the whole-number arguments of commands, e.g. the 5 in MODE AUTOSHOW 5.
+fn parse_count<T: FromStr>(token: &str, name: &str) -> Result<T, CommandError> {
+    token
+        .parse::<T>()
+        .map_err(|_| CommandError::Parse(format!("invalid {name} {token}")))
+}
+
+// Fails on the first operand that is not valid, if any.
+fn parse_operands(
+    tokens: &[&str],
+    connection_state: &ConnectionState,
+) -> Result<Vec<Operand>, InvalidOperand> {
+    tokens
+        .iter()
+        .map(|token| connection_state.parse_operand(token))
+        .collect()
+}
+
+// Applies the operation to the transaction's private X if there is a transaction, otherwise to the
+// shared X, and describes the outcome.
+fn run_operation(
+    operation: Operation,
+    global_state: &Prioritized<GlobalState>,
+    connection_state: &mut ConnectionState,
+) -> Result<String, CommandError> {
+    run_operation_on(|_| Ok(operation), global_state, connection_state)
+}
+
+// Like run_operation, for an operation whose operands may refer to X, e.g. ADD X*0.1. The
+// operation is only worked out from the X it is about to be applied to, which for the shared X
+// means while holding the lock, so no other connection can change X in between.
+//
+// History and transactions keep the operation as worked out, e.g. ADD 1.5 for ADD X*0.1 with an X
+// of 15. So UNDO reverts exactly what was done, and a transaction that COMMIT applies to the
+// current X adds the 1.5 worked out from the private X, rather than 10% of the current X.
+fn run_operation_on(
+    operation: impl FnOnce(f64) -> Result<Operation, &'static str>,
+    global_state: &Prioritized<GlobalState>,
+    connection_state: &mut ConnectionState,
+) -> Result<String, CommandError> {
+    let result = match &mut connection_state.transaction {
+        Some(transaction) => operation(transaction.x).and_then(|operation| {
+            transaction
+                .apply(operation)
+                .map(|new_value| (operation, new_value))
+        }),
+        None => apply_on(operation, global_state).map(|(operation, change)| {
+            connection_state
+                .history
+                .record(&operation.command(), change);
+
+            (operation, change.value)
+        }),
+    };
+
+    let (operation, new_value) = match result {
+        Ok(result) => result,
+        Err(e) => return Err(CommandError::Domain(e.to_string())),
+    };
+
+    Ok(format!(
+        "{} = {}\r\n",
+        operation.describe(),
+        connection_state.format_number(new_value)
+    ))
+}
+
+// If the operation fails, X is left unchanged.
+fn apply(
+    operation: Operation,
+    global_state: &Prioritized<GlobalState>,
+) -> Result<Change, &'static str> {
+    apply_on(|_| Ok(operation), global_state).map(|(_, change)| change)
+}
+
+// Works the operation out from X and applies it, all under the same lock. If either fails, X is
+// left unchanged.
+fn apply_on(
+    operation: impl FnOnce(f64) -> Result<Operation, &'static str>,
+    global_state: &Prioritized<GlobalState>,
+) -> Result<(Operation, Change), &'static str> {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+    let operation = operation(previous)?;
+    let new_value = operation.apply(previous)?;
+    guarded_state.set_x(new_value);
+
+    Ok((
+        operation,
+        Change {
+            previous,
+            value: new_value,
+        },
+    ))
+}
+
+// Puts back the value X had before the modification. Whatever other connections did to X in
+// the meantime is overwritten.
+fn undo(entry: &HistoryEntry, global_state: &Prioritized<GlobalState>) -> f64 {
+    let mut guarded_state = global_state.write();
+    guarded_state.set_x(entry.change.previous);
+
+    guarded_state.x
+}
+
+fn replace_x(new_value: f64, global_state: &Prioritized<GlobalState>) -> Change {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+    guarded_state.set_x(new_value);
+
+    Change {
+        previous,
+        value: new_value,
+    }
+}
+
+// Fails if the transaction conflicted with another change to X, in which case X is left unchanged.
+// A serializable transaction has held the shard since BEGIN, so X cannot have been changed.
+fn commit(
+    transaction: &Transaction,
+    global_state: &Prioritized<GlobalState>,
+) -> Result<Change, &'static str> {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+
+    // Compare the bits, so that a NaN snapshot still counts as unchanged.
+    if transaction.isolation == Isolation::Optimistic
+        && previous.to_bits() != transaction.snapshot.to_bits()
+    {
+        return Err("X was changed since BEGIN");
+    }
+
+    guarded_state.set_x(transaction.x);
+
+    Ok(Change {
+        previous,
+        value: transaction.x,
+    })
+}
+
+/// Floored division: the quotient is rounded towards negative infinity and the remainder has the
+/// same sign as the divisor, so that `quotient * divisor + remainder` gives back the original X.
+fn divmod(value: f64, global_state: &Prioritized<GlobalState>) -> (Change, f64) {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+    let quotient = (previous / value).floor();
+    let remainder = previous - quotient * value;
+    guarded_state.set_x(quotient);
+
+    let change = Change {
+        previous,
+        value: quotient,
+    };
+
+    (change, remainder)
+}
+
+// The caller is responsible for making sure there is at least one sample.
+fn sample_mean(samples: &[f64]) -> f64 {
+    samples.iter().sum::<f64>() / samples.len() as f64
+}
+
+// The caller is responsible for making sure there is at least one sample.
+// Welford's online algorithm, which avoids the catastrophic cancellation that the naive
+// "mean of squares minus square of mean" suffers from when the values are large and close together.
+fn population_variance(samples: &[f64]) -> f64 {
+    let mut mean = 0.0;
+    let mut sum_of_squared_deltas = 0.0;
+
+    for (i, sample) in samples.iter().enumerate() {
+        let delta = sample - mean;
+        mean += delta / (i + 1) as f64;
+        sum_of_squared_deltas += delta * (sample - mean);
+    }
+
+    sum_of_squared_deltas / samples.len() as f64
+}
+
+fn show(global_state: &Prioritized<GlobalState>) -> f64 {
+    let guarded_state = global_state.read();
+    guarded_state.x
+}
+
+fn store(name: &str, global_state: &Prioritized<GlobalState>) -> f64 {
+    let mut guarded_state = global_state.write();
+    let value = guarded_state.x;
+    guarded_state.registers.insert(name.to_string(), value);
+
+    value
+}
+
+fn recall(name: &str, global_state: &Prioritized<GlobalState>) -> Option<Change> {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+    let new_value = *guarded_state.registers.get(name)?;
+    guarded_state.set_x(new_value);
+
+    Some(Change {
+        previous,
+        value: new_value,
+    })
+}
+
+// Holds every shard's lock while adding up, so changes made in the meantime are either counted in
+// full or not at all, rather than the sum mixing shards from before and after them.
+fn total(shards: &Shards<GlobalState>, priority: Priority) -> f64 {
+    shards
+        .read_all(priority)
+        .iter()
+        .map(|guarded_state| guarded_state.x)
+        .sum()
+}
+
+// Copies all registers under a single lock, so the listing is a consistent snapshot.
+fn show_all(global_state: &Prioritized<GlobalState>) -> BTreeMap<String, f64> {
+    let guarded_state = global_state.read();
+    guarded_state.registers.clone()
+}
+
+// A snapshot holds a single X and set of registers, so it would only capture part of the state of
+// a server with several shards, and restoring it would leave the other shards as they were.
+fn require_single_shard(command: &str, server: &Server) -> Result<(), CommandError> {
+    if server.shards.count() > 1 {
+        return Err(CommandError::State(format!(
+            "{command} is not available with more than one shard"
+        )));
+    }
+
+    Ok(())
+}
+
+// Takes X and the registers under a single lock, so they are consistent with each other.
+fn export(global_state: &Prioritized<GlobalState>) -> Snapshot {
+    let guarded_state = global_state.read();
+
+    Snapshot {
+        x: guarded_state.x,
+        registers: guarded_state.registers.clone(),
+    }
+}
+
+// Replaces X and all registers in one step. Registers missing from the snapshot are removed.
+fn import(snapshot: Snapshot, global_state: &Prioritized<GlobalState>) -> Change {
+    let mut guarded_state = global_state.write();
+    let previous = guarded_state.x;
+    guarded_state.set_x(snapshot.x);
+    guarded_state.registers = snapshot.registers;
+
+    Change {
+        previous,
+        value: snapshot.x,
+    }
+}
+
+// Picks the register values out of the given environment variables, logging what is loaded and
+// skipping anything malformed with a warning. Variables without the prefix are ignored.
+fn registers_from_env(vars: impl IntoIterator<Item = (String, String)>) -> BTreeMap<String, f64> {
+    let mut registers = BTreeMap::new();
+
+    for (var, value) in vars {
+        let Some(name) = var.strip_prefix(REGISTER_ENV_PREFIX) else {
+            continue;
+        };
+
+        if name.is_empty() || !is_valid_register_name(name) {
+            eprintln!("Warning: ignoring {var}, {name:?} is not a valid register name");
+            continue;
+        }
+
+        let Ok(value) = value.trim().parse::<f64>() else {
+            eprintln!("Warning: ignoring {var}, {value:?} is not a number");
+            continue;
+        };
+
+        eprintln!("Loaded register {name} = {value} from {var}");
+        registers.insert(name.to_string(), value);
+    }
+
+    registers
+}
+
+fn is_valid_register_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server(config: Config) -> Server {
+        Server::new(config, BTreeMap::new(), None)
+    }
+
+    // X on the first shard, the only one unless a test asks for more.
+    fn x_of(server: &Server) -> f64 {
+        show(&server.shards.get(0).with_priority(Priority::Normal))
+    }
+
+    fn test_connection(server: &Server) -> ConnectionState {
+        ConnectionState {
+            history: History::new(server.config.history_size),
+            ..Default::default()
+        }
+    }
+
+    // Runs the lines one after the other on the same connection and returns the last response.
+    async fn run(
+        lines: &[&str],
+        server: &Server,
+        connection_state: &mut ConnectionState,
+    ) -> String {
+        let mut response = String::new();
+
+        for line in lines {
+            response = execute_command(line, server, connection_state).await;
+        }
+
+        response
+    }
+
+    #[tokio::test]
+    async fn divmod_floors_the_quotient() {
+        let cases = [
+            ("ADD 7", "DIVMOD 2", "quotient=3 remainder=1"),
+            ("ADD -7", "DIVMOD 2", "quotient=-4 remainder=1"),
+            ("ADD 7", "DIVMOD -2", "quotient=-4 remainder=-1"),
+            ("ADD 7.5", "DIVMOD 2", "quotient=3 remainder=1.5"),
+        ];
+
+        for (add, divmod, expected) in cases {
+            let server = test_server(Config::default());
+            let mut connection_state = ConnectionState::default();
+            let response = run(&[add, divmod], &server, &mut connection_state).await;
+            assert!(
+                response.ends_with(&format!(": {expected}\r\n")),
+                "{add}, {divmod} replied {response:?}"
+            );
+        }
+
+        // X is left as the quotient.
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+        run(&["ADD 7", "DIVMOD 2"], &server, &mut connection_state).await;
+        assert_eq!(x_of(&server), 3.0);
+
+        let response = run(&["DIVMOD 0"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EDIVZERO division by zero\r\n");
+        assert_eq!(x_of(&server), 3.0);
+    }
+
+    #[tokio::test]
+    async fn percentages_scale_x() {
+        let cases = [
+            ("PERCENT 0", 0.0),
+            ("PERCENT 100", 80.0),
+            ("PERCENT 150", 120.0),
+            ("INCREASE 0", 80.0),
+            ("INCREASE 100", 160.0),
+            ("INCREASE 150", 200.0),
+            ("DECREASE 0", 80.0),
+            ("DECREASE 100", 0.0),
+            ("DECREASE 150", -40.0),
+        ];
+
+        for (command, expected) in cases {
+            let server = test_server(Config::default());
+            let mut connection_state = ConnectionState::default();
+            run(&["ADD 80", command], &server, &mut connection_state).await;
+            assert_eq!(x_of(&server), expected, "{command}");
+        }
+
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+        let response = run(&["ADD 80", "INCREASE 25"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 25% = 100\r\n");
+    }
+
+    #[tokio::test]
+    async fn increment_counts_up_from_zero() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(
+            &["INCREMENT", "INCREMENT", "INCREMENT", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = 3\r\n");
+
+        let response = run(&["DECREMENT"], &server, &mut connection_state).await;
+        assert_eq!(response, "X -= 1 = 2\r\n");
+    }
+
+    #[tokio::test]
+    async fn mean_of_the_samples() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(
+            &["SAMPLE 1", "SAMPLE 2", "SAMPLE 6", "COUNT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "SAMPLES = 3\r\n");
+
+        let response = run(&["MEAN"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = mean = 3\r\n");
+        assert_eq!(x_of(&server), 3.0);
+
+        let response = run(&["CLEAR", "MEAN"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EDOMAIN no samples\r\n");
+        assert_eq!(x_of(&server), 3.0);
+    }
+
+    #[tokio::test]
+    async fn variance_and_stddev_of_the_samples() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        for sample in [2, 4, 4, 4, 5, 5, 7, 9] {
+            run(
+                &[&format!("SAMPLE {sample}")],
+                &server,
+                &mut connection_state,
+            )
+            .await;
+        }
+
+        let response = run(&["VARIANCE"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = variance = 4\r\n");
+
+        let response = run(&["STDDEV"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = stddev = 2\r\n");
+    }
+
+    #[test]
+    fn variance_of_large_close_samples_is_exact() {
+        // The naive mean of squares minus square of mean loses all of this to cancellation.
+        let samples = [4.0, 7.0, 13.0, 16.0].map(|sample| 1e9 + sample);
+        assert_eq!(population_variance(&samples), 22.5);
+        assert_eq!(population_variance(&[5.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn aliases_are_registered_used_and_overridden() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["ALIAS up=ADD 10", "up"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 10 = 10\r\n");
+
+        let response = run(
+            &["ALIAS up=SUBTRACT 1", "up"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X -= 1 = 9\r\n");
+
+        let response = run(&["ALIAS SHOW=CLEAR"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+
+        let response = run(&["UNALIAS up", "up"], &server, &mut connection_state).await;
+        assert!(
+            response.starts_with("ERROR EUNKNOWN unknown command up"),
+            "{response:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn precision_trims_the_decimals() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(
+            &["ADD 3.14159", "MODE PRECISION 2", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = 3.14\r\n");
+
+        let response = run(&["ADD 1"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 1 = 4.14\r\n");
+
+        let response = run(&["MODE PRECISION 16"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+
+        let response = run(
+            &["MODE PRECISION OFF", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = 4.14159\r\n");
+    }
+
+    #[tokio::test]
+    async fn abs_of_a_negative_x() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["SUBTRACT 4.5", "ABS"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = |X| = 4.5\r\n");
+
+        let response = run(&["ABS"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = |X| = 4.5\r\n");
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_get_a_suggestion() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["ADDD 5"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EUNKNOWN unknown command ADDD (did you mean ADD?)\r\n"
+        );
+
+        let response = run(&["FROBNICATE"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EUNKNOWN unknown command FROBNICATE\r\n");
+
+        let response = run(&["MODE FROBNICATE"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EUNKNOWN unknown mode FROBNICATE\r\n");
+    }
+
+    #[tokio::test]
+    async fn datagrams_share_x_with_connections() {
+        let server = Arc::new(test_server(Config::default()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(udp::serve(socket, server.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ADD 5\r\n", address).await.unwrap();
+
+        let mut buffer = [0; 64];
+        let (length, _) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"X += 5 = 5\r\n");
+
+        let mut connection_state = ConnectionState::default();
+        let response = run(&["SHOW"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = 5\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_datagram_cannot_take_over_a_session() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["SESSION"], &server, &mut connection_state).await;
+        let token = response
+            .trim_end()
+            .strip_prefix("SESSION ")
+            .unwrap()
+            .to_string();
+        run(&["SAMPLE 3"], &server, &mut connection_state).await;
+        server.sessions.save(token.clone(), connection_state);
+
+        for line in ["SESSION", &format!("RESUME {token}"), "BEGIN", "COMMIT"] {
+            let mut datagram_state = ConnectionState {
+                connectionless: true,
+                ..Default::default()
+            };
+            let response = run(&[line], &server, &mut datagram_state).await;
+            assert!(
+                response.starts_with("ERROR ESTATE"),
+                "{line} replied {response:?}"
+            );
+        }
+
+        let mut connection_state = ConnectionState::default();
+        let response = run(
+            &[&format!("RESUME {token}"), "COUNT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "SAMPLES = 1\r\n");
+    }
+
+    #[test]
+    fn only_commands_over_the_threshold_are_slow() {
+        let config = Config {
+            slow_command_threshold: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            slow_command_warning("DELAY", Duration::from_millis(150), &config),
+            Some("Slow command: DELAY took 150ms".to_string())
+        );
+        assert_eq!(
+            slow_command_warning("ADD", Duration::from_millis(100), &config),
+            None
+        );
+        assert_eq!(
+            slow_command_warning("ADD", Duration::from_micros(20), &config),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn show_all_lists_the_registers_by_name() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["SHOW ALL"], &server, &mut connection_state).await;
+        assert_eq!(response, "No registers defined\r\n");
+
+        let response = run(
+            &[
+                "ADD 3",
+                "STORE zeta",
+                "SUBTRACT 2",
+                "STORE alpha",
+                "ADD 1.5",
+                "STORE mid",
+                "SHOW ALL",
+            ],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "alpha = 1\r\nmid = 2.5\r\nzeta = 3\r\n");
+    }
+
+    #[tokio::test]
+    async fn transactions_commit_or_roll_back_as_a_whole() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+
+        let response = run(&["BEGIN", "ADD 5", "ADD 2"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 2 = 7\r\n");
+        assert_eq!(x_of(&server), 0.0);
+
+        let response = run(&["COMMIT"], &server, &mut connection_state).await;
+        assert_eq!(response, "COMMIT: X = 7\r\n");
+        assert_eq!(x_of(&server), 7.0);
+
+        let response = run(
+            &["BEGIN", "ADD 100", "ROLLBACK"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "ROLLBACK\r\n");
+        assert_eq!(x_of(&server), 7.0);
+
+        let response = run(&["COMMIT"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR ESTATE no transaction in progress\r\n");
+
+        let response = run(&["ROLLBACK"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR ESTATE no transaction in progress\r\n");
+
+        let response = run(&["BEGIN", "BEGIN"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EBUSY transaction already in progress\r\n");
+
+        let response = run(&["STORE r1"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EBUSY STORE cannot be used in a transaction\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn serializable_transactions_keep_others_waiting_until_they_end() {
+        let server = Arc::new(test_server(Config::default()));
+        let mut connection_state = ConnectionState::default();
+
+        run(&["BEGIN", "ADD 5"], &server, &mut connection_state).await;
+
+        let other = {
+            let server = server.clone();
+
+            tokio::spawn(async move {
+                let mut other_connection_state = ConnectionState::default();
+                run(&["ADD 10"], &server, &mut other_connection_state).await
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!other.is_finished());
+
+        let response = run(&["ADD 2", "COMMIT"], &server, &mut connection_state).await;
+        assert_eq!(response, "COMMIT: X = 7\r\n");
+        assert_eq!(other.await.unwrap(), "X += 10 = 17\r\n");
+
+        // Giving up on the transaction, or on the connection, lets the others in just the same.
+        run(
+            &["BEGIN", "ADD 1", "ROLLBACK"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(
+            run(&["SHOW"], &server, &mut ConnectionState::default()).await,
+            "X = 17\r\n"
+        );
+
+        run(&["BEGIN"], &server, &mut connection_state).await;
+        drop(connection_state);
+        assert_eq!(
+            run(&["SHOW"], &server, &mut ConnectionState::default()).await,
+            "X = 17\r\n"
+        );
+
+        // Those who would wait for longer than the command timeout give up instead.
+        let server = test_server(Config {
+            command_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let mut connection_state = ConnectionState::default();
+        run(&["BEGIN"], &server, &mut connection_state).await;
+
+        let response = run(&["ADD 10"], &server, &mut ConnectionState::default()).await;
+        assert_eq!(response, "ERROR ETIMEOUT command took too long\r\n");
+
+        let response = run(&["ADD 1", "COMMIT"], &server, &mut connection_state).await;
+        assert_eq!(response, "COMMIT: X = 1\r\n");
+    }
+
+    #[tokio::test]
+    async fn optimistic_commit_fails_if_x_changed_since_begin() {
+        let server = test_server(Config::default());
+        let mut connection_state = ConnectionState::default();
+        let mut other_connection_state = ConnectionState::default();
+
+        run(
+            &["MODE ISOLATION OPTIMISTIC", "BEGIN", "ADD 1"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+
+        // Nobody else sees the transaction's X before COMMIT, but they can change X meanwhile.
+        let response = run(&["SHOW"], &server, &mut other_connection_state).await;
+        assert_eq!(response, "X = 0\r\n");
+        run(&["ADD 10"], &server, &mut other_connection_state).await;
+
+        let response = run(&["COMMIT"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EBUSY X was changed since BEGIN, transaction rolled back\r\n"
+        );
+        assert_eq!(x_of(&server), 10.0);
+
+        // Of two transactions started on the same X, only the first to COMMIT succeeds.
+        run(
+            &["MODE ISOLATION OPTIMISTIC"],
+            &server,
+            &mut other_connection_state,
+        )
+        .await;
+        run(&["BEGIN", "ADD 1"], &server, &mut connection_state).await;
+        run(&["BEGIN", "ADD 2"], &server, &mut other_connection_state).await;
+
+        let response = run(&["COMMIT"], &server, &mut other_connection_state).await;
+        assert_eq!(response, "COMMIT: X = 12\r\n");
+
+        let response = run(&["COMMIT"], &server, &mut connection_state).await;
+        assert!(
+            response.starts_with("ERROR EBUSY X was changed"),
+            "{response:?}"
+        );
+        assert_eq!(x_of(&server), 12.0);
+
+        // The connection is out of the failed transaction, so it can start another one.
+        let response = run(
+            &["BEGIN", "ADD 1", "COMMIT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "COMMIT: X = 13\r\n");
+
+        let response = run(&["MODE ISOLATION NONE"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EARGS unknown isolation level NONE\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_optimistic_commits_never_lose_an_update() {
+        let server = Arc::new(test_server(Config::default()));
+
+        let clients: Vec<_> = (0..32)
+            .map(|_| {
+                let server = server.clone();
+
+                tokio::spawn(async move {
+                    let mut connection_state = ConnectionState::default();
+                    run(
+                        &["MODE ISOLATION OPTIMISTIC", "BEGIN", "ADD 1"],
+                        &server,
+                        &mut connection_state,
+                    )
+                    .await;
+                    tokio::task::yield_now().await;
+                    run(&["COMMIT"], &server, &mut connection_state).await
+                })
+            })
+            .collect();
+
+        let mut committed = 0;
+
+        for client in clients {
+            let response = client.await.unwrap();
+
+            if response.starts_with("COMMIT") {
+                committed += 1;
+            } else {
+                assert!(
+                    response.starts_with("ERROR EBUSY X was changed"),
+                    "{response:?}"
+                );
+            }
+        }
+
+        // Every successful COMMIT added exactly 1, every failed one added nothing.
+        assert!(committed >= 1);
+        assert_eq!(x_of(&server), committed as f64);
+    }
+
+    #[tokio::test]
+    async fn undo_stops_at_the_history_window() {
+        let server = test_server(Config {
+            history_size: 2,
+            ..Default::default()
+        });
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["ADD 1", "ADD 2", "ADD 3", "UNDO", "UNDO"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "UNDO ADD 2: X = 1\r\n");
+
+        let response = run(&["UNDO"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR ESTATE history exhausted\r\n");
+        assert_eq!(x_of(&server), 1.0);
+    }
+
+    #[test]
+    fn currency_is_grouped_in_thousands() {
+        assert_eq!(format_currency(1234.5, "$"), "$1,234.50");
+        assert_eq!(format_currency(-1234567.891, "€"), "-€1,234,567.89");
+        assert_eq!(format_currency(999.999, "$"), "$1,000.00");
+        assert_eq!(format_currency(-0.001, "$"), "$0.00");
+        assert_eq!(format_currency(f64::INFINITY, "$"), "inf");
+    }
+
+    #[tokio::test]
+    async fn currency_mode_formats_show() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["ADD 12345.678", "MODE CURRENCY $", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = $12,345.68\r\n");
+
+        let response = run(
+            &["MODE CURRENCY OFF", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = 12345.678\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_refused_power_leaves_x_alone() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["ADD -8", "POWER 0.5"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EDOMAIN fractional power of negative base is undefined\r\n"
+        );
+        assert_eq!(x_of(&server), -8.0);
+    }
+
+    #[tokio::test]
+    async fn set_replaces_x_exactly() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["ADD 7", "SET 0.1"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = 0.1\r\n");
+
+        let response = run(&["SHOW"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = 0.1\r\n");
+
+        let response = run(&["UNDO"], &server, &mut connection_state).await;
+        assert_eq!(response, "UNDO SET 0.1: X = 7\r\n");
+    }
+
+    #[tokio::test]
+    async fn connections_are_counted_while_open() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let first = ConnectionGuard::new(&server, peer);
+        let second = ConnectionGuard::new(&server, peer);
+        let response = run(&["CONNECTIONS"], &server, &mut connection_state).await;
+        assert_eq!(response, "CONNECTIONS = 2\r\n");
+
+        drop(first);
+        let response = run(&["CONNECTIONS"], &server, &mut connection_state).await;
+        assert_eq!(response, "CONNECTIONS = 1\r\n");
+
+        drop(second);
+        let response = run(&["CONNECTIONS"], &server, &mut connection_state).await;
+        assert_eq!(response, "CONNECTIONS = 0\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_bad_operand_anywhere_leaves_x_alone() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["ADD 10", "ADD 1 2 bad 4"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EPARSE invalid operand bad: unexpected b\r\n"
+        );
+        assert_eq!(x_of(&server), 10.0);
+
+        let response = run(&["HISTORY"], &server, &mut connection_state).await;
+        assert_eq!(response, "ADD 10: 0 -> 10\r\nEND\r\n");
+    }
+
+    #[tokio::test]
+    async fn preview_leaves_x_alone() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let cases = [
+            ("PREVIEW ADD 1 2", "PREVIEW: X would be 13\r\n"),
+            ("PREVIEW POWER 2", "PREVIEW: X would be 100\r\n"),
+            ("PREVIEW SET 30", "PREVIEW: X would be 30\r\n"),
+            ("PREVIEW DECREASE 10", "PREVIEW: X would be 9\r\n"),
+        ];
+
+        run(&["SET 10"], &server, &mut connection_state).await;
+
+        for (command, expected) in cases {
+            let response = run(&[command], &server, &mut connection_state).await;
+            assert_eq!(response, expected);
+        }
+
+        let response = run(&["PREVIEW STORE r"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR EARGS STORE is not an arithmetic command\r\n"
+        );
+
+        assert_eq!(x_of(&server), 10.0);
+
+        let response = run(&["HISTORY"], &server, &mut connection_state).await;
+        assert_eq!(response, "SET 10: 0 -> 10\r\nEND\r\n");
+    }
+
+    #[tokio::test]
+    async fn a_failed_read_ends_the_connection_without_an_error() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = test_server(Config::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // A line that is not UTF-8 cannot be read, which is as good as a broken connection.
+        for sent in [&b"SHOW\r\n"[..], &b"\xff\xfe\r\n"[..]] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, peer) = listener.accept().await.unwrap();
+
+            client.write_all(sent).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut connection_state = test_connection(&server);
+            let result =
+                process_commands(stream, peer, &server, &mut connection_state, LineFramer).await;
+            assert!(result.is_ok(), "{sent:?}: {result:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn delta_reports_the_last_change() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["DELTA"], &server, &mut connection_state).await;
+        assert_eq!(response, "DELTA: no changes yet\r\n");
+
+        let response = run(
+            &["SET 10", "ADD 2.5", "DELTA"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "DELTA ADD 2.5: 2.5\r\n");
+
+        let response = run(&["SUBTRACT 4", "DELTA"], &server, &mut connection_state).await;
+        assert_eq!(response, "DELTA SUBTRACT 4: -4\r\n");
+    }
+
+    #[tokio::test]
+    async fn raw_shows_the_exact_bits() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["MODE PRECISION 1", "SET 0.1", "ADD 0.2", "RAW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(
+            response,
+            "X = 0.30000000000000004 (bits 0x3fd3333333333334)\r\n"
+        );
+
+        let response = run(&["SET 1", "RAW"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = 1.0 (bits 0x3ff0000000000000)\r\n");
+    }
+
+    #[tokio::test]
+    async fn every_failure_class_has_its_code() {
+        let server = test_server(Config::default());
+
+        let cases: &[(&[&str], &str)] = &[
+            (&["ADD"], "EARGS"),
+            (&["SUBTRACT 1 2"], "EARGS"),
+            (&["SUBTRACT abc"], "EPARSE"),
+            (&["ADD 1 abc"], "EPARSE"),
+            (&["DIVMOD abc"], "EPARSE"),
+            (&["MODE PRECISION abc"], "EPARSE"),
+            (&["SHOW\u{7}"], "EPARSE"),
+            (&["DIVMOD 0"], "EDIVZERO"),
+            (&["MEAN"], "EDOMAIN"),
+            (&["BEGIN", "BEGIN"], "EBUSY"),
+            (&["RECALL nope"], "ENOTFOUND"),
+            (&["COMMIT"], "ESTATE"),
+        ];
+
+        for (lines, code) in cases {
+            let mut connection_state = test_connection(&server);
+            let response = run(lines, &server, &mut connection_state).await;
+
+            assert!(
+                response.starts_with(&format!("ERROR {code} ")),
+                "{lines:?} replied {response:?}, expected {code}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn prose_errors_leave_out_the_code() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["MODE ERRORS PROSE", "ADD"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(
+            response,
+            "ERROR: ADD command requires at least one argument\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_are_listed_by_name() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["ALIASES"], &server, &mut connection_state).await;
+        assert_eq!(response, "No aliases defined\r\n");
+
+        let response = run(
+            &["ALIAS up=ADD 1", "ALIAS down=SUBTRACT 1", "ALIASES"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "ALIAS down=SUBTRACT 1\r\nALIAS up=ADD 1\r\n");
+    }
+
+    #[tokio::test]
+    async fn import_restores_what_was_exported() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["ADD 0.1", "ADD 0.2", "STORE r1", "EXPORT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        let blob = response
+            .strip_prefix("EXPORT ")
+            .unwrap()
+            .trim_end()
+            .to_string();
+
+        run(&["SET 7", "STORE r2"], &server, &mut connection_state).await;
+
+        let response = run(&[&format!("IMPORT {blob}")], &server, &mut connection_state).await;
+        assert_eq!(response, "IMPORT: X = 0.30000000000000004, 1 registers\r\n");
+        assert_eq!(x_of(&server), 0.1 + 0.2);
+
+        let response = run(&["RECALL r2"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR ENOTFOUND"), "{response:?}");
+
+        let response = run(&["IMPORT abc"], &server, &mut connection_state).await;
+        assert!(
+            response.starts_with("ERROR EPARSE invalid snapshot"),
+            "{response:?}"
+        );
+        assert_eq!(x_of(&server), 0.1 + 0.2);
+    }
+
+    #[tokio::test]
+    async fn nop_acknowledges_without_touching_x() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 4", "NOP"], &server, &mut connection_state).await;
+        assert_eq!(response, "OK\r\n");
+
+        assert_eq!(x_of(&server), 4.0);
+
+        let response = run(&["HISTORY"], &server, &mut connection_state).await;
+        assert_eq!(response, "SET 4: 0 -> 4\r\nEND\r\n");
+    }
+
+    #[tokio::test]
+    async fn priority_is_a_connection_setting() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["PRIORITY HIGH", "ADD 2"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 2 = 2\r\n");
+        assert_eq!(connection_state.settings.priority, Priority::High);
+
+        let response = run(&["PRIORITY URGENT"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+        assert_eq!(connection_state.settings.priority, Priority::High);
+    }
+
+    #[tokio::test]
+    async fn show_in_every_base() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        run(&["SET 10"], &server, &mut connection_state).await;
+
+        for (base, expected) in [
+            ("2", "X = 0b1010\r\n"),
+            ("8", "X = 0o12\r\n"),
+            ("16", "X = 0xa\r\n"),
+            ("10", "X = 10\r\n"),
+        ] {
+            let response = run(
+                &[&format!("BASE {base}"), "SHOW"],
+                &server,
+                &mut connection_state,
+            )
+            .await;
+            assert_eq!(response, expected, "base {base}");
+        }
+
+        let response = run(
+            &["SET -31", "BASE 16", "SHOW"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = -0x1f\r\n");
+
+        let response = run(&["SET 2.5", "SHOW"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "X = 2.5 (not a 64-bit whole number, shown in base 10)\r\n"
+        );
+    }
+
+    #[test]
+    fn only_64_bit_whole_numbers_have_other_bases() {
+        assert_eq!(format_in_base(255.0, 2).as_deref(), Some("0b11111111"));
+        assert_eq!(
+            format_in_base(-(2f64.powi(63)), 16).as_deref(),
+            Some("-0x8000000000000000")
+        );
+        assert_eq!(format_in_base(2f64.powi(63), 16), None);
+        assert_eq!(format_in_base(0.5, 8), None);
+        assert_eq!(format_in_base(f64::NAN, 2), None);
+    }
+
+    #[tokio::test]
+    async fn commands_over_the_budget_time_out() {
+        let server = test_server(Config {
+            enable_delay: true,
+            command_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 1", "DELAY 5"], &server, &mut connection_state).await;
+        assert_eq!(response, "OK\r\n");
+
+        let response = run(&["DELAY 500"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR ETIMEOUT command took too long\r\n");
+        assert_eq!(x_of(&server), 1.0);
+
+        // Without --enable-delay, DELAY is not a command at all.
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+        let response = run(&["DELAY 5"], &server, &mut connection_state).await;
+        assert!(
+            response.starts_with("ERROR EUNKNOWN unknown command DELAY"),
+            "{response:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn registers_are_seeded_from_the_environment() {
+        let vars = [
+            ("CALCULON_REG_TAXRATE", "0.08"),
+            ("CALCULON_REG_LIMIT", " 100 "),
+            ("CALCULON_REG_BAD", "lots"),
+            ("CALCULON_REG_", "1"),
+            ("CALCULON_REG_NO-DASHES", "1"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()));
+
+        let registers = registers_from_env(vars);
+        assert_eq!(
+            registers,
+            BTreeMap::from([("LIMIT".to_string(), 100.0), ("TAXRATE".to_string(), 0.08)])
+        );
+
+        let server = Server::new(Config::default(), registers, None);
+        let mut connection_state = test_connection(&server);
+        let response = run(&["RECALL TAXRATE"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = TAXRATE = 0.08\r\n");
+    }
+
+    #[tokio::test]
+    async fn autoshow_after_every_second_modification() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["MODE AUTOSHOW 2", "ADD 1"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X += 1 = 1\r\n");
+
+        // Commands that do not modify X do not count.
+        let response = run(&["NOP", "COUNT", "ADD 1"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 1 = 2\r\nX = 2\r\n");
+
+        let response = run(&["ADD 1", "ADD 1"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 1 = 4\r\nX = 4\r\n");
+
+        let response = run(
+            &["MODE AUTOSHOW 0", "ADD 1", "ADD 1"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X += 1 = 6\r\n");
+    }
+
+    #[tokio::test]
+    async fn last_describes_the_previous_modification() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["LAST"], &server, &mut connection_state).await;
+        assert_eq!(response, "LAST: none\r\n");
+
+        // Commands that do not modify X are not the last operation.
+        let response = run(&["ADD 2 3", "SHOW", "LAST"], &server, &mut connection_state).await;
+        assert_eq!(response, "LAST: ADD 5 -> 5\r\n");
+
+        let response = run(
+            &["SAMPLE 4", "MEAN", "LAST"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "LAST: MEAN -> 4\r\n");
+    }
+
+    #[tokio::test]
+    async fn idle_once_the_last_client_has_been_gone_for_the_grace_period() {
+        let grace = Duration::from_millis(100);
+        let (connections_tx, mut connections) = watch::channel(0);
+        let idle = tokio::spawn(async move { wait_until_idle(&mut connections, grace).await });
+
+        // Not idle right after starting up, before anyone connected.
+        tokio::time::sleep(grace * 2).await;
+        assert!(!idle.is_finished());
+
+        // Nor when a client reconnects within the grace period.
+        connections_tx.send(1).unwrap();
+        tokio::time::sleep(grace / 4).await;
+        connections_tx.send(0).unwrap();
+        tokio::time::sleep(grace / 4).await;
+        connections_tx.send(1).unwrap();
+        tokio::time::sleep(grace * 2).await;
+        assert!(!idle.is_finished());
+
+        connections_tx.send(0).unwrap();
+        tokio::time::timeout(grace * 10, idle)
+            .await
+            .expect("the server never became idle")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn separators_are_a_connection_mode() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["ADD 1,000"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EPARSE"), "{response:?}");
+
+        let response = run(
+            &["MODE SEPARATORS ON", "ADD 1,000 1_000"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X += 2000 = 2000\r\n");
+
+        let response = run(&["ADD 1,00,0"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EPARSE"), "{response:?}");
+    }
+
+    #[tokio::test]
+    async fn json_applies_to_one_command_only() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 7", "JSON ADD 5"], &server, &mut connection_state).await;
+        assert_eq!(response, "{\"x\": 12}\r\n");
+
+        let response = run(&["ADD 1"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 1 = 13\r\n");
+
+        let response = run(&["JSON ADD"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "{\"error\": \"EARGS\", \"message\": \"ADD command requires at least one argument\"}\r\n"
+        );
+
+        let response = run(&["ADD"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+
+        let response = run(&["JSON FOO"], &server, &mut connection_state).await;
+        assert!(
+            response.starts_with("{\"error\": \"EUNKNOWN\""),
+            "{response:?}"
+        );
+
+        let response = run(
+            &["SET -8", "JSON POWER 0.5"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert!(
+            response.starts_with("{\"error\": \"EDOMAIN\""),
+            "{response:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_refuses_commands_that_report_more_than_x() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+        run(
+            &["SET 7", "ALIAS info=VERSION"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+
+        for line in [
+            "JSON STATS",
+            "JSON VERSION",
+            "JSON LAST",
+            "JSON HELP",
+            "JSON ALIASES",
+            "JSON SHOW ALL",
+            "JSON DIVMOD 2",
+            "JSON info",
+        ] {
+            let response = run(&[line], &server, &mut connection_state).await;
+            assert!(
+                response.starts_with("{\"error\": \"EARGS\"")
+                    && response.contains("cannot be used with JSON"),
+                "{line}: {response:?}"
+            );
+        }
+
+        // Refused without being run.
+        assert_eq!(x_of(&server), 7.0);
+
+        let response = run(&["JSON SHOW"], &server, &mut connection_state).await;
+        assert_eq!(response, "{\"x\": 7}\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_updates_are_not_lost_among_readers() {
+        let server = Arc::new(test_server(Config::default()));
+
+        let clients: Vec<_> = (0..8)
+            .map(|client| {
+                let server = server.clone();
+
+                tokio::spawn(async move {
+                    let mut connection_state = test_connection(&server);
+
+                    for _ in 0..250 {
+                        // Half the clients only read, so writers have to get past them.
+                        let line = if client % 2 == 0 { "ADD 1" } else { "SHOW" };
+                        execute_command(line, &server, &mut connection_state).await;
+                    }
+                })
+            })
+            .collect();
+
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        assert_eq!(x_of(&server), 1000.0);
+    }
+
+    #[tokio::test]
+    async fn connections_on_different_shards_only_change_their_own() {
+        let server = test_server(Config {
+            shards: 3,
+            ..Default::default()
+        });
+
+        let mut first = test_connection(&server);
+        let mut second = ConnectionState {
+            shard: 2,
+            ..test_connection(&server)
+        };
+
+        run(&["ADD 5"], &server, &mut first).await;
+        let response = run(&["ADD 7"], &server, &mut second).await;
+        assert_eq!(response, "X += 7 = 7\r\n");
+
+        assert_eq!(x_of(&server), 5.0);
+        assert_eq!(run(&["SHOW"], &server, &mut second).await, "X = 7\r\n");
+
+        // Every shard is counted, including the one nobody has touched.
+        assert_eq!(run(&["TOTAL"], &server, &mut first).await, "TOTAL = 12\r\n");
+
+        let response = run(&["STATS"], &server, &mut first).await;
+        assert!(
+            response.contains("shard 0: ") && response.contains("shard 2: "),
+            "{response:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_refused_with_several_shards() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+        let response = run(&["ADD 5", "EXPORT"], &server, &mut connection_state).await;
+        let token = response
+            .strip_prefix("EXPORT ")
+            .unwrap()
+            .trim_end()
+            .to_string();
+
+        let server = test_server(Config {
+            shards: 2,
+            ..Default::default()
+        });
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["EXPORT"], &server, &mut connection_state).await;
+        assert_eq!(
+            response,
+            "ERROR ESTATE EXPORT is not available with more than one shard\r\n"
+        );
+
+        let response = run(
+            &[&format!("IMPORT {token}")],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert!(response.starts_with("ERROR ESTATE IMPORT"), "{response:?}");
+        assert_eq!(x_of(&server), 0.0);
+    }
+
+    #[tokio::test]
+    async fn fma_rounds_once_and_refuses_overflow() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        run(&["SET 0.1", "FMA 10 -1"], &server, &mut connection_state).await;
+        assert_eq!(
+            show(&server.global_state(&connection_state)),
+            5.551115123125783e-17
+        );
+
+        let response = run(&["SET 1e308", "FMA 10 0"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EDOMAIN"), "{response:?}");
+        assert_eq!(show(&server.global_state(&connection_state)), 1e308);
+
+        let response = run(&["FMA 10"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+    }
+
+    #[tokio::test]
+    async fn mode_reset_keeps_aliases_and_samples() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &[
+                "MODE PRECISION 2",
+                "BASE 16",
+                "MODE AUTOSHOW 1",
+                "ALIAS inc=ADD 1",
+                "SAMPLE 4",
+                "SET 255",
+                "SHOW",
+            ],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = 0xff\r\n");
+
+        let response = run(&["MODE RESET", "SHOW"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = 255\r\n");
+
+        // Without autoshow, the response no longer ends with X.
+        let response = run(&["inc"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 1 = 256\r\n");
+
+        let response = run(&["SAMPLE 6", "MEAN"], &server, &mut connection_state).await;
+        assert_eq!(response, "X = mean = 5\r\n");
+    }
+
+    #[tokio::test]
+    async fn operands_can_be_worked_out_from_x() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 40", "ADD X/2"], &server, &mut connection_state).await;
+        assert_eq!(response, "X += 20 = 60\r\n");
+
+        run(&["SET 5", "SET X*X"], &server, &mut connection_state).await;
+        assert_eq!(show(&server.global_state(&connection_state)), 25.0);
+
+        let response = run(&["ADD X/2*3"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EPARSE"), "{response:?}");
+
+        let response = run(&["SET 0", "ADD 1/X"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EDOMAIN"), "{response:?}");
+        assert_eq!(show(&server.global_state(&connection_state)), 0.0);
+    }
+
+    #[tokio::test]
+    async fn fastread_sees_every_kind_of_write() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+        run(&["MODE FASTREAD ON"], &server, &mut connection_state).await;
+
+        for line in [
+            "SET 5", "ADD 2 3", "DIVMOD 3", "UNDO", "STORE a", "SET 1", "RECALL a", "SAMPLE 4",
+            "MEAN", "BEGIN", "ADD 10", "COMMIT", "CLEAR",
+        ] {
+            run(&[line], &server, &mut connection_state).await;
+
+            let x = show(&server.global_state(&connection_state));
+            assert_eq!(server.fast_read_x(&connection_state), x, "{line}");
+
+            let response = run(&["SHOW"], &server, &mut connection_state).await;
+            assert_eq!(response, format!("X = {x}\r\n"), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_needs_the_admin_token() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(
+            &["SET 3", "SHUTDOWN s3cret"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert!(response.starts_with("ERROR ESTATE"), "{response:?}");
+        assert!(!*server.shutdown.borrow());
+
+        let server = test_server(Config {
+            admin_token: Some("s3cret".to_string()),
+            ..Default::default()
+        });
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 3", "SHUTDOWN guess"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EARGS wrong admin token\r\n");
+        assert!(!*server.shutdown.borrow());
+        assert_eq!(show(&server.global_state(&connection_state)), 3.0);
+
+        let response = run(&["SHUTDOWN"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+        assert!(!*server.shutdown.borrow());
+
+        let response = run(&["SHUTDOWN s3cret"], &server, &mut connection_state).await;
+        assert_eq!(response, "SHUTTING DOWN\r\n");
+        assert!(*server.shutdown.borrow());
+    }
+
+    #[test]
+    fn the_shutdown_argument_is_redacted() {
+        assert_eq!(redact("SHUTDOWN s3cret"), "SHUTDOWN <redacted>");
+        assert_eq!(redact("SHUTDOWN  s3cret extra"), "SHUTDOWN <redacted>");
+        assert_eq!(redact("JSON SHUTDOWN s3cret"), "JSON SHUTDOWN <redacted>");
+        assert_eq!(
+            redact("ALIAS off=SHUTDOWN s3cret"),
+            "ALIAS off=SHUTDOWN <redacted>"
+        );
+
+        // Nothing else is touched, even if it happens to look like the token.
+        assert_eq!(redact("SHUTDOWN"), "SHUTDOWN");
+        assert_eq!(redact("STORE s3cret"), "STORE s3cret");
+    }
+
+    #[test]
+    fn tokens_match_only_exactly() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3creT", "s3cret"));
+        assert!(!tokens_match("s3cre", "s3cret"));
+        assert!(!tokens_match("s3crets", "s3cret"));
+        assert!(!tokens_match("", "s3cret"));
+    }
+
+    #[tokio::test]
+    async fn version_lists_the_enabled_features() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["VERSION"], &server, &mut connection_state).await;
+        let base_features = BASE_FEATURES.join(",");
+        assert!(
+            response.starts_with(&format!(
+                "VERSION {} protocol=1 features={base_features}",
+                env!("CARGO_PKG_VERSION")
+            )),
+            "{response:?}"
+        );
+        assert_eq!(
+            response.contains("lock-stats"),
+            cfg!(feature = "lock-stats")
+        );
+        assert!(!response.contains("delay") && !response.contains("shutdown"));
+
+        let config = Config {
+            enable_delay: true,
+            admin_token: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        assert!(features(&config).ends_with(&["delay", "shutdown"]));
+
+        let response = run(&["VERSION 2"], &server, &mut connection_state).await;
+        assert!(response.starts_with("ERROR EARGS"), "{response:?}");
+    }
+
+    #[tokio::test]
+    async fn product_of_the_samples() {
+        let server = test_server(Config::default());
+        let mut connection_state = test_connection(&server);
+
+        let response = run(&["SET 7", "PRODUCT"], &server, &mut connection_state).await;
+        assert_eq!(response, "ERROR EDOMAIN no samples\r\n");
+        assert_eq!(show(&server.global_state(&connection_state)), 7.0);
+
+        let response = run(
+            &["SAMPLE 2", "SAMPLE -3", "SAMPLE 0.5", "PRODUCT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(response, "X = product = -3\r\n");
+
+        let response = run(&["UNDO"], &server, &mut connection_state).await;
+        assert!(response.ends_with("= 7\r\n"), "{response:?}");
+
+        let response = run(
+            &["SAMPLE 1e200", "SAMPLE 1e200", "PRODUCT"],
+            &server,
+            &mut connection_state,
+        )
+        .await;
+        assert_eq!(
+            response,
+            "ERROR EDOMAIN product of the samples is too large\r\n"
+        );
+        assert_eq!(show(&server.global_state(&connection_state)), 7.0);
+    }
 }
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// A copy of X that SHOW reads without taking the lock, for connections in MODE FASTREAD.
+//
+// The authoritative X stays under the lock and every write to it also stores the new value here,
+// while still holding the lock. A read sees the value of a complete write, never a mix of two, as
+// the f64 is stored as its bits in a single atomic. But it may be a hair stale: a write that is in
+// progress, or whose lock holder has yet to store here, is not seen until it has been stored.
+#[derive(Debug, Default)]
+pub struct XMirror {
+    bits: AtomicU64,
+}
+
+impl XMirror {
+    pub fn new(x: f64) -> Self {
+        Self {
+            bits: AtomicU64::new(x.to_bits()),
+        }
+    }
+
+    pub fn store(&self, x: f64) {
+        self.bits.store(x.to_bits(), Ordering::Release);
+    }
+
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.bits.load(Ordering::Acquire))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_exactly() {
+        let mirror = XMirror::new(1.5);
+        assert_eq!(mirror.load(), 1.5);
+
+        for x in [-0.0, f64::MIN_POSITIVE, f64::MAX, 0.1 + 0.2] {
+            mirror.store(x);
+            assert_eq!(mirror.load().to_bits(), x.to_bits());
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+
+// An operand that is not a number, or not one in the form the connection accepts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidNumber(pub String);
+
+impl fmt::Display for InvalidNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid number {}", self.0)
+    }
+}
+
+impl Error for InvalidNumber {}
+
+// Parses an operand. With thousands separators enabled, the whole part of the number may also be
+// grouped in threes with commas or underscores, e.g. 1,000 or -1_234_567.5, as long as every group
+// is in the right place and all separators are the same.
+pub fn parse_number(token: &str, thousands_separators: bool) -> Result<f64, InvalidNumber> {
+    let invalid = || InvalidNumber(token.to_string());
+
+    if !thousands_separators || !token.contains([',', '_']) {
+        return token.parse::<f64>().map_err(|_| invalid());
+    }
+
+    let unsigned = token.trim_start_matches(['+', '-']);
+    let sign = &token[..token.len() - unsigned.len()];
+
+    let whole_length = unsigned
+        .find(|c: char| !(c.is_ascii_digit() || c == ',' || c == '_'))
+        .unwrap_or(unsigned.len());
+    let (whole, rest) = unsigned.split_at(whole_length);
+
+    // Separators elsewhere, e.g. in the fraction, would be ambiguous at best.
+    if rest.contains([',', '_']) {
+        return Err(invalid());
+    }
+
+    let separator = if whole.contains(',') { ',' } else { '_' };
+    let groups: Vec<&str> = whole.split(separator).collect();
+
+    let (first, others) = groups.split_first().ok_or_else(invalid)?;
+    let first_is_valid =
+        (1..=3).contains(&first.len()) && first.chars().all(|c| c.is_ascii_digit());
+    let others_are_valid = others
+        .iter()
+        .all(|group| group.len() == 3 && group.chars().all(|c| c.is_ascii_digit()));
+
+    if !first_is_valid || !others_are_valid {
+        return Err(invalid());
+    }
+
+    format!("{sign}{}{rest}", groups.concat())
+        .parse::<f64>()
+        .map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thousands_separators_when_enabled() {
+        assert_eq!(parse_number("1,000", true), Ok(1000.0));
+        assert_eq!(parse_number("1_000", true), Ok(1000.0));
+        assert_eq!(parse_number("-1,234,567.5", true), Ok(-1234567.5));
+        assert_eq!(parse_number("12", true), Ok(12.0));
+
+        assert_eq!(
+            parse_number("1,000", false),
+            Err(InvalidNumber("1,000".to_string()))
+        );
+    }
+
+    #[test]
+    fn misplaced_separators_are_refused() {
+        for token in [
+            "1,00,0",
+            "1,0000",
+            ",100",
+            "1000,",
+            "1,000_000",
+            "1.000,5",
+            "1234,567",
+        ] {
+            assert!(parse_number(token, true).is_err(), "{token}");
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::CommandError;
+use crate::expression::Operand;
+
+// The commands that Operation::parse() understands.
+const ARITHMETIC_COMMANDS: &[&str] = &[
+    "ADD",
+    "SUBTRACT",
+    "POWER",
+    "FMA",
+    "PERCENT",
+    "INCREASE",
+    "DECREASE",
+    "INCREMENT",
+    "DECREMENT",
+    "ABS",
+    "SET",
+];
+
+// An arithmetic operation on X, computed without touching the shared state. This lets the same
+// operation be applied to the shared X or, inside a transaction, to the transaction's private copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Add(f64),
+    Subtract(f64),
+    Power(f64),
+    // X * factor + addend, with a single rounding at the end.
+    Fma { factor: f64, addend: f64 },
+    Percent(f64),
+    Increase(f64),
+    Decrease(f64),
+    Abs,
+    Set(f64),
+}
+
+impl Operation {
+    // Parses an arithmetic command such as ["ADD", "5"], working out any operands that refer to X
+    // from the given X. Used where a command has to be understood without being executed, e.g. by
+    // PREVIEW.
+    pub fn parse(words: &[&str], thousands_separators: bool, x: f64) -> Result<Self, CommandError> {
+        let (&command, operands) = words
+            .split_first()
+            .ok_or_else(|| CommandError::Args("no command given".to_string()))?;
+
+        if !ARITHMETIC_COMMANDS.contains(&command) {
+            return Err(CommandError::Args(format!(
+                "{command} is not an arithmetic command"
+            )));
+        }
+
+        let operands = operands
+            .iter()
+            .map(|token| {
+                Operand::parse(token, thousands_separators)
+                    .map_err(|e| CommandError::Parse(e.to_string()))?
+                    .value(x)
+                    .map_err(|e| CommandError::Domain(e.to_string()))
+            })
+            .collect::<Result<Vec<f64>, CommandError>>()?;
+
+        let constructor: fn(f64) -> Operation = match (command, operands.as_slice()) {
+            ("ADD", [_, ..]) => return Ok(Operation::Add(operands.iter().sum())),
+            ("INCREMENT", []) => return Ok(Operation::Add(1.0)),
+            ("DECREMENT", []) => return Ok(Operation::Subtract(1.0)),
+            ("ABS", []) => return Ok(Operation::Abs),
+            ("FMA", &[factor, addend]) => return Ok(Operation::Fma { factor, addend }),
+            ("SUBTRACT", [_]) => Operation::Subtract,
+            ("POWER", [_]) => Operation::Power,
+            ("PERCENT", [_]) => Operation::Percent,
+            ("INCREASE", [_]) => Operation::Increase,
+            ("DECREASE", [_]) => Operation::Decrease,
+            ("SET", [_]) => Operation::Set,
+            _ => {
+                return Err(CommandError::Args(format!(
+                    "wrong number of operands for {command}"
+                )))
+            }
+        };
+
+        Ok(constructor(operands[0]))
+    }
+
+    // Returns the new X, or an error message if the operation is undefined for this X.
+    pub fn apply(self, x: f64) -> Result<f64, &'static str> {
+        let new_value = match self {
+            Operation::Add(value) => x + value,
+            Operation::Subtract(value) => x - value,
+            Operation::Power(value) => {
+                // A negative base only has a real power if the exponent is a whole number.
+                // Anything else, including NaN and infinite exponents, is refused here rather
+                // than letting powf() turn X into NaN. A base of -0 counts as not negative.
+                if x < 0.0 && value.fract() != 0.0 {
+                    return Err("fractional power of negative base is undefined");
+                }
+
+                x.powf(value)
+            }
+            Operation::Fma { factor, addend } => {
+                // mul_add() rounds only once, so it is more accurate than x * factor + addend.
+                let value = x.mul_add(factor, addend);
+
+                if !value.is_finite() {
+                    return Err("result of multiply-add is not finite");
+                }
+
+                value
+            }
+            Operation::Percent(value) => x * value / 100.0,
+            Operation::Increase(value) => x * (1.0 + value / 100.0),
+            Operation::Decrease(value) => x * (1.0 - value / 100.0),
+            Operation::Abs => x.abs(),
+            Operation::Set(value) => value,
+        };
+
+        Ok(new_value)
+    }
+
+    // The command that performs this operation, e.g. "ADD 5".
+    pub fn command(self) -> String {
+        match self {
+            Operation::Add(value) => format!("ADD {value}"),
+            Operation::Subtract(value) => format!("SUBTRACT {value}"),
+            Operation::Power(value) => format!("POWER {value}"),
+            Operation::Fma { factor, addend } => format!("FMA {factor} {addend}"),
+            Operation::Percent(value) => format!("PERCENT {value}"),
+            Operation::Increase(value) => format!("INCREASE {value}"),
+            Operation::Decrease(value) => format!("DECREASE {value}"),
+            Operation::Abs => "ABS".to_string(),
+            Operation::Set(value) => format!("SET {value}"),
+        }
+    }
+
+    // The left hand side of the response, e.g. "X += 5" for "X += 5 = 12".
+    pub fn describe(self) -> String {
+        match self {
+            Operation::Add(value) => format!("X += {value}"),
+            Operation::Subtract(value) => format!("X -= {value}"),
+            Operation::Power(value) => format!("X ^= {value}"),
+            Operation::Fma { factor, addend } => format!("X = X * {factor} + {addend}"),
+            Operation::Percent(value) => format!("X = {value}% of X"),
+            Operation::Increase(value) => format!("X += {value}%"),
+            Operation::Decrease(value) => format!("X -= {value}%"),
+            Operation::Abs => "X = |X|".to_string(),
+            // There is nothing to say about the old X, so the response is just "X = 5".
+            Operation::Set(_) => "X".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_powers_of_negative_bases_are_refused() {
+        assert_eq!(Operation::Power(2.0).apply(-3.0), Ok(9.0));
+        assert_eq!(Operation::Power(3.0).apply(-2.0), Ok(-8.0));
+        assert_eq!(Operation::Power(0.5).apply(4.0), Ok(2.0));
+        assert_eq!(Operation::Power(0.5).apply(-0.0), Ok(0.0));
+
+        for exponent in [0.5, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                Operation::Power(exponent).apply(-4.0),
+                Err("fractional power of negative base is undefined"),
+                "{exponent}"
+            );
+        }
+    }
+
+    #[test]
+    fn multiply_add_rounds_once() {
+        let fma = Operation::Fma {
+            factor: 10.0,
+            addend: -1.0,
+        };
+
+        // 0.1 is slightly more than a tenth, which the naive product rounds away.
+        assert_eq!(0.1 * 10.0 - 1.0, 0.0);
+        assert_eq!(fma.apply(0.1), Ok(5.551115123125783e-17));
+        assert_eq!(fma.apply(2.0), Ok(19.0));
+
+        let overflowing = Operation::Fma {
+            factor: 10.0,
+            addend: 0.0,
+        };
+        assert_eq!(
+            overflowing.apply(f64::MAX),
+            Err("result of multiply-add is not finite")
+        );
+    }
+}
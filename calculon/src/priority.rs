@@ -0,0 +1,298 @@
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "lock-stats")]
+use std::time::Instant;
+
+#[cfg(feature = "lock-stats")]
+use crate::contention::LockContention;
+
+// How urgently a connection wants the shared state, chosen with PRIORITY.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "NORMAL" => Some(Priority::Normal),
+            "HIGH" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+// A read-write lock that lets high priority lockers go ahead of normal ones.
+//
+// Readers share the value with each other, so commands that only look at it (e.g. SHOW) do not hold
+// each other up, while a writer has the value to itself. Anything that reads the value and then
+// writes based on what it read must take the write lock for the whole of it, or concurrent updates
+// could be lost.
+//
+// While any high priority locker is waiting, normal lockers hold back from even trying to take the
+// lock. A high priority locker therefore only waits for the current holders and the normal lockers
+// that were already waiting when it arrived, however many normal lockers keep arriving after it.
+// Among themselves, lockers of the same priority get no ordering guarantees beyond those of
+// std::sync::RwLock. A steady stream of high priority lockers can starve the normal ones.
+#[derive(Debug, Default)]
+pub struct PriorityRwLock<T> {
+    value: RwLock<T>,
+
+    // Number of high priority lockers waiting for the value. Never held while waiting for the
+    // value, so it cannot deadlock with the holders of the value.
+    high_waiting: Mutex<usize>,
+    no_high_waiting: Condvar,
+
+    #[cfg(feature = "lock-stats")]
+    contention: LockContention,
+}
+
+impl<T> PriorityRwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: RwLock::new(value),
+            high_waiting: Mutex::new(0),
+            no_high_waiting: Condvar::new(),
+            #[cfg(feature = "lock-stats")]
+            contention: LockContention::default(),
+        }
+    }
+
+    pub fn read(&self, priority: Priority) -> RwLockReadGuard<'_, T> {
+        self.lock(
+            priority,
+            || self.value.try_read().ok(),
+            || self.value.read().unwrap(),
+        )
+    }
+
+    pub fn write(&self, priority: Priority) -> RwLockWriteGuard<'_, T> {
+        self.lock(
+            priority,
+            || self.value.try_write().ok(),
+            || self.value.write().unwrap(),
+        )
+    }
+
+    #[cfg(not(feature = "lock-stats"))]
+    fn lock<G>(
+        &self,
+        priority: Priority,
+        _try_take: impl FnOnce() -> Option<G>,
+        take: impl FnOnce() -> G,
+    ) -> G {
+        self.acquire(priority, take)
+    }
+
+    // Tries to take the value without waiting first, so that only the lockers that really had to
+    // wait are counted and timed. A poisoned lock is left for the blocking attempt to panic on.
+    #[cfg(feature = "lock-stats")]
+    fn lock<G>(
+        &self,
+        priority: Priority,
+        try_take: impl FnOnce() -> Option<G>,
+        take: impl FnOnce() -> G,
+    ) -> G {
+        // Normal lockers must not skip ahead of a waiting high priority locker, same as in acquire.
+        let may_try = priority == Priority::High || *self.high_waiting.lock().unwrap() == 0;
+
+        if may_try {
+            if let Some(guard) = try_take() {
+                self.contention.record_uncontended();
+                return guard;
+            }
+        }
+
+        let started = Instant::now();
+        let guard = self.acquire(priority, take);
+        self.contention.record_wait(started.elapsed());
+
+        guard
+    }
+
+    #[cfg(feature = "lock-stats")]
+    pub fn contention(&self) -> &LockContention {
+        &self.contention
+    }
+
+    // Takes the value with `take` once the priority allows it.
+    fn acquire<G>(&self, priority: Priority, take: impl FnOnce() -> G) -> G {
+        match priority {
+            Priority::High => {
+                *self.high_waiting.lock().unwrap() += 1;
+
+                let guard = take();
+
+                let mut high_waiting = self.high_waiting.lock().unwrap();
+                *high_waiting -= 1;
+
+                if *high_waiting == 0 {
+                    self.no_high_waiting.notify_all();
+                }
+
+                guard
+            }
+            Priority::Normal => {
+                let high_waiting = self.high_waiting.lock().unwrap();
+                drop(
+                    self.no_high_waiting
+                        .wait_while(high_waiting, |high_waiting| *high_waiting > 0)
+                        .unwrap(),
+                );
+
+                take()
+            }
+        }
+    }
+
+    // Binds the priority to the lock, so it can be passed around as one.
+    pub fn with_priority(&self, priority: Priority) -> Prioritized<'_, T> {
+        Prioritized {
+            lock: self,
+            priority,
+        }
+    }
+}
+
+// A PriorityRwLock together with the priority its user locks it at.
+#[derive(Debug)]
+pub struct Prioritized<'a, T> {
+    lock: &'a PriorityRwLock<T>,
+    priority: Priority,
+}
+
+impl<T> Prioritized<'_, T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.lock.read(self.priority)
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.lock.write(self.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn high_priority_goes_ahead_of_normal_lockers_that_came_later() {
+        let lock = Arc::new(PriorityRwLock::new(Vec::new()));
+        let holder = lock.write(Priority::Normal);
+
+        let high = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.write(Priority::High).push("high"))
+        };
+
+        while *lock.high_waiting.lock().unwrap() == 0 {
+            thread::yield_now();
+        }
+
+        let normals: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || lock.write(Priority::Normal).push("normal"))
+            })
+            .collect();
+
+        // Give the normal lockers time to line up behind the high priority one.
+        thread::sleep(Duration::from_millis(50));
+        drop(holder);
+
+        high.join().unwrap();
+        normals
+            .into_iter()
+            .for_each(|normal| normal.join().unwrap());
+
+        let order = lock.read(Priority::Normal).clone();
+        assert_eq!(order, ["high", "normal", "normal", "normal", "normal"]);
+    }
+
+    #[test]
+    fn high_priority_makes_progress_under_a_flood_of_normal_lockers() {
+        let lock = Arc::new(PriorityRwLock::new(0u64));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let flood: Vec<_> = (0..8)
+            .map(|_| {
+                let lock = lock.clone();
+                let stop = stop.clone();
+
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let mut value = lock.write(Priority::Normal);
+                        *value += 1;
+                        thread::sleep(Duration::from_micros(100));
+                    }
+                })
+            })
+            .collect();
+
+        let started = Instant::now();
+
+        for _ in 0..20 {
+            *lock.write(Priority::High) += 1;
+        }
+
+        let elapsed = started.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        flood.into_iter().for_each(|thread| thread.join().unwrap());
+
+        assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+    }
+
+    #[cfg(feature = "lock-stats")]
+    #[test]
+    fn only_lockers_that_had_to_wait_count_as_contended() {
+        let lock = Arc::new(PriorityRwLock::new(0u64));
+
+        for _ in 0..3 {
+            *lock.write(Priority::Normal) += 1;
+        }
+
+        assert!(lock
+            .contention()
+            .summary()
+            .starts_with("lock taken 3 times, waited for 0 times (0.0%)"));
+
+        let holder = lock.write(Priority::Normal);
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write(Priority::High) += 1)
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        drop(holder);
+        waiter.join().unwrap();
+
+        let summary = lock.contention().summary();
+        assert!(
+            summary.starts_with("lock taken 5 times, waited for 1 times (20.0%)"),
+            "{summary}"
+        );
+    }
+
+    #[test]
+    fn readers_share_the_lock() {
+        let lock = Arc::new(PriorityRwLock::new(5));
+        let reader = lock.read(Priority::Normal);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let other = {
+            let lock = lock.clone();
+            thread::spawn(move || sender.send(*lock.read(Priority::Normal)).unwrap())
+        };
+
+        // The second reader gets in while the first still holds its guard.
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(5));
+        drop(reader);
+        other.join().unwrap();
+    }
+}
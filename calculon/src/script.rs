@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::path::Path;
+
+use crate::{redact, run_command, ConnectionState, History, Server};
+
+// Runs the commands in a --script file once at startup, before any client can connect, so the
+// server starts out from a known X and known registers.
+//
+// Every line is a command in the same grammar clients use, all of them sharing one connection
+// state, so e.g. a MODE or ALIAS line applies to the lines after it. Commands that fail are logged
+// and skipped, unless `strict` is set, in which case the first failure aborts startup. A script
+// that cannot be read at all always aborts startup. With several shards, the script is run on each
+// of them in turn, so they all start out the same.
+pub async fn run(path: &Path, server: &Server, strict: bool) -> Result<(), Box<dyn Error>> {
+    let script = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read script {}: {e}", path.display()))?;
+
+    for shard in 0..server.shards.count() {
+        if server.shards.count() > 1 {
+            println!("Running script {} on shard {shard}", path.display());
+        }
+
+        run_on_shard(path, &script, shard, server, strict).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_on_shard(
+    path: &Path,
+    script: &str,
+    shard: usize,
+    server: &Server,
+    strict: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut connection_state = ConnectionState {
+        history: History::new(server.config.history_size),
+        shard,
+        ..Default::default()
+    };
+
+    for (index, line) in script.lines().enumerate() {
+        let line_number = index + 1;
+
+        let error = match run_command(line, server, &mut connection_state).await {
+            Ok(response) => {
+                if !response.is_empty() {
+                    println!("Script line {line_number}: {}", response.trim_end());
+                }
+
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // Reported the way a client would see it.
+        let failure = connection_state.format_error(&error);
+
+        let message = format!(
+            "Script {} line {line_number} ({}) failed: {}",
+            path.display(),
+            redact(line.trim()),
+            failure.trim_end()
+        );
+
+        if strict {
+            return Err(message.into());
+        }
+
+        eprintln!("Warning: {message}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{show, show_all, Config};
+
+    fn write_script(name: &str, lines: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("calculon-script-{}-{name}.txt", std::process::id()));
+        std::fs::write(&path, lines).unwrap();
+        path
+    }
+
+    fn x_of_shard(server: &Server, shard: usize) -> f64 {
+        let connection_state = ConnectionState {
+            shard,
+            ..Default::default()
+        };
+
+        show(&server.global_state(&connection_state))
+    }
+
+    #[tokio::test]
+    async fn the_script_sets_up_every_shard() {
+        let path = write_script("shards", "SET 20\nADD 2.5\n");
+        let server = Server::new(
+            Config {
+                shards: 2,
+                ..Default::default()
+            },
+            BTreeMap::new(),
+            None,
+        );
+
+        run(&path, &server, true).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(x_of_shard(&server, 0), 22.5);
+        assert_eq!(x_of_shard(&server, 1), 22.5);
+    }
+
+    #[tokio::test]
+    async fn the_script_sets_up_x_and_the_registers() {
+        let path = write_script(
+            "setup",
+            "SET 20\nALIAS bump=ADD 2.5\nbump\nSTORE start\nADD 1\n",
+        );
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+
+        run(&path, &server, true).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let global_state = server.global_state(&ConnectionState::default());
+        assert_eq!(show(&global_state), 23.5);
+        assert_eq!(show_all(&global_state).get("start"), Some(&22.5));
+    }
+
+    #[tokio::test]
+    async fn failures_only_abort_a_strict_script() {
+        let path = write_script("strict", "ADD 1\nADD oops\nADD 2\n");
+
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+        run(&path, &server, false).await.unwrap();
+        assert_eq!(x_of_shard(&server, 0), 3.0);
+
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+        let error = run(&path, &server, true).await.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            error.to_string().contains("line 2 (ADD oops) failed"),
+            "{error}"
+        );
+        assert_eq!(x_of_shard(&server, 0), 1.0);
+
+        let missing = write_script("missing", "");
+        std::fs::remove_file(&missing).unwrap();
+        assert!(run(&missing, &server, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_fail_a_strict_script() {
+        let path = write_script("unknown", "ADD 1\nFROBNICATE\n");
+
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+        let error = run(&path, &server, true).await.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            error.to_string().contains("failed: ERROR EUNKNOWN"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn operations_that_fail_fail_a_strict_script() {
+        let path = write_script("domain", "SET -8\nPOWER 0.5\n");
+
+        let server = Server::new(Config::default(), BTreeMap::new(), None);
+        let error = run(&path, &server, true).await.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            error
+                .to_string()
+                .contains("line 2 (POWER 0.5) failed: ERROR EDOMAIN"),
+            "{error}"
+        );
+    }
+}
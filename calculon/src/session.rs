@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ConnectionState;
+
+// Keeps the per-connection state of disconnected sessions so that a new connection can RESUME it.
+// Only sessions that have been given a token via SESSION are kept, and only until the TTL runs out.
+#[derive(Debug)]
+pub struct SessionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+#[derive(Debug)]
+struct StoredSession {
+    state: ConnectionState,
+    disconnected_at: Instant,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn new_token() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    pub fn save(&self, token: String, state: ConnectionState) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.remove_expired(&mut sessions);
+
+        sessions.insert(
+            token,
+            StoredSession {
+                state,
+                disconnected_at: Instant::now(),
+            },
+        );
+    }
+
+    // A session can only be resumed once - it belongs to the resuming connection from then on
+    // and is saved again when that connection ends.
+    pub fn resume(&self, token: &str) -> Option<ConnectionState> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.remove_expired(&mut sessions);
+
+        sessions.remove(token).map(|session| session.state)
+    }
+
+    fn remove_expired(&self, sessions: &mut HashMap<String, StoredSession>) {
+        sessions.retain(|_, session| session.disconnected_at.elapsed() < self.ttl);
+    }
+}
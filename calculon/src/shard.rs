@@ -0,0 +1,93 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLockReadGuard;
+
+use crate::priority::{Priority, PriorityRwLock};
+
+// The global state split into independent shards for --shards, each behind a lock of its own, so
+// connections working on different shards never wait for each other.
+//
+// Every connection works on a single shard, picked by hashing a key such as its peer address, and
+// only ever locks that one. Only read_all takes several locks at once, always in shard order, so
+// there is no order in which two lockers could each hold a lock the other is waiting for.
+#[derive(Debug)]
+pub struct Shards<T> {
+    shards: Vec<PriorityRwLock<T>>,
+}
+
+impl<T> Shards<T> {
+    pub fn new(count: usize, mut value: impl FnMut() -> T) -> Self {
+        assert!(count > 0, "there must be at least one shard");
+
+        Self {
+            shards: (0..count).map(|_| PriorityRwLock::new(value())).collect(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn get(&self, index: usize) -> &PriorityRwLock<T> {
+        &self.shards[index]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PriorityRwLock<T>> {
+        self.shards.iter()
+    }
+
+    // The shard that a key belongs to. The same key always maps to the same shard for as long as
+    // the server runs with the same number of shards.
+    pub fn index_for(&self, key: &impl Hash) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    // Read locks every shard and holds on to all of them, so what they contain is a consistent
+    // snapshot: every change to a shard is either fully in it or not at all, and no shard can
+    // change while the others are being read. Writers to any shard wait until the guards are
+    // dropped, so keep them only for as long as it takes to read.
+    pub fn read_all(&self, priority: Priority) -> Vec<RwLockReadGuard<'_, T>> {
+        self.iter().map(|shard| shard.read(priority)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_key_always_maps_to_the_same_shard() {
+        let shards = Shards::new(4, || 0);
+
+        for port in 0..100u16 {
+            let peer = format!("127.0.0.1:{port}");
+            let index = shards.index_for(&peer);
+            assert!(index < shards.count());
+            assert_eq!(shards.index_for(&peer), index);
+        }
+
+        let single = Shards::new(1, || 0);
+        assert_eq!(single.index_for(&"anything"), 0);
+    }
+
+    #[test]
+    fn read_all_sees_every_shard() {
+        let mut next = 0;
+        let shards = Shards::new(3, || {
+            next += 1;
+            next
+        });
+
+        *shards.get(1).write(Priority::Normal) += 10;
+
+        let values: Vec<i32> = shards
+            .read_all(Priority::Normal)
+            .iter()
+            .map(|guard| **guard)
+            .collect();
+        assert_eq!(values, [1, 12, 3]);
+    }
+}
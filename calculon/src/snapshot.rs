@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+
+use crate::is_valid_register_name;
+
+// Bumped whenever the snapshot format changes, so that IMPORT rejects blobs it would misread.
+const VERSION: f64 = 1.0;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// The server state captured by EXPORT and restored by IMPORT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub x: f64,
+    pub registers: BTreeMap<String, f64>,
+}
+
+impl Snapshot {
+    // Base64 encoded JSON like {"version": 1, "x": 5.0, "registers": {"r1": 2.0}}, which fits in a
+    // single token of a command line.
+    pub fn encode(&self) -> String {
+        let registers: Vec<_> = self
+            .registers
+            .iter()
+            .map(|(name, value)| format!("\"{name}\": {}", json_number(*value)))
+            .collect();
+
+        let json = format!(
+            "{{\"version\": {VERSION}, \"x\": {}, \"registers\": {{{}}}}}",
+            json_number(self.x),
+            registers.join(", ")
+        );
+
+        base64_encode(json.as_bytes())
+    }
+
+    // Everything is validated before anything is returned, so a corrupt blob cannot be
+    // partially restored.
+    pub fn decode(blob: &str) -> Result<Self, String> {
+        let json = base64_decode(blob)?;
+        let json = String::from_utf8(json).map_err(|_| "snapshot is not UTF-8".to_string())?;
+
+        let mut parser = Parser::new(&json);
+        let mut version = None;
+        let mut x = None;
+        let mut registers = None;
+
+        parser.object(|parser, key| {
+            match key.as_str() {
+                "version" => version = Some(parser.number()?),
+                "x" => x = Some(parser.number()?),
+                "registers" => {
+                    let mut values = BTreeMap::new();
+
+                    parser.object(|parser, name| {
+                        if name.is_empty() || !is_valid_register_name(&name) {
+                            return Err(format!("invalid register name {name}"));
+                        }
+
+                        let value = parser.number()?;
+
+                        if values.insert(name.clone(), value).is_some() {
+                            return Err(format!("duplicate register {name}"));
+                        }
+
+                        Ok(())
+                    })?;
+
+                    registers = Some(values);
+                }
+                _ => return Err(format!("unexpected field {key}")),
+            }
+
+            Ok(())
+        })?;
+
+        parser.end()?;
+
+        match version {
+            Some(version) if version == VERSION => {}
+            Some(version) => return Err(format!("unsupported version {version}")),
+            None => return Err("missing version".to_string()),
+        }
+
+        Ok(Snapshot {
+            x: x.ok_or("missing x")?,
+            registers: registers.ok_or("missing registers")?,
+        })
+    }
+}
+
+// Finite values are written so that they parse back to exactly the same f64. JSON has no
+// representation for NaN or infinity, so those are written as strings instead.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{value:?}")
+    } else {
+        format!("\"{value}\"")
+    }
+}
+
+// Just enough of a JSON parser for snapshots: objects, strings without escapes and numbers.
+struct Parser<'a> {
+    remaining: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(json: &'a str) -> Self {
+        Self { remaining: json }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.remaining = self.remaining.trim_start();
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+
+        match self.remaining.strip_prefix(expected) {
+            Some(rest) => {
+                self.remaining = rest;
+                Ok(())
+            }
+            None => Err(format!("expected {expected}")),
+        }
+    }
+
+    fn next_is(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        self.remaining.starts_with(expected)
+    }
+
+    // Calls `member` with the key of every member, leaving it to parse the value.
+    fn object(
+        &mut self,
+        mut member: impl FnMut(&mut Self, String) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.expect('{')?;
+
+        if self.next_is('}') {
+            return self.expect('}');
+        }
+
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            member(self, key)?;
+
+            if self.next_is(',') {
+                self.expect(',')?;
+            } else {
+                return self.expect('}');
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+
+        let end = self
+            .remaining
+            .find(['"', '\\'])
+            .ok_or("unterminated string")?;
+
+        if self.remaining[end..].starts_with('\\') {
+            return Err("escapes are not supported".to_string());
+        }
+
+        let value = self.remaining[..end].to_string();
+        self.remaining = &self.remaining[end + 1..];
+
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        if self.next_is('"') {
+            return match self.string()?.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                other => Err(format!("invalid number {other}")),
+            };
+        }
+
+        let length = self
+            .remaining
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(self.remaining.len());
+
+        let (number, rest) = self.remaining.split_at(length);
+        let value = number
+            .parse::<f64>()
+            .map_err(|_| format!("invalid number {number}"))?;
+        self.remaining = rest;
+
+        Ok(value)
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+
+        if self.remaining.is_empty() {
+            Ok(())
+        } else {
+            Err("unexpected data after the end".to_string())
+        }
+    }
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+
+        // n bytes make up n + 1 characters, the rest of the 4 is padding.
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(char::from(BASE64_ALPHABET[index as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(4) {
+        return Err("invalid base64 length".to_string());
+    }
+
+    let chunk_count = text.len() / 4;
+    let mut bytes = Vec::with_capacity(chunk_count * 3);
+
+    for (chunk_index, chunk) in text.as_bytes().chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+
+        if padding > 2 || (padding > 0 && chunk_index + 1 != chunk_count) {
+            return Err("invalid base64 padding".to_string());
+        }
+
+        let mut group = 0u32;
+
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .ok_or("invalid base64 character")?;
+            group = group << 6 | value as u32;
+        }
+
+        group <<= 6 * padding;
+        bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            x: 0.1 + 0.2,
+            registers: BTreeMap::from([
+                ("r1".to_string(), -2.5),
+                ("r2".to_string(), f64::INFINITY),
+            ]),
+        }
+    }
+
+    #[test]
+    fn a_decoded_snapshot_is_exactly_the_encoded_one() {
+        let decoded = Snapshot::decode(&snapshot().encode()).unwrap();
+
+        assert_eq!(decoded.x.to_bits(), (0.1 + 0.2_f64).to_bits());
+        assert_eq!(decoded, snapshot());
+    }
+
+    #[test]
+    fn base64_round_trips_every_length() {
+        for length in 0..=7 {
+            let bytes: Vec<u8> = (0..length).map(|i| 250 - i).collect();
+            assert_eq!(base64_decode(&base64_encode(&bytes)).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn a_truncated_snapshot_is_rejected() {
+        let blob = snapshot().encode();
+
+        // Cut on a base64 group boundary, so that it is the JSON that is incomplete.
+        let truncated = &blob[..blob.len() - 8];
+        assert!(Snapshot::decode(truncated).is_err());
+
+        let truncated = &blob[..blob.len() - 1];
+        assert_eq!(
+            Snapshot::decode(truncated).unwrap_err(),
+            "invalid base64 length"
+        );
+    }
+
+    #[test]
+    fn bad_base64_is_rejected() {
+        assert_eq!(
+            Snapshot::decode("ab!d").unwrap_err(),
+            "invalid base64 character"
+        );
+        assert_eq!(
+            Snapshot::decode("a===").unwrap_err(),
+            "invalid base64 padding"
+        );
+        assert_eq!(
+            Snapshot::decode("ab==abcd").unwrap_err(),
+            "invalid base64 padding"
+        );
+    }
+
+    #[test]
+    fn bad_json_is_rejected() {
+        let decode = |json: &str| Snapshot::decode(&base64_encode(json.as_bytes()));
+
+        assert_eq!(
+            decode(r#"{"version": 1, "x": 1}"#).unwrap_err(),
+            "missing registers"
+        );
+        assert_eq!(
+            decode(r#"{"version": 2, "x": 1, "registers": {}}"#).unwrap_err(),
+            "unsupported version 2"
+        );
+        assert_eq!(
+            decode(r#"{"version": 1, "x": one, "registers": {}}"#).unwrap_err(),
+            "invalid number "
+        );
+        assert_eq!(
+            decode(r#"{"version": 1, "x": 1, "registers": {"a b": 1}}"#).unwrap_err(),
+            "invalid register name a b"
+        );
+        assert_eq!(
+            decode(r#"{"version": 1, "x": 1, "registers": {"r1": 1, "r1": 2}}"#).unwrap_err(),
+            "duplicate register r1"
+        );
+        assert_eq!(
+            decode(r#"{"version": 1, "x": 1, "registers": {}} {}"#).unwrap_err(),
+            "unexpected data after the end"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::command_names;
+
+// Typos further away than this from every command get no suggestion, as any guess would be noise.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+// Finds the known command closest to an unknown one, e.g. ADD for ADDD or add.
+pub fn suggest_command(unknown: &str) -> Option<&'static str> {
+    let unknown = unknown.to_uppercase();
+
+    command_names()
+        .map(|name| (name, levenshtein(&unknown, name)))
+        .filter(|(_, distance)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+// Number of single character insertions, deletions and substitutions to turn one string into the other.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+
+    // Distances from the prefix of `a` processed so far to every prefix of `b`.
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution_cost = usize::from(a_char != *b_char);
+
+            current[j + 1] = (previous[j] + substitution_cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levenshtein_counts_single_character_edits() {
+        assert_eq!(levenshtein("ADD", "ADD"), 0);
+        assert_eq!(levenshtein("ADDD", "ADD"), 1);
+        assert_eq!(levenshtein("SHWO", "SHOW"), 2);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "MEAN"), 4);
+    }
+
+    #[test]
+    fn near_misses_get_a_suggestion() {
+        assert_eq!(suggest_command("ADDD"), Some("ADD"));
+        assert_eq!(suggest_command("show"), Some("SHOW"));
+        assert_eq!(suggest_command("XYZZYQ"), None);
+    }
+}
@@ -0,0 +1,54 @@
+// Upper limit on the words in one command line, so that a client cannot make a multi-operand
+// command such as ADD process an unbounded number of operands.
+const MAX_TOKENS: usize = 64;
+
+// Splits a command line into words. A trailing \r is dropped, as it survives from clients that
+// end lines with \r\n when the line is not read by a reader that strips it. Control characters
+// other than tabs are refused, since no command contains them and they only confuse the logs.
+pub fn tokenize(line: &str) -> Result<Vec<&str>, String> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    if let Some(c) = line.chars().find(|c| c.is_control() && *c != '\t') {
+        return Err(format!("control character {c:?} in command"));
+    }
+
+    let words: Vec<_> = line.split_whitespace().collect();
+
+    if words.len() > MAX_TOKENS {
+        return Err(format!("too many words in command (max {MAX_TOKENS})"));
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitespace_of_any_kind_separates_words() {
+        assert_eq!(tokenize("ADD\t1   2\r").unwrap(), ["ADD", "1", "2"]);
+        assert_eq!(tokenize("  SHOW  ").unwrap(), ["SHOW"]);
+        assert_eq!(tokenize("ADD 1\u{a0}2").unwrap(), ["ADD", "1", "2"]);
+        assert!(tokenize("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        assert!(tokenize("ADD 1\u{0}2").is_err());
+        assert!(tokenize("ADD 1\r2").is_err());
+        assert!(tokenize("SHOW\u{1b}[2J").is_err());
+    }
+
+    #[test]
+    fn token_floods_are_refused() {
+        let line = format!("ADD{}", " 1".repeat(MAX_TOKENS - 1));
+        assert_eq!(tokenize(&line).unwrap().len(), MAX_TOKENS);
+
+        let line = format!("ADD{}", " 1".repeat(MAX_TOKENS));
+        assert_eq!(
+            tokenize(&line).unwrap_err(),
+            format!("too many words in command (max {MAX_TOKENS})")
+        );
+    }
+}
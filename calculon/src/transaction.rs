@@ -0,0 +1,72 @@
+use tokio::sync::OwnedRwLockWriteGuard;
+
+use crate::operation::Operation;
+
+// How a transaction's operations are reconciled with changes other clients make to X meanwhile.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    // BEGIN waits until the transaction can have the shard to itself and keeps it until COMMIT
+    // or ROLLBACK, so nobody else can change X in between and COMMIT never conflicts. Meanwhile,
+    // the commands of other connections on the shard wait, and fail once they have waited for
+    // longer than the command timeout.
+    #[default]
+    Serializable,
+
+    // COMMIT only succeeds if nobody else has changed X since BEGIN, in which case X becomes the
+    // value the transaction computed. Otherwise the transaction is rolled back.
+    Optimistic,
+}
+
+impl Isolation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "SERIALIZABLE" => Some(Isolation::Serializable),
+            "OPTIMISTIC" => Some(Isolation::Optimistic),
+            _ => None,
+        }
+    }
+}
+
+// An open transaction. Operations are applied to a private copy of X taken at BEGIN and only
+// reach the shared X on COMMIT.
+#[derive(Debug)]
+pub struct Transaction {
+    pub isolation: Isolation,
+
+    // The shared X at the time of BEGIN.
+    pub snapshot: f64,
+
+    // The private X, with all the operations so far applied to the snapshot.
+    pub x: f64,
+
+    // Held by a serializable transaction for as long as it is open, see Server::hold_shard.
+    // Dropping the transaction, e.g. with its connection, lets the other connections back in.
+    shard: Option<OwnedRwLockWriteGuard<()>>,
+}
+
+impl Transaction {
+    // A serializable transaction has to be given the shard to hold.
+    pub fn begin(
+        isolation: Isolation,
+        snapshot: f64,
+        shard: Option<OwnedRwLockWriteGuard<()>>,
+    ) -> Self {
+        Self {
+            isolation,
+            snapshot,
+            x: snapshot,
+            shard,
+        }
+    }
+
+    pub fn holds_shard(&self) -> bool {
+        self.shard.is_some()
+    }
+
+    // An operation that fails leaves the private X as it was.
+    pub fn apply(&mut self, operation: Operation) -> Result<f64, &'static str> {
+        self.x = operation.apply(self.x)?;
+
+        Ok(self.x)
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+
+// How many lines may wait to be written before further lines are dropped from the transcript.
+const TRANSCRIPT_QUEUE_LENGTH: usize = 1024;
+
+// Appends every command line received from a TCP client to the --transcript file, e.g.
+// "1700000000.123 127.0.0.1:50312 ADD 5", for replaying or debugging a session later.
+//
+// The file is written by a single task of its own, so a slow disk never holds up a connection.
+// Recording is best effort: if the writer falls behind or writing fails, lines are lost from the
+// transcript and a warning is logged, but the client is served as usual.
+#[derive(Debug)]
+pub struct Transcript {
+    entries: mpsc::Sender<Entry>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    Line(String),
+    // Answered once every line queued before it has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+impl Transcript {
+    // Opens the file up front, so a transcript that cannot be written at all aborts startup.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let (entries, entries_rx) = mpsc::channel(TRANSCRIPT_QUEUE_LENGTH);
+        tokio::spawn(write_lines(path.to_path_buf(), file, entries_rx));
+
+        Ok(Self { entries })
+    }
+
+    pub fn record(&self, peer: SocketAddr, line: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let entry = format!(
+            "{}.{:03} {peer} {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            line.trim_end()
+        );
+
+        if let Err(e) = self.entries.try_send(Entry::Line(entry)) {
+            eprintln!("Warning: dropped a line from client {peer} from the transcript: {e}");
+        }
+    }
+
+    // Waits until every line recorded so far has been written, e.g. before shutting down. Unlike
+    // recording, this waits for room in the queue rather than giving up.
+    pub async fn flush(&self) {
+        let (flushed, flushed_rx) = oneshot::channel();
+
+        if self.entries.send(Entry::Flush(flushed)).await.is_ok() {
+            _ = flushed_rx.await;
+        }
+    }
+}
+
+async fn write_lines(path: PathBuf, mut file: File, mut entries: mpsc::Receiver<Entry>) {
+    while let Some(entry) = entries.recv().await {
+        let line = match entry {
+            Entry::Line(line) => line,
+            // Every line is flushed as it is written, so there is nothing left to do.
+            Entry::Flush(flushed) => {
+                _ = flushed.send(());
+                continue;
+            }
+        };
+
+        // Flushed line by line, as tokio only hands the write to the OS once it is flushed.
+        let written = match file.write_all(line.as_bytes()).await {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = written {
+            eprintln!(
+                "Warning: writing to the transcript {} failed: {e}",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recorded_lines_are_appended() {
+        let path =
+            std::env::temp_dir().join(format!("calculon-transcript-{}.txt", std::process::id()));
+        std::fs::write(&path, "earlier\n").unwrap();
+
+        let transcript = Transcript::open(&path).await.unwrap();
+        let peer: SocketAddr = "127.0.0.1:50312".parse().unwrap();
+        transcript.record(peer, "ADD 5\r\n");
+        transcript.record(peer, "SHOW");
+
+        transcript.flush().await;
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 3, "{written:?}");
+        assert_eq!(lines[0], "earlier");
+
+        for (line, command) in lines[1..].iter().zip(["ADD 5", "SHOW"]) {
+            let (timestamp, rest) = line.split_once(' ').unwrap();
+            assert!(timestamp.parse::<f64>().is_ok(), "{line:?}");
+            assert_eq!(rest, format!("127.0.0.1:50312 {command}"));
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+use crate::{execute_command, redact, ConnectionState, Server};
+
+// Large enough for any sensible command line. Anything longer is truncated by the OS and will
+// most likely fail to parse, which is the right outcome for such input.
+const MAX_DATAGRAM_SIZE: usize = 1024;
+
+// Serves commands arriving as UDP datagrams, one command per datagram. The response, if there is
+// one, is sent back to the address the datagram came from.
+//
+// UDP has no connections, so every datagram is executed with fresh default connection state:
+// MODE settings, samples and aliases do not carry over from one datagram to the next. SESSION,
+// RESUME and the transaction commands are refused with an error, as their state would be thrown
+// away with the datagram's. The main use case is stateless commands like ADD and SHOW against the
+// shared X, which behaves exactly as it does for TCP clients.
+pub async fn serve(socket: UdpSocket, server: Arc<Server>) {
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (length, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Failed to receive datagram; error = {}", e);
+                continue;
+            }
+        };
+
+        let line = String::from_utf8_lossy(&buffer[..length]);
+        println!("Received datagram from {peer}: {}", redact(line.trim_end()));
+
+        // The peer address picks the shard, the same way as for TCP connections.
+        let mut connection_state = ConnectionState {
+            connectionless: true,
+            shard: server.shards.index_for(&peer),
+            peer: Some(peer),
+            ..Default::default()
+        };
+        let response = execute_command(line.trim_end(), &server, &mut connection_state).await;
+
+        if response.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = socket.send_to(response.as_bytes(), peer).await {
+            eprintln!("Failed to send response to {peer}; error = {}", e);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Runs scripts with calculon-client against a real calculon server. The client talks to the server
+// through a proxy in the test, which cuts the connection right after forwarding a chosen command,
+// as if the network had failed before the response arrived.
+
+// A calculon server of its own for the test, stopped when dropped.
+struct TestServer {
+    child: Child,
+    port: u16,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        let port = free_port();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_calculon"))
+            .args(["--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("the server starts");
+
+        let started = Instant::now();
+
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "the server accepts connections"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        Self { child, port }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Forwards connections to the server on `port`. Whenever a client sends `cut_after`, the proxy
+// forwards it and then closes both connections, up to `cuts` times in all.
+fn start_proxy(port: u16, cut_after: &'static str, cuts: usize) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_port = listener.local_addr().unwrap().port();
+    let cuts_left = Arc::new(AtomicUsize::new(cuts));
+
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let cuts_left = cuts_left.clone();
+
+            let (client_reader, server_writer) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || {
+                copy_until_cut(client_reader, server_writer, cut_after, &cuts_left)
+            });
+            thread::spawn(move || _ = io::copy(&mut &server, &mut &client));
+        }
+    });
+
+    proxy_port
+}
+
+fn copy_until_cut(
+    client: TcpStream,
+    mut server: TcpStream,
+    cut_after: &str,
+    cuts_left: &AtomicUsize,
+) {
+    let mut reader = BufReader::new(client.try_clone().unwrap());
+    let mut line = String::new();
+
+    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+        if server.write_all(line.as_bytes()).is_err() {
+            break;
+        }
+
+        let cut = line.trim_end() == cut_after
+            && cuts_left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+
+        if cut {
+            // Give the server a moment to receive the command before the connection goes.
+            thread::sleep(Duration::from_millis(50));
+            _ = client.shutdown(Shutdown::Both);
+            _ = server.shutdown(Shutdown::Both);
+            return;
+        }
+
+        line.clear();
+    }
+
+    _ = server.shutdown(Shutdown::Write);
+}
+
+fn run_client(port: u16, name: &str, script: &str) -> Output {
+    let path =
+        std::env::temp_dir().join(format!("calculon-client-{}-{name}.txt", std::process::id()));
+    fs::write(&path, script).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_calculon-client"))
+        .args(["--address", &format!("127.0.0.1:{port}")])
+        .arg(&path)
+        .output()
+        .unwrap();
+
+    _ = fs::remove_file(&path);
+    output
+}
+
+#[test]
+fn script_resumes_after_the_connection_is_cut() {
+    let server = TestServer::start();
+    let proxy_port = start_proxy(server.port, "COUNT", 1);
+
+    let output = run_client(proxy_port, "resume", "SAMPLE 2\nSAMPLE 3\nCOUNT\nMEAN\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "the client failed: {output:?}");
+    assert_eq!(
+        stdout,
+        "> SAMPLE 2\nSAMPLES = 1\n> SAMPLE 3\nSAMPLES = 2\n> COUNT\nSAMPLES = 2\n> MEAN\nX = mean = 2.5\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("resumed the session"));
+}
+
+#[test]
+fn client_gives_up_when_a_command_keeps_cutting_the_connection() {
+    let server = TestServer::start();
+    let proxy_port = start_proxy(server.port, "COUNT", usize::MAX);
+
+    let output = run_client(proxy_port, "give-up", "SAMPLE 2\nCOUNT\nMEAN\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success());
+    assert!(
+        !stdout.contains("> MEAN"),
+        "the client carried on: {stdout}"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("again while resending COUNT"));
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+/// How long work has actually been in flight, as opposed to the app waiting for input. A work
+/// item is in flight from its creation until its completion is reported, and the active time is
+/// the time covered by at least one completed work item.
+#[derive(Debug)]
+pub struct Activity {
+    active: Duration,
+    /// Up to when the active time has been counted. Never counted twice, however many work items
+    /// were in flight at the same time.
+    counted_until: Instant,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            active: Duration::ZERO,
+            counted_until: Instant::now(),
+        }
+    }
+
+    /// Completions are reported in order, so the time between the previous completion and this
+    /// one is active unless the work item was created after it. In that case the app was idle in
+    /// between. A gap between two earlier work items that is covered by this one is not noticed,
+    /// which only makes the active time err on the short side.
+    pub fn record(&mut self, created_at: Instant, reported_at: Instant) {
+        let start = created_at.max(self.counted_until);
+        self.active += reported_at.saturating_duration_since(start);
+        self.counted_until = self.counted_until.max(reported_at);
+    }
+
+    pub fn active(&self) -> Duration {
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn idle_time_between_work_items_is_not_active() {
+        let mut activity = Activity::new();
+        let start = activity.counted_until;
+
+        // Entered two seconds apart, taking 1s and 2s.
+        activity.record(start, start + seconds(1));
+        activity.record(start + seconds(2), start + seconds(4));
+
+        assert_eq!(activity.active(), seconds(3));
+    }
+
+    #[test]
+    fn overlapping_work_items_are_only_counted_once() {
+        let mut activity = Activity::new();
+        let start = activity.counted_until;
+
+        activity.record(start, start + seconds(2));
+        activity.record(start + seconds(1), start + seconds(3));
+        assert_eq!(activity.active(), seconds(3));
+
+        // Created before the app started counting, e.g. before a reset.
+        let mut activity = Activity::new();
+        let start = activity.counted_until;
+
+        activity.record(start - seconds(5), start + seconds(1));
+        assert_eq!(activity.active(), seconds(1));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::config::Autoscale;
+
+/// How often the autoscaler checks the backlog and latency of every fruit type.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a collector waiting for work checks whether it should retire.
+const RETIREMENT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many checks in a row a fruit type must be under pressure before it gets another collector.
+const SCALE_UP_CHECKS: u32 = 3;
+
+/// How many checks in a row a fruit type must be idle before it loses a collector. More than it
+/// takes to scale up, as a collector too few costs more than one too many.
+const SCALE_DOWN_CHECKS: u32 = 10;
+
+/// The collectors of one fruit type, which share its work queue. How many of them there should
+/// be is up to the autoscaler: it spawns new members itself, while surplus members retire on
+/// their own once they are done with their current work order.
+pub struct Crew<T> {
+    rx: Mutex<Receiver<T>>,
+    /// How many members there should be.
+    target: AtomicUsize,
+    /// How many members there are, not counting those that have retired or seen the queue close.
+    members: AtomicUsize,
+    /// Set once the work queue has been closed and drained, after which every member exits.
+    closed: AtomicBool,
+}
+
+impl<T> Crew<T> {
+    pub fn new(rx: Receiver<T>, target: usize) -> Self {
+        Self {
+            rx: Mutex::new(rx),
+            target: AtomicUsize::new(target),
+            members: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts in a new member, which the caller then spawns.
+    pub fn join(&self) {
+        self.members.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn members(&self) -> usize {
+        self.members.load(Ordering::Relaxed)
+    }
+
+    pub fn set_target(&self, target: usize) {
+        self.target.store(target, Ordering::Relaxed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the next work order for a member. Returns `None` once there will be no more
+    /// work, or if the member is surplus to the target and should retire.
+    pub fn next(&self) -> Option<T> {
+        loop {
+            if self.try_retire() {
+                return None;
+            }
+
+            // Like a shared pool queue, the lock is only held while waiting for a work order.
+            // Waiting with a timeout lets the members queued up behind the lock check in turn
+            // whether they have been made surplus.
+            let received = self
+                .rx
+                .lock()
+                .unwrap()
+                .recv_timeout(RETIREMENT_CHECK_INTERVAL);
+
+            match received {
+                Ok(work_order) => return Some(work_order),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.closed.store(true, Ordering::Relaxed);
+                    self.members.fetch_sub(1, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Leaves the crew if it has more members than the target. Only one member can take each
+    /// surplus place, so the crew never shrinks below the target.
+    fn try_retire(&self) -> bool {
+        self.members
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |members| {
+                (members > self.target.load(Ordering::Relaxed)).then(|| members - 1)
+            })
+            .is_ok()
+    }
+}
+
+/// Decides how many collectors one fruit type should have, from what it sees at every check.
+///
+/// To keep from flapping, the collectors only change after the same pressure was seen for several
+/// checks in a row, and the thresholds leave a band in between where nothing changes: a fruit
+/// type is under pressure while its backlog exceeds the threshold (or its latency the target),
+/// but only idle once nothing at all is waiting (and the latency is down to half the target).
+#[derive(Debug)]
+pub struct Scaler {
+    settings: Autoscale,
+    collectors: usize,
+    pressured_checks: u32,
+    idle_checks: u32,
+}
+
+impl Scaler {
+    pub fn new(settings: Autoscale) -> Self {
+        Self {
+            settings,
+            collectors: settings.min_collectors,
+            pressured_checks: 0,
+            idle_checks: 0,
+        }
+    }
+
+    pub fn collectors(&self) -> usize {
+        self.collectors
+    }
+
+    /// Takes the number of containers waiting for a collector and the longest latency of those
+    /// completed since the previous check, if any were. Returns the new number of collectors
+    /// if it changes.
+    pub fn check(&mut self, backlog: usize, latency: Option<Duration>) -> Option<usize> {
+        let latency_over = |limit: Duration| latency.is_some_and(|latency| latency > limit);
+
+        let pressured = backlog > self.settings.scale_up_backlog
+            || self.settings.scale_up_latency.is_some_and(latency_over);
+        let idle = backlog == 0
+            && !self
+                .settings
+                .scale_up_latency
+                .is_some_and(|target| latency_over(target / 2));
+
+        // Saturating, as a fruit type can stay under pressure for good once at the maximum.
+        self.pressured_checks = if pressured {
+            self.pressured_checks.saturating_add(1)
+        } else {
+            0
+        };
+        self.idle_checks = if idle {
+            self.idle_checks.saturating_add(1)
+        } else {
+            0
+        };
+
+        let collectors = if self.pressured_checks >= SCALE_UP_CHECKS {
+            (self.collectors + 1).min(self.settings.max_collectors)
+        } else if self.idle_checks >= SCALE_DOWN_CHECKS {
+            (self.collectors - 1).max(self.settings.min_collectors)
+        } else {
+            self.collectors
+        };
+
+        if collectors == self.collectors {
+            return None;
+        }
+
+        // Every change has to earn the next one with checks of its own.
+        self.pressured_checks = 0;
+        self.idle_checks = 0;
+        self.collectors = collectors;
+
+        Some(collectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn scaler(scale_up_latency: Option<Duration>) -> Scaler {
+        Scaler::new(Autoscale {
+            min_collectors: 1,
+            max_collectors: 3,
+            scale_up_backlog: 5,
+            scale_up_latency,
+        })
+    }
+
+    #[test]
+    fn collectors_change_only_after_checks_in_a_row() {
+        let mut scaler = scaler(None);
+
+        // Pressure that lets up starts over.
+        assert_eq!(scaler.check(10, None), None);
+        assert_eq!(scaler.check(10, None), None);
+        assert_eq!(scaler.check(3, None), None);
+        assert_eq!(scaler.check(10, None), None);
+        assert_eq!(scaler.check(10, None), None);
+        assert_eq!(scaler.check(10, None), Some(2));
+
+        // In between under pressure and idle, nothing changes however long it lasts.
+        for _ in 0..50 {
+            assert_eq!(scaler.check(3, None), None);
+        }
+
+        for _ in 0..SCALE_UP_CHECKS - 1 {
+            assert_eq!(scaler.check(10, None), None);
+        }
+        assert_eq!(scaler.check(10, None), Some(3));
+
+        for _ in 0..2 * SCALE_UP_CHECKS {
+            assert_eq!(scaler.check(10, None), None);
+        }
+
+        for _ in 0..SCALE_DOWN_CHECKS - 1 {
+            assert_eq!(scaler.check(0, None), None);
+        }
+        assert_eq!(scaler.check(0, None), Some(2));
+        assert_eq!(scaler.collectors(), 2);
+
+        for _ in 0..SCALE_DOWN_CHECKS - 1 {
+            assert_eq!(scaler.check(0, None), None);
+        }
+        assert_eq!(scaler.check(0, None), Some(1));
+
+        for _ in 0..2 * SCALE_DOWN_CHECKS {
+            assert_eq!(scaler.check(0, None), None);
+        }
+    }
+
+    #[test]
+    fn latency_has_a_band_of_its_own() {
+        let mut scaler = scaler(Some(Duration::from_millis(100)));
+        let latency = |millis| Some(Duration::from_millis(millis));
+
+        for _ in 0..SCALE_UP_CHECKS - 1 {
+            assert_eq!(scaler.check(0, latency(150)), None);
+        }
+        assert_eq!(scaler.check(0, latency(150)), Some(2));
+
+        // Over half the target is not idle, even with nothing waiting.
+        for _ in 0..2 * SCALE_DOWN_CHECKS {
+            assert_eq!(scaler.check(0, latency(80)), None);
+        }
+
+        for _ in 0..SCALE_DOWN_CHECKS - 1 {
+            assert_eq!(scaler.check(0, latency(40)), None);
+        }
+        assert_eq!(scaler.check(0, latency(40)), Some(1));
+    }
+
+    #[test]
+    fn surplus_members_retire_and_the_rest_exit_once_closed() {
+        let (tx, rx) = mpsc::channel();
+        let crew = Crew::new(rx, 2);
+        crew.join();
+        crew.join();
+
+        tx.send(1).unwrap();
+        assert_eq!(crew.next(), Some(1));
+
+        crew.set_target(1);
+        assert_eq!(crew.next(), None);
+        assert_eq!(crew.members(), 1);
+        assert!(!crew.is_closed());
+
+        // The remaining member is not surplus, so it carries on.
+        tx.send(2).unwrap();
+        assert_eq!(crew.next(), Some(2));
+
+        drop(tx);
+        assert_eq!(crew.next(), None);
+        assert_eq!(crew.members(), 0);
+        assert!(crew.is_closed());
+    }
+}
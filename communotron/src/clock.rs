@@ -0,0 +1,191 @@
+//! How the collectors wait out the fill delays. The app really waits, while tests can put in a
+//! `ManualClock` to run the whole pipeline without any real delay, yet with the collectors still
+//! finishing in the order their delays dictate.
+
+use std::{
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Waits out a fill delay on the calling thread.
+pub trait Sleeper: Send + Sync {
+    fn sleep(&self, duration: Duration);
+}
+
+/// Really waits, which is what the app does.
+#[derive(Debug, Default)]
+pub struct RealSleeper;
+
+impl Sleeper for RealSleeper {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Does not wait at all, so every container is filled as soon as a collector gets to it,
+/// whatever its fill delay. For when only the outcome matters, not the order.
+#[derive(Debug, Default)]
+pub struct NoWait;
+
+impl Sleeper for NoWait {
+    fn sleep(&self, _duration: Duration) {}
+}
+
+/// A clock that only moves when told to. A thread sleeping on it wakes once the clock has been
+/// advanced to its wake-up time, so threads wake in the order real delays would have woken them,
+/// without any real time passing.
+///
+/// To control the order exactly, wait with `wait_for_sleepers` until every thread that is
+/// expected to sleep is asleep before advancing the clock.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    state: Mutex<ClockState>,
+    /// Notified whenever the clock moves or the sleeping threads change.
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ClockState {
+    /// How far the clock has been advanced since it was created.
+    now: Duration,
+    /// The wake-up times of the sleeping threads.
+    wake_ups: Vec<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How far the clock has been advanced since it was created.
+    pub fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    /// How many threads are sleeping on the clock.
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().wake_ups.len()
+    }
+
+    /// Blocks until at least `count` threads are sleeping on the clock.
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let state = self.state.lock().unwrap();
+
+        drop(
+            self.changed
+                .wait_while(state, |state| state.wake_ups.len() < count)
+                .unwrap(),
+        );
+    }
+
+    /// Moves the clock forward, waking every thread whose wake-up time has come.
+    pub fn advance(&self, by: Duration) {
+        self.state.lock().unwrap().now += by;
+        self.changed.notify_all();
+    }
+
+    /// Moves the clock forward to the earliest wake-up time, waking the threads due then.
+    /// Returns how far the clock moved, or `None` if no thread is sleeping.
+    pub fn advance_to_next(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let next = state.wake_ups.iter().min().copied()?;
+        let by = next.saturating_sub(state.now);
+
+        state.now = state.now.max(next);
+        drop(state);
+        self.changed.notify_all();
+
+        Some(by)
+    }
+}
+
+impl Sleeper for ManualClock {
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let wake_up = state.now + duration;
+        state.wake_ups.push(wake_up);
+        self.changed.notify_all();
+
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.now < wake_up)
+            .unwrap();
+
+        if let Some(index) = state.wake_ups.iter().position(|&time| time == wake_up) {
+            state.wake_ups.swap_remove(index);
+        }
+
+        drop(state);
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn sleepers_wake_in_the_order_of_their_wake_up_times() {
+        let clock = Arc::new(ManualClock::new());
+        let woken = Arc::new(Mutex::new(Vec::new()));
+
+        let sleepers: Vec<_> = [3, 1, 2]
+            .into_iter()
+            .map(|seconds| {
+                let clock = clock.clone();
+                let woken = woken.clone();
+
+                thread::spawn(move || {
+                    clock.sleep(Duration::from_secs(seconds));
+                    woken.lock().unwrap().push(seconds);
+                })
+            })
+            .collect();
+
+        clock.wait_for_sleepers(3);
+
+        for expected_woken in 1..=3 {
+            assert_eq!(clock.advance_to_next(), Some(Duration::from_secs(1)));
+
+            // A woken sleeper is gone from the clock, so the next wake-up time is a later one.
+            while woken.lock().unwrap().len() < expected_woken {
+                thread::yield_now();
+            }
+        }
+
+        sleepers
+            .into_iter()
+            .for_each(|sleeper| sleeper.join().unwrap());
+
+        assert_eq!(*woken.lock().unwrap(), [1, 2, 3]);
+        assert_eq!(clock.now(), Duration::from_secs(3));
+        assert_eq!(clock.advance_to_next(), None);
+    }
+
+    #[test]
+    fn advancing_wakes_every_sleeper_that_is_due() {
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_secs(10));
+
+        let sleepers: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|seconds| {
+                let clock = clock.clone();
+                thread::spawn(move || clock.sleep(Duration::from_secs(seconds)))
+            })
+            .collect();
+
+        clock.wait_for_sleepers(2);
+        clock.advance(Duration::from_secs(5));
+
+        sleepers
+            .into_iter()
+            .for_each(|sleeper| sleeper.join().unwrap());
+
+        assert_eq!(clock.sleepers(), 0);
+        assert_eq!(clock.now(), Duration::from_secs(15));
+    }
+}
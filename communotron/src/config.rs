@@ -0,0 +1,610 @@
+use std::env;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::registry::ItemRegistry;
+use crate::report::Verbosity;
+
+/// Command line configuration. Every setting has a default, so no arguments are required.
+#[derive(Debug)]
+pub struct Config {
+    /// If set, a pool of this many workers collects any fruit type from a single shared queue,
+    /// instead of each fruit type having its own dedicated collector.
+    pub workers: Option<usize>,
+
+    /// Which idle worker of the pool gets the next work order. Ignored without a pool.
+    pub worker_pickup: WorkerPickup,
+
+    /// If set, each fruit type starts out with the minimum number of dedicated collectors and
+    /// gets more of them while its work piles up, see `Autoscale`. Cannot be used with a pool.
+    pub autoscale: Option<Autoscale>,
+
+    /// The fruit types to generate work for, with how long it takes a collector to fill a
+    /// container with each. Apples and oranges unless `--item-types` names a file defining others.
+    pub item_types: ItemRegistry,
+
+    /// Bounds (inclusive) for the size of the containers generated as work.
+    pub min_size: usize,
+    pub max_size: usize,
+
+    /// How much progress output to print, see `Verbosity`.
+    pub verbosity: Verbosity,
+
+    /// In quiet mode, how many completions or how much time to let pass between summaries.
+    pub summary_every: u64,
+    pub summary_interval: Duration,
+
+    /// If set, a heartbeat line with the queue depth is printed whenever this much time passes
+    /// without a completion while work is outstanding, so a long backlog does not look like a hang.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// If set, a work item is generated at this interval, in addition to one for every line
+    /// entered on stdin.
+    pub auto_interval: Option<Duration>,
+
+    /// If set, at most this many work items are generated per second, whatever their source.
+    /// Anything beyond that waits its turn.
+    pub gen_rate: Option<f64>,
+
+    /// If set, the app shuts down once it has been running for this long.
+    pub max_runtime: Option<Duration>,
+
+    /// If set, each work queue holds at most this many containers waiting for a collector.
+    pub queue_capacity: Option<usize>,
+
+    /// What happens to new work when its queue is already at capacity.
+    pub when_full: FullQueuePolicy,
+
+    /// If set, each result channel holds at most this many filled containers waiting for a
+    /// reporter (or for the inspector, with inspection).
+    pub result_capacity: Option<usize>,
+
+    /// What a collector does with a filled container when its result channel is already at
+    /// capacity, i.e. when the reporter is lagging behind.
+    pub when_results_full: FullQueuePolicy,
+
+    /// How many items the collectors put into a container, relative to its size.
+    pub fill_distribution: FillDistribution,
+
+    /// If set, filled containers go through an inspection stage before being reported, which
+    /// removes each item with this probability.
+    pub inspection_reject_rate: Option<f64>,
+
+    /// What happens to the queued work when shutting down.
+    pub shutdown_policy: ShutdownPolicy,
+
+    /// Whether the reporter interleaves the fruit types of completions that arrive close together,
+    /// instead of reporting them in the order they arrived.
+    pub fair_reporting: bool,
+
+    /// Whether each fruit type gets its own reporter thread reading its own result channel,
+    /// instead of one reporter reading the results of all fruit types.
+    pub per_type_reporters: bool,
+
+    /// Whether to check at the end that every work item created was either completed or lost,
+    /// and log an error with the discrepancy if not.
+    pub verify_totals: bool,
+
+    /// Whether the report lines are colored by fruit type. Ignored if stdout is not a terminal.
+    pub color: bool,
+
+    /// If set, every filled container is also posted as JSON to this `http://` URL.
+    pub webhook: Option<String>,
+
+    /// If set, the progress output is written to this file instead of stdout.
+    pub output: Option<PathBuf>,
+}
+
+/// Bounds and thresholds for scaling the collectors of each fruit type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Autoscale {
+    /// Bounds (inclusive) for the number of collectors of each fruit type.
+    pub min_collectors: usize,
+    pub max_collectors: usize,
+
+    /// A fruit type is under pressure while more than this many of its containers are waiting
+    /// for a collector.
+    pub scale_up_backlog: usize,
+
+    /// If set, a fruit type is also under pressure while its containers take longer than this
+    /// from being created to being reported.
+    pub scale_up_latency: Option<Duration>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FullQueuePolicy {
+    /// Wait until the receiving end makes room. For a work queue, input is not processed in the
+    /// meantime; for a result channel, the collector stops collecting.
+    #[default]
+    Block,
+    /// Throw the new message away and carry on.
+    Drop,
+}
+
+impl FromStr for FullQueuePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerPickup {
+    /// Whichever idle worker gets to the shared queue first, which is up to the OS scheduler.
+    #[default]
+    Any,
+    /// The worker that has been idle the longest, so the workers take turns in the order they
+    /// finished their previous work.
+    Fifo,
+    /// The worker whose previous work order was handed out the longest ago, workers that have
+    /// not had any work yet coming first in the order they were numbered. Unlike `Fifo`, a worker
+    /// that was given a slow work order early goes ahead of one that has since done quick ones.
+    LeastRecentlyUsed,
+}
+
+impl FromStr for WorkerPickup {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::Any),
+            "fifo" => Ok(Self::Fifo),
+            "lru" => Ok(Self::LeastRecentlyUsed),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FillDistribution {
+    /// Every fill count from 1 to the container size is equally likely.
+    #[default]
+    Uniform,
+    /// Fill counts closer to the container size are more likely.
+    FullBiased,
+    /// Fill counts closer to 1 are more likely.
+    Sparse,
+}
+
+impl FromStr for FillDistribution {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "full-biased" => Ok(Self::FullBiased),
+            "sparse" => Ok(Self::Sparse),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Finish all the queued work before exiting.
+    #[default]
+    Drain,
+    /// Exit right away, abandoning the queued work and any containers being filled.
+    Abort,
+}
+
+impl FromStr for ShutdownPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drain" => Ok(Self::Drain),
+            "abort" => Ok(Self::Abort),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workers: None,
+            worker_pickup: WorkerPickup::Any,
+            autoscale: None,
+            item_types: ItemRegistry::default(),
+            min_size: 1,
+            max_size: 9,
+            verbosity: Verbosity::Normal,
+            summary_every: 100,
+            summary_interval: Duration::from_secs(10),
+            heartbeat_interval: None,
+            auto_interval: None,
+            gen_rate: None,
+            max_runtime: None,
+            queue_capacity: None,
+            when_full: FullQueuePolicy::Block,
+            result_capacity: None,
+            when_results_full: FullQueuePolicy::Block,
+            fill_distribution: FillDistribution::Uniform,
+            inspection_reject_rate: None,
+            shutdown_policy: ShutdownPolicy::Drain,
+            fair_reporting: false,
+            per_type_reporters: false,
+            verify_totals: false,
+            color: false,
+            webhook: None,
+            output: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        Self::parse(env::args().skip(1))
+    }
+
+    /// Like `from_args`, but with the arguments given rather than taken from the command line.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        let mut verbosity_flags = Vec::new();
+        let mut delay_flags = Vec::new();
+        let mut disabled_types = Vec::new();
+        let mut autoscale_max = None;
+        let mut autoscale_flags = Vec::new();
+        let mut autoscale = Autoscale {
+            min_collectors: 1,
+            max_collectors: 1,
+            scale_up_backlog: 10,
+            scale_up_latency: None,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--workers" => config.workers = Some(parse_value(&arg, args.next())?),
+                "--worker-pickup" => config.worker_pickup = parse_value(&arg, args.next())?,
+                "--autoscale-max" => autoscale_max = Some(parse_value(&arg, args.next())?),
+                "--autoscale-min" => {
+                    autoscale.min_collectors = parse_value(&arg, args.next())?;
+                    autoscale_flags.push(arg);
+                }
+                "--scale-up-backlog" => {
+                    autoscale.scale_up_backlog = parse_value(&arg, args.next())?;
+                    autoscale_flags.push(arg);
+                }
+                "--scale-up-latency-ms" => {
+                    autoscale.scale_up_latency =
+                        Some(Duration::from_millis(parse_value(&arg, args.next())?));
+                    autoscale_flags.push(arg);
+                }
+                "--item-types" => {
+                    let path: PathBuf = parse_value(&arg, args.next())?;
+                    config.item_types = ItemRegistry::from_file(&path)?;
+                }
+                "--apple-delay-ms" | "--orange-delay-ms" => {
+                    let delay = Duration::from_millis(parse_value(&arg, args.next())?);
+                    delay_flags.push((arg, delay));
+                }
+                "--disable" => disabled_types.push(parse_value::<String>(&arg, args.next())?),
+                "--min-size" => config.min_size = parse_value(&arg, args.next())?,
+                "--max-size" => config.max_size = parse_value(&arg, args.next())?,
+                "--quiet" => verbosity_flags.push((arg, Verbosity::Quiet)),
+                "--verbose" => verbosity_flags.push((arg, Verbosity::Verbose)),
+                "--summary-every" => config.summary_every = parse_value(&arg, args.next())?,
+                "--heartbeat-secs" => {
+                    config.heartbeat_interval =
+                        Some(Duration::from_secs(parse_value(&arg, args.next())?));
+                }
+                "--summary-secs" => {
+                    config.summary_interval = Duration::from_secs(parse_value(&arg, args.next())?);
+                }
+                "--auto-interval" => {
+                    config.auto_interval =
+                        Some(Duration::from_millis(parse_value(&arg, args.next())?));
+                }
+                "--gen-rate" => config.gen_rate = Some(parse_value(&arg, args.next())?),
+                "--max-runtime" => {
+                    config.max_runtime = Some(Duration::from_secs(parse_value(&arg, args.next())?));
+                }
+                "--fair-reporting" => config.fair_reporting = true,
+                "--per-type-reporters" => config.per_type_reporters = true,
+                "--verify-totals" => config.verify_totals = true,
+                "--color" => config.color = true,
+                "--webhook" => config.webhook = Some(parse_value(&arg, args.next())?),
+                "--output" => config.output = Some(parse_value(&arg, args.next())?),
+                "--queue-capacity" => {
+                    config.queue_capacity = Some(parse_value(&arg, args.next())?);
+                }
+                "--when-full" => config.when_full = parse_value(&arg, args.next())?,
+                "--result-capacity" => {
+                    config.result_capacity = Some(parse_value(&arg, args.next())?);
+                }
+                "--when-results-full" => {
+                    config.when_results_full = parse_value(&arg, args.next())?;
+                }
+                "--fill-distribution" => {
+                    config.fill_distribution = parse_value(&arg, args.next())?;
+                }
+                "--inspect" => {
+                    config.inspection_reject_rate = Some(parse_value(&arg, args.next())?);
+                }
+                "--shutdown" => config.shutdown_policy = parse_value(&arg, args.next())?,
+                _ => return Err(format!("Unknown argument: {arg}")),
+            }
+        }
+
+        match verbosity_flags.as_slice() {
+            [] => {}
+            [(_, verbosity)] => config.verbosity = *verbosity,
+            [(first, _), (second, _), ..] => {
+                return Err(format!("{first} cannot be combined with {second}."));
+            }
+        }
+
+        // Applied once all the fruit types are known, whichever order the arguments came in.
+        for (flag, delay) in delay_flags {
+            let name = flag.trim_start_matches("--").trim_end_matches("-delay-ms");
+
+            let Some(definition) = config.item_types.find_mut(name) else {
+                return Err(format!(
+                    "{flag} requires the item type {name} to be registered."
+                ));
+            };
+
+            definition.fill_delay = delay;
+        }
+
+        // A disabled fruit type is left out of the registry altogether, so no work is generated
+        // for it and it gets no queue for work to pile up in without a collector.
+        for name in disabled_types {
+            if config.item_types.remove(&name).is_none() {
+                return Err(format!(
+                    "--disable requires the item type {name} to be registered."
+                ));
+            }
+        }
+
+        if config.item_types.types().is_empty() {
+            return Err("At least one item type must be left enabled.".to_string());
+        }
+
+        if config.fair_reporting && config.per_type_reporters {
+            // Interleaving fruit types needs a reporter that sees all of them.
+            return Err(
+                "--fair-reporting cannot be combined with --per-type-reporters.".to_string(),
+            );
+        }
+
+        if config.auto_interval == Some(Duration::ZERO) {
+            return Err("--auto-interval must be at least 1.".to_string());
+        }
+
+        if let Some(gen_rate) = config.gen_rate {
+            if !(gen_rate > 0.0 && gen_rate.is_finite()) {
+                return Err("--gen-rate must be a positive number.".to_string());
+            }
+        }
+
+        if let Some(reject_rate) = config.inspection_reject_rate {
+            if !(0.0..=1.0).contains(&reject_rate) {
+                return Err("--inspect must be a probability between 0 and 1.".to_string());
+            }
+        }
+
+        // Without any room, every result would count as arriving at a full channel.
+        if config.result_capacity == Some(0) {
+            return Err("--result-capacity must be at least 1.".to_string());
+        }
+
+        if config.heartbeat_interval == Some(Duration::ZERO) {
+            return Err("--heartbeat-secs must be at least 1.".to_string());
+        }
+
+        if config.summary_every == 0 {
+            return Err("--summary-every must be at least 1.".to_string());
+        }
+
+        if config.workers == Some(0) {
+            return Err("--workers must be at least 1.".to_string());
+        }
+
+        match (autoscale_max, autoscale_flags.first()) {
+            (None, None) => {}
+            (None, Some(flag)) => return Err(format!("{flag} requires --autoscale-max.")),
+            (Some(max_collectors), _) => {
+                autoscale.max_collectors = max_collectors;
+
+                if config.workers.is_some() {
+                    // The pool already shares its workers between the fruit types.
+                    return Err("--autoscale-max cannot be combined with --workers.".to_string());
+                }
+
+                // Without a collector left, nothing would notice new work for the fruit type.
+                if autoscale.min_collectors == 0 {
+                    return Err("--autoscale-min must be at least 1.".to_string());
+                }
+
+                if autoscale.min_collectors > autoscale.max_collectors {
+                    return Err(format!(
+                        "--autoscale-min ({}) cannot be greater than --autoscale-max ({}).",
+                        autoscale.min_collectors, autoscale.max_collectors
+                    ));
+                }
+
+                if autoscale.scale_up_latency == Some(Duration::ZERO) {
+                    return Err("--scale-up-latency-ms must be at least 1.".to_string());
+                }
+
+                config.autoscale = Some(autoscale);
+            }
+        }
+
+        if config.min_size == 0 {
+            return Err("--min-size must be at least 1.".to_string());
+        }
+
+        if config.min_size > config.max_size {
+            return Err(format!(
+                "--min-size ({}) cannot be greater than --max-size ({}).",
+                config.min_size, config.max_size
+            ));
+        }
+
+        Ok(config)
+    }
+
+    pub fn container_sizes(&self) -> RangeInclusive<usize> {
+        self.min_size..=self.max_size
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{name} requires a value."))?;
+
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for {name}: {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn container_sizes_come_from_the_bounds() {
+        assert_eq!(parse(&[]).unwrap().container_sizes(), 1..=9);
+
+        let config = parse(&["--min-size", "4", "--max-size", "4"]).unwrap();
+        assert_eq!(config.container_sizes(), 4..=4);
+
+        assert_eq!(
+            parse(&["--min-size", "5", "--max-size", "3"]).unwrap_err(),
+            "--min-size (5) cannot be greater than --max-size (3)."
+        );
+        assert_eq!(
+            parse(&["--min-size", "0"]).unwrap_err(),
+            "--min-size must be at least 1."
+        );
+        assert!(parse(&["--max-size", "-1"]).is_err());
+    }
+
+    #[test]
+    fn quiet_and_verbose_cannot_be_combined() {
+        assert_eq!(parse(&[]).unwrap().verbosity, Verbosity::Normal);
+        assert_eq!(parse(&["--quiet"]).unwrap().verbosity, Verbosity::Quiet);
+        assert_eq!(parse(&["--verbose"]).unwrap().verbosity, Verbosity::Verbose);
+        assert_eq!(
+            parse(&["--quiet", "--verbose"]).unwrap_err(),
+            "--quiet cannot be combined with --verbose."
+        );
+        assert_eq!(
+            parse(&["--summary-every", "0"]).unwrap_err(),
+            "--summary-every must be at least 1."
+        );
+    }
+
+    #[test]
+    fn auto_interval_is_in_milliseconds() {
+        assert_eq!(parse(&[]).unwrap().auto_interval, None);
+        assert_eq!(
+            parse(&["--auto-interval", "250"]).unwrap().auto_interval,
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse(&["--auto-interval", "0"]).unwrap_err(),
+            "--auto-interval must be at least 1."
+        );
+    }
+
+    #[test]
+    fn fill_delays_are_in_milliseconds() {
+        let fill_delay = |config: &Config, name| config.item_types.find(name).unwrap().fill_delay;
+
+        let config = parse(&[]).unwrap();
+        assert_eq!(fill_delay(&config, "Apple"), Duration::from_secs(1));
+        assert_eq!(fill_delay(&config, "Orange"), Duration::from_secs(2));
+
+        let config = parse(&["--apple-delay-ms", "0", "--orange-delay-ms", "1500"]).unwrap();
+        assert_eq!(fill_delay(&config, "Apple"), Duration::ZERO);
+        assert_eq!(fill_delay(&config, "Orange"), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn item_types_come_from_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("communotron-item-types-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# name delay weight\nBanana 500 100-150 33\n\nPlum 20 30-40\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = parse(&["--item-types", path]).unwrap();
+        let names: Vec<_> = config
+            .item_types
+            .types()
+            .iter()
+            .map(|definition| definition.item_type.name())
+            .collect();
+        assert_eq!(names, ["Banana", "Plum"]);
+
+        // Even given before the file, the delay flags apply to the types registered by it.
+        let config = parse(&["--apple-delay-ms", "5", "--item-types", path]);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            config.unwrap_err(),
+            "--apple-delay-ms requires the item type apple to be registered."
+        );
+    }
+
+    #[test]
+    fn fair_reporting_needs_a_single_reporter() {
+        assert!(parse(&["--per-type-reporters"]).unwrap().per_type_reporters);
+        assert_eq!(
+            parse(&["--fair-reporting", "--per-type-reporters"]).unwrap_err(),
+            "--fair-reporting cannot be combined with --per-type-reporters."
+        );
+    }
+
+    #[test]
+    fn inspection_takes_a_probability() {
+        assert_eq!(parse(&[]).unwrap().inspection_reject_rate, None);
+        assert_eq!(
+            parse(&["--inspect", "0.25"])
+                .unwrap()
+                .inspection_reject_rate,
+            Some(0.25)
+        );
+        assert!(parse(&["--inspect", "1.5"]).is_err());
+        assert!(parse(&["--inspect", "-0.1"]).is_err());
+    }
+
+    #[test]
+    fn disabled_types_are_left_out_of_the_registry() {
+        let config = parse(&["--disable", "oranges"]).unwrap();
+        let names: Vec<_> = config
+            .item_types
+            .types()
+            .iter()
+            .map(|definition| definition.item_type.name())
+            .collect();
+        assert_eq!(names, ["Apple"]);
+
+        assert_eq!(
+            parse(&["--disable", "banana"]).unwrap_err(),
+            "--disable requires the item type banana to be registered."
+        );
+        assert_eq!(
+            parse(&["--disable", "apple", "--disable", "orange"]).unwrap_err(),
+            "At least one item type must be left enabled."
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How many latencies a `LatencyReservoir` keeps at most. Enough for stable p99 estimates while
+/// keeping memory bounded however long the app runs.
+const RESERVOIR_CAPACITY: usize = 10_000;
+
+/// A uniform random sample of all the latencies recorded so far, kept with reservoir sampling.
+/// Until the reservoir is full it holds every latency, so the percentiles are exact.
+#[derive(Debug, Default)]
+pub struct LatencyReservoir {
+    samples: Vec<Duration>,
+    /// How many latencies have been recorded, including those not kept.
+    recorded: u64,
+}
+
+impl LatencyReservoir {
+    pub fn record(&mut self, latency: Duration, rng: &mut impl Rng) {
+        self.recorded = self.recorded.saturating_add(1);
+
+        if self.samples.len() < RESERVOIR_CAPACITY {
+            self.samples.push(latency);
+            return;
+        }
+
+        // The n-th latency replaces a random kept one with probability capacity / n, which keeps
+        // every latency recorded so far equally likely to be in the sample.
+        let index = rng.gen_range(0..self.recorded);
+
+        if let Some(sample) = self.samples.get_mut(index as usize) {
+            *sample = latency;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.recorded = 0;
+    }
+
+    /// The latencies at each of the given percentiles (0-100), using the nearest-rank method.
+    /// Returns `None` if nothing has been recorded.
+    pub fn percentiles<const N: usize>(&self, percentiles: [f64; N]) -> Option<[Duration; N]> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+
+        Some(percentiles.map(|percentile| {
+            let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_known_latencies() {
+        let mut rng = rand::thread_rng();
+        let mut reservoir = LatencyReservoir::default();
+
+        assert_eq!(reservoir.percentiles([50.0]), None);
+
+        // 1 to 100 ms, recorded out of order.
+        for millis in (1..=100).rev() {
+            reservoir.record(Duration::from_millis(millis), &mut rng);
+        }
+
+        assert_eq!(
+            reservoir.percentiles([0.0, 50.0, 95.0, 99.0, 100.0]),
+            Some([1, 50, 95, 99, 100].map(Duration::from_millis))
+        );
+
+        reservoir.clear();
+        reservoir.record(Duration::from_millis(7), &mut rng);
+        assert_eq!(
+            reservoir.percentiles([50.0, 99.0]),
+            Some([Duration::from_millis(7); 2])
+        );
+    }
+
+    #[test]
+    fn the_reservoir_stays_bounded() {
+        let mut rng = rand::thread_rng();
+        let mut reservoir = LatencyReservoir::default();
+
+        for millis in 0..3 * RESERVOIR_CAPACITY as u64 {
+            reservoir.record(Duration::from_millis(millis), &mut rng);
+        }
+
+        assert_eq!(reservoir.samples.len(), RESERVOIR_CAPACITY);
+        assert_eq!(reservoir.recorded, 3 * RESERVOIR_CAPACITY as u64);
+
+        // A uniform sample of 0 to 30 s has its median somewhere around 15 s.
+        let [p50] = reservoir.percentiles([50.0]).unwrap();
+        assert!(
+            (Duration::from_secs(13)..Duration::from_secs(17)).contains(&p50),
+            "{p50:?}"
+        );
+    }
+}
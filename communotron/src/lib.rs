@@ -0,0 +1,3282 @@
+//! A pipeline that generates containers to fill with fruit, fills them on collector threads and
+//! reports on the filled containers. Embedding programs can observe every filled container by
+//! passing a `CompletionObserver` to `App::run`.
+
+use activity::Activity;
+use autoscale::{Crew, Scaler};
+use clock::{RealSleeper, Sleeper};
+use config::{Autoscale, Config, FillDistribution, FullQueuePolicy, ShutdownPolicy};
+use latency::LatencyReservoir;
+use pickup::{work_sources, WorkSource};
+use rand::{rngs::ThreadRng, Rng};
+use rate_limit::TokenBucket;
+pub use registry::ItemType;
+use registry::{Item, ItemFactory, ItemRegistry, PerType, TypeDefinition};
+use report::{Reporter, Verbosity};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    error::Error,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, IsTerminal, Write},
+    iter,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+    vec,
+};
+
+mod activity;
+mod autoscale;
+pub mod clock;
+pub mod config;
+mod latency;
+mod pickup;
+mod rate_limit;
+pub mod registry;
+pub mod report;
+mod signals;
+pub mod webhook;
+
+/// How many times the reporter is restarted after panicking before we give up on it.
+const MAX_REPORTER_RESTARTS: usize = 3;
+
+/// With fair reporting, how long the reporter waits after a completion for more completions
+/// to interleave it with.
+const FAIR_REPORTING_WINDOW: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+struct FillContainerMessage {
+    /// Identifies the work item in verbose output. Work items are numbered from 1 as they are
+    /// created, starting over when the counters are reset.
+    work_id: u64,
+    created_at: Instant,
+    epoch: u64,
+    item_type: ItemType,
+
+    /// Every slot starts out empty and is filled by the collector.
+    container: Vec<Option<Item>>,
+}
+
+/// Something for `generate_work` to react to.
+#[derive(Debug)]
+enum Input {
+    /// A line entered on stdin.
+    Line(String),
+    /// A work item of the given fruit type and container size, injected by `run_pipeline`
+    /// instead of being made up at random.
+    Work(ItemType, usize),
+    /// Stdin has been closed, e.g. because piped input ran out or Ctrl-D was pressed. Also sent
+    /// once `run_pipeline` has injected all its work.
+    StdinClosed,
+    /// Time to generate a work item automatically. Ticks and stdin lines are independent
+    /// sources of work: while both are active, each of them adds its own work items.
+    Tick,
+    /// A request to stop accepting work and exit, with the reason for it.
+    Shutdown(String),
+}
+
+/// Where `generate_work` sends the containers to be filled.
+enum WorkQueues {
+    /// Every fruit type has its own queue with its own dedicated collector.
+    PerType(PerType<QueueSender<FillContainerMessage>>),
+    /// All fruit types share one queue, drained by a pool of workers that can collect any fruit.
+    /// This way the capacity flows to whichever fruit type has the most work waiting.
+    Shared(QueueSender<FillContainerMessage>),
+}
+
+impl WorkQueues {
+    /// On failure, also returns a description of the queue.
+    fn send(
+        &self,
+        work_order: FillContainerMessage,
+        when_full: FullQueuePolicy,
+    ) -> Result<(), (QueueError, String)> {
+        match self {
+            WorkQueues::PerType(queues) => {
+                let item_type = work_order.item_type.clone();
+
+                queues
+                    .get(&item_type)
+                    .send(work_order, when_full)
+                    .map_err(|e| (e, format!("{item_type} queue ({item_type} collector)")))
+            }
+            WorkQueues::Shared(tx) => tx
+                .send(work_order, when_full)
+                .map_err(|e| (e, "shared queue (all pool workers)".to_string())),
+        }
+    }
+}
+
+/// The sending end of a work queue or result channel, which may only have room for a limited
+/// number of containers.
+enum QueueSender<T> {
+    Unbounded(Sender<T>),
+    Bounded(SyncSender<T>),
+}
+
+// Not derived, as that would require `T: Clone` although only the sender is cloned.
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        match self {
+            QueueSender::Unbounded(tx) => QueueSender::Unbounded(tx.clone()),
+            QueueSender::Bounded(tx) => QueueSender::Bounded(tx.clone()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum QueueError {
+    /// The queue is at capacity and the policy is to drop the work.
+    Full,
+    /// The collectors are gone.
+    Closed,
+}
+
+impl<T> QueueSender<T> {
+    fn send(&self, value: T, when_full: FullQueuePolicy) -> Result<(), QueueError> {
+        match (self, when_full) {
+            (QueueSender::Unbounded(tx), _) => tx.send(value).map_err(|_| QueueError::Closed),
+            (QueueSender::Bounded(tx), FullQueuePolicy::Block) => {
+                tx.send(value).map_err(|_| QueueError::Closed)
+            }
+            (QueueSender::Bounded(tx), FullQueuePolicy::Drop) => {
+                tx.try_send(value).map_err(|e| match e {
+                    TrySendError::Full(_) => QueueError::Full,
+                    TrySendError::Disconnected(_) => QueueError::Closed,
+                })
+            }
+        }
+    }
+
+    /// Sends without ever waiting, handing the value back if the queue is full.
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self {
+            QueueSender::Unbounded(tx) => tx
+                .send(value)
+                .map_err(|SendError(value)| TrySendError::Disconnected(value)),
+            QueueSender::Bounded(tx) => tx.try_send(value),
+        }
+    }
+}
+
+/// Creates a work queue or result channel, limited to `capacity` waiting containers if there is
+/// a capacity.
+fn queue<T>(capacity: Option<usize>) -> (QueueSender<T>, Receiver<T>) {
+    match capacity {
+        None => {
+            let (tx, rx) = mpsc::channel();
+            (QueueSender::Unbounded(tx), rx)
+        }
+        Some(capacity) => {
+            let (tx, rx) = mpsc::sync_channel(capacity);
+            (QueueSender::Bounded(tx), rx)
+        }
+    }
+}
+
+/// Sent by a collector once it has filled a container.
+#[derive(Debug)]
+pub struct ContainerFilledMessage {
+    /// The work items are numbered from 1 in the order they were created, starting over when
+    /// the counters are reset.
+    pub work_id: u64,
+    pub created_at: Instant,
+    /// How many times the counters had been reset when the work item was created.
+    pub epoch: u64,
+    pub container_size: usize,
+    /// After inspection, only the items that passed it.
+    pub items_added: usize,
+    /// How many items inspection removed from the container, or `None` if it was not inspected.
+    pub items_rejected: Option<usize>,
+    /// The combined weight of the items in the container. `None` once inspection has removed
+    /// items, as the inspector only knows how many there were, not what they weighed.
+    pub total_weight_grams: Option<u64>,
+    pub item_type: ItemType,
+}
+
+impl ContainerFilledMessage {
+    /// How many items the collector put into the container, including any removed by inspection.
+    pub fn items_collected(&self) -> usize {
+        self.items_added + self.items_rejected.unwrap_or(0)
+    }
+}
+
+/// Gets to react to every filled container, e.g. to print it or to record it in metrics.
+/// Called on a reporter thread after the stats have been updated for the container. With
+/// per-type reporters, different fruit types are observed from different threads.
+pub trait CompletionObserver: Send + Sync {
+    fn on_completion(&self, message: &ContainerFilledMessage);
+
+    /// Called periodically with the progress so far, every `--summary-every` completions or after
+    /// `--summary-interval` seconds without one, e.g. to drive a UI. Also called on a reporter
+    /// thread. Does nothing unless implemented.
+    fn on_progress(&self, _progress: &ProgressSnapshot) {}
+}
+
+/// Lets two observers react to the same completions, e.g. to print them and post them somewhere.
+impl<A: CompletionObserver, B: CompletionObserver> CompletionObserver for (A, B) {
+    fn on_completion(&self, message: &ContainerFilledMessage) {
+        self.0.on_completion(message);
+        self.1.on_completion(message);
+    }
+
+    fn on_progress(&self, progress: &ProgressSnapshot) {
+        self.0.on_progress(progress);
+        self.1.on_progress(progress);
+    }
+}
+
+/// An observer that may or may not be there, e.g. depending on the command line.
+impl<T: CompletionObserver> CompletionObserver for Option<T> {
+    fn on_completion(&self, message: &ContainerFilledMessage) {
+        if let Some(observer) = self {
+            observer.on_completion(message);
+        }
+    }
+
+    fn on_progress(&self, progress: &ProgressSnapshot) {
+        if let Some(observer) = self {
+            observer.on_progress(progress);
+        }
+    }
+}
+
+/// The counters at one point in time, as shown by the `stats` command.
+#[derive(Debug, Clone)]
+pub struct ProgressSnapshot {
+    pub work_created: u64,
+    /// One entry for every registered fruit type, in the order they were registered.
+    pub per_type: Vec<TypeProgress>,
+    /// Since the start or the most recent reset of the counters.
+    pub elapsed: Duration,
+    /// How much of `elapsed` work was in flight, rather than the app waiting for input.
+    pub active: Duration,
+    pub largest_container: usize,
+    pub most_items_added: usize,
+    pub items_collected: u64,
+    pub items_passed: u64,
+    pub anomalies: u64,
+    /// Filled containers thrown away because their result channel was full.
+    pub results_lost: u64,
+}
+
+/// The counters of one fruit type.
+#[derive(Debug, Clone)]
+pub struct TypeProgress {
+    pub item_type: ItemType,
+    pub completed: u64,
+    pub queued: usize,
+    /// `None` if no containers of this fruit type have been completed yet.
+    pub latency: Option<LatencyPercentiles>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+impl ProgressSnapshot {
+    pub fn work_completed(&self) -> u64 {
+        self.per_type.iter().fold(0, |total, progress| {
+            total.saturating_add(progress.completed)
+        })
+    }
+
+    /// The counters of the given fruit type, if it is registered.
+    pub fn of_type(&self, item_type: &ItemType) -> Option<&TypeProgress> {
+        self.per_type
+            .iter()
+            .find(|progress| progress.item_type == *item_type)
+    }
+
+    /// Completed work items per second over the elapsed time.
+    pub fn wall_throughput(&self) -> f32 {
+        self.work_completed() as f32 / self.elapsed.as_secs_f32()
+    }
+
+    /// Completed work items per second over the time work was in flight.
+    pub fn active_throughput(&self) -> f32 {
+        if self.active.is_zero() {
+            0.0
+        } else {
+            self.work_completed() as f32 / self.active.as_secs_f32()
+        }
+    }
+
+    /// E.g. "3 Apple and 4 Orange", or "1 Apple, 2 Banana and 3 Orange" with more fruit types.
+    fn per_type_counts(&self, count: impl Fn(&TypeProgress) -> String) -> String {
+        let counts: Vec<_> = self
+            .per_type
+            .iter()
+            .map(|progress| format!("{} {}", count(progress), progress.item_type))
+            .collect();
+
+        match counts.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+            None => "0".to_string(),
+        }
+    }
+}
+
+impl Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let latencies = self
+            .per_type
+            .iter()
+            .map(|progress| match progress.latency {
+                Some(LatencyPercentiles { p50, p95, p99 }) => format!(
+                    "{} latency p50 {p50:.1?}, p95 {p95:.1?}, p99 {p99:.1?}",
+                    progress.item_type
+                ),
+                None => format!("{} latency unknown", progress.item_type),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        write!(
+            f,
+            "Stats: {} work items created, {} containers completed, throughput (wall) {:.2} items/s, throughput (active) {:.2} items/s over {:.1?}, {} containers waiting, largest container of size {}, at most {} items added to one, {} items collected of which {} passed inspection, {} anomalies, {} results lost. {latencies}.",
+            self.work_created,
+            self.per_type_counts(|progress| progress.completed.to_string()),
+            self.wall_throughput(),
+            self.active_throughput(),
+            self.active,
+            self.per_type_counts(|progress| progress.queued.to_string()),
+            self.largest_container,
+            self.most_items_added,
+            self.items_collected,
+            self.items_passed,
+            self.anomalies,
+            self.results_lost,
+        )
+    }
+}
+
+/// Prints a line about every filled container, with the progress made so far.
+pub struct PrintingObserver {
+    stats: Arc<Stats>,
+    reporter: Arc<Reporter>,
+    colors: PerType<Option<String>>,
+}
+
+impl PrintingObserver {
+    pub fn new(app: &App) -> Self {
+        Self {
+            stats: app.stats.clone(),
+            reporter: app.reporter.clone(),
+            colors: PerType::new(&app.config.item_types, |definition| {
+                definition.color.clone()
+            }),
+        }
+    }
+}
+
+impl CompletionObserver for PrintingObserver {
+    fn on_completion(&self, message: &ContainerFilledMessage) {
+        let (work_completed, work_created_value) = self.stats.progress();
+
+        let inspection = match message.items_rejected {
+            Some(_) => format!(", {} passed inspection", message.items_added),
+            None => String::new(),
+        };
+
+        let mut line = format!(
+            "Collected {}x {} into a container of size {}{inspection}. {work_completed} of {work_created_value} work items completed ({}).",
+            message.items_collected(),
+            message.item_type,
+            message.container_size,
+            format_percent_completed(work_completed, work_created_value)
+        );
+
+        if self.reporter.verbosity() == Verbosity::Verbose {
+            line.push_str(&format!(
+                " Work item #{} took {:.1?}.",
+                message.work_id,
+                message.created_at.elapsed()
+            ));
+
+            if let Some(total_weight_grams) = message.total_weight_grams {
+                line.push_str(&format!(
+                    " The fruit weighs {total_weight_grams} g in total."
+                ));
+            }
+        }
+
+        let line = match self.colors.get(&message.item_type) {
+            Some(color) => self.reporter.colorize(color, line),
+            None => line,
+        };
+
+        self.reporter.print(Verbosity::Normal, line);
+    }
+
+    /// The periodic summaries only make it to the output in quiet mode, where there are no
+    /// completion lines to show the progress.
+    fn on_progress(&self, progress: &ProgressSnapshot) {
+        if self.reporter.verbosity() == Verbosity::Quiet {
+            self.reporter.print(Verbosity::Quiet, progress);
+        }
+    }
+}
+
+/// Before any work has been created there is nothing to take a percentage of. The completed work
+/// cannot outnumber the created work if the counters come from `Stats::progress`, but should that
+/// ever break, a pending percentage is less misleading than one above 100 %.
+fn format_percent_completed(work_completed: u64, work_created: u64) -> String {
+    if work_created == 0 || work_completed > work_created {
+        return "pending".to_string();
+    }
+
+    let percent_completed = work_completed as f32 / work_created as f32 * 100.0;
+    format!("{percent_completed:.1} %")
+}
+
+/// Counters shared between the work generator, the collectors and the reporter.
+/// The counters are atomics so that taking a snapshot (e.g. for the `stats` command)
+/// never blocks the threads doing the actual work.
+#[derive(Debug)]
+struct Stats {
+    /// Locked to reset the counters, to count a work item as created or completed and to read the
+    /// created and completed counters together, so none of these can interleave.
+    baseline: Mutex<Baseline>,
+    work_created: AtomicU64,
+
+    per_type: PerType<TypeStats>,
+
+    /// The largest completed container and the most items added to one, to characterize the workload.
+    largest_container: AtomicUsize,
+    most_items_added: AtomicUsize,
+
+    /// Items in the completed containers as collected, and those of them that passed inspection.
+    /// The same unless inspection is enabled.
+    items_collected: AtomicU64,
+    items_passed: AtomicU64,
+
+    /// Lets the throughput be measured over the time work was in flight, leaving out the time
+    /// spent waiting for input.
+    activity: Mutex<Activity>,
+
+    /// Completion messages that violated an invariant (e.g. more items than fit in the container).
+    anomalies: AtomicU64,
+
+    /// Filled containers that were thrown away rather than reported, because the reporter lagged
+    /// behind so far that their result channel was full and the policy is to drop.
+    results_lost: AtomicU64,
+
+    /// Set once the reporter has died for good, at which point there is no point accepting more work.
+    reporter_failed: AtomicBool,
+}
+
+/// The counters kept for each fruit type separately.
+#[derive(Debug, Default)]
+struct TypeStats {
+    completed: AtomicU64,
+
+    /// Containers sent to a collector's channel but not yet picked up by the collector.
+    queued: AtomicUsize,
+
+    /// How long the completed containers took from being created to being reported.
+    latencies: Mutex<LatencyReservoir>,
+
+    /// The longest latency of the containers completed since the autoscaler last looked, in
+    /// microseconds. Zero if none were.
+    recent_latency_micros: AtomicU64,
+}
+
+/// The point from which the counters count, moved forward by the `reset` command.
+#[derive(Debug)]
+struct Baseline {
+    started: Instant,
+    /// How many times the counters have been reset.
+    epoch: u64,
+}
+
+impl Stats {
+    fn new(item_types: &ItemRegistry) -> Self {
+        Self {
+            baseline: Mutex::new(Baseline {
+                started: Instant::now(),
+                epoch: 0,
+            }),
+            work_created: AtomicU64::new(0),
+            per_type: PerType::new(item_types, |_| TypeStats::default()),
+            largest_container: AtomicUsize::new(0),
+            most_items_added: AtomicUsize::new(0),
+            items_collected: AtomicU64::new(0),
+            items_passed: AtomicU64::new(0),
+            activity: Mutex::new(Activity::new()),
+            anomalies: AtomicU64::new(0),
+            results_lost: AtomicU64::new(0),
+            reporter_failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Zeroes the counters, except for the queued containers which are still really queued.
+    /// Work created before the reset does not count towards the new baseline when it completes,
+    /// so the completed containers can never outnumber the created ones.
+    fn reset(&self) {
+        let mut baseline = lock_stats(&self.baseline);
+        baseline.started = Instant::now();
+        baseline.epoch += 1;
+
+        self.work_created.store(0, Ordering::Relaxed);
+        self.largest_container.store(0, Ordering::Relaxed);
+        self.most_items_added.store(0, Ordering::Relaxed);
+        self.items_collected.store(0, Ordering::Relaxed);
+        self.items_passed.store(0, Ordering::Relaxed);
+        *lock_stats(&self.activity) = Activity::new();
+        self.anomalies.store(0, Ordering::Relaxed);
+        self.results_lost.store(0, Ordering::Relaxed);
+
+        for (_, type_stats) in self.per_type.iter() {
+            type_stats.completed.store(0, Ordering::Relaxed);
+            lock_stats(&type_stats.latencies).clear();
+        }
+    }
+
+    fn snapshot(&self) -> ProgressSnapshot {
+        // Under the baseline lock, like `progress`, so the completed never exceed the created.
+        let baseline = lock_stats(&self.baseline);
+
+        let mut snapshot = ProgressSnapshot {
+            work_created: self.work_created.load(Ordering::Relaxed),
+            per_type: self
+                .per_type
+                .iter()
+                .map(|(item_type, type_stats)| TypeProgress {
+                    item_type: item_type.clone(),
+                    completed: type_stats.completed.load(Ordering::Relaxed),
+                    queued: type_stats.queued.load(Ordering::Relaxed),
+                    latency: None,
+                })
+                .collect(),
+            elapsed: baseline.started.elapsed(),
+            active: lock_stats(&self.activity).active(),
+            largest_container: self.largest_container.load(Ordering::Relaxed),
+            most_items_added: self.most_items_added.load(Ordering::Relaxed),
+            items_collected: self.items_collected.load(Ordering::Relaxed),
+            items_passed: self.items_passed.load(Ordering::Relaxed),
+            anomalies: self.anomalies.load(Ordering::Relaxed),
+            results_lost: self.results_lost.load(Ordering::Relaxed),
+        };
+
+        drop(baseline);
+
+        for progress in &mut snapshot.per_type {
+            let percentiles = lock_stats(&self.per_type.get(&progress.item_type).latencies)
+                .percentiles([50.0, 95.0, 99.0]);
+
+            progress.latency =
+                percentiles.map(|[p50, p95, p99]| LatencyPercentiles { p50, p95, p99 });
+        }
+
+        snapshot
+    }
+
+    fn completed(&self, item_type: &ItemType) -> &AtomicU64 {
+        &self.per_type.get(item_type).completed
+    }
+
+    fn latencies(&self, item_type: &ItemType) -> &Mutex<LatencyReservoir> {
+        &self.per_type.get(item_type).latencies
+    }
+
+    fn queued(&self, item_type: &ItemType) -> &AtomicUsize {
+        &self.per_type.get(item_type).queued
+    }
+
+    /// The longest latency since the previous call, if any containers were completed since then.
+    fn take_recent_latency(&self, item_type: &ItemType) -> Option<Duration> {
+        let micros = self
+            .per_type
+            .get(item_type)
+            .recent_latency_micros
+            .swap(0, Ordering::Relaxed);
+
+        (micros > 0).then(|| Duration::from_micros(micros))
+    }
+
+    fn total_completed(&self) -> u64 {
+        self.per_type.iter().fold(0, |total, (_, type_stats)| {
+            total.saturating_add(type_stats.completed.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Work items created but not ended yet, i.e. still queued or being filled.
+    fn outstanding(&self) -> u64 {
+        let books = self.books();
+        books.created.saturating_sub(books.ended())
+    }
+
+    /// The created work items and what became of them. Read under the baseline lock, under which
+    /// they are all counted, so the counters are consistent with each other.
+    fn books(&self) -> Books {
+        let _baseline = lock_stats(&self.baseline);
+
+        Books {
+            created: self.work_created.load(Ordering::Relaxed),
+            completed: self.total_completed(),
+            lost: self.results_lost.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The completed and created work items, as a pair in which the completed never exceed the
+    /// created. A work item is counted as created before it is sent to a collector, and its
+    /// completion is counted under the baseline lock. Reading both under the same lock therefore
+    /// always sees the creation of every completion it sees.
+    fn progress(&self) -> (u64, u64) {
+        let _baseline = lock_stats(&self.baseline);
+
+        (
+            self.total_completed(),
+            self.work_created.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The work items created since the baseline and the terminal outcomes they have reached. Work
+/// dropped because its queue was full never counts as created in the first place.
+#[derive(Debug, Clone, Copy)]
+struct Books {
+    created: u64,
+    completed: u64,
+    /// Thrown away because their result channel was full.
+    lost: u64,
+}
+
+impl Books {
+    /// The work items that have reached a terminal outcome. Every outcome is added up here, so
+    /// that everything checking on the work agrees on what counts as ended; a new way for work to
+    /// end has to be added here too.
+    fn ended(self) -> u64 {
+        self.completed.saturating_add(self.lost)
+    }
+}
+
+/// Increments a counter, stopping at the maximum value instead of wrapping around to zero.
+/// A `u64` will not realistically get there but if it ever does, a stuck counter is far less
+/// misleading than one that suddenly restarts from zero. Returns the new value.
+fn saturating_increment(counter: &AtomicU64) -> u64 {
+    saturating_add(counter, 1)
+}
+
+/// Like `saturating_increment`, but by any amount.
+fn saturating_add(counter: &AtomicU64, amount: u64) -> u64 {
+    let result = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+        (value < u64::MAX).then(|| value.saturating_add(amount))
+    });
+
+    match result {
+        Ok(previous) if previous.saturating_add(amount) == u64::MAX => {
+            eprintln!("A counter has reached its maximum value and will not increase any further.");
+            u64::MAX
+        }
+        Ok(previous) => previous + amount,
+        Err(value) => value,
+    }
+}
+
+/// Locks one of the mutexes in `Stats`. A reporter that panics while holding one is restarted with
+/// the same stats, so the lock is taken even if that poisoned it. What the mutexes guard is only
+/// ever changed in single steps, which a panic cannot leave half done.
+fn lock_stats<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The whole pipeline: work is generated from stdin, filled by the collectors and reported on.
+pub struct App {
+    config: Config,
+    stats: Arc<Stats>,
+    reporter: Arc<Reporter>,
+    sleeper: Arc<dyn Sleeper>,
+}
+
+impl App {
+    /// Writes the progress output to stdout, or to the file given with `--output`, which fails
+    /// if the file cannot be created.
+    pub fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        let Some(path) = &config.output else {
+            // Escape codes would only be noise in a file or another program's input.
+            let color = io::stdout().is_terminal();
+            return Ok(Self::with_output(config, Box::new(io::stdout()), color));
+        };
+
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create output file {}: {e}", path.display()))?;
+
+        Ok(Self::with_output(config, Box::new(file), false))
+    }
+
+    /// Like `new`, but the progress output goes to `output`, e.g. a buffer to be inspected
+    /// afterwards. Colors are only used if `terminal` says the output is shown on a terminal.
+    pub fn with_output(config: Config, output: Box<dyn Write + Send>, terminal: bool) -> Self {
+        let reporter = Arc::new(Reporter::new(
+            config.verbosity,
+            config.summary_every,
+            config.summary_interval,
+            config.heartbeat_interval,
+            config.color && terminal,
+            output,
+        ));
+
+        Self {
+            stats: Arc::new(Stats::new(&config.item_types)),
+            config,
+            reporter,
+            sleeper: Arc::new(RealSleeper),
+        }
+    }
+
+    /// Has the collectors wait out the fill delays with `sleeper` instead of really waiting,
+    /// e.g. with a `clock::ManualClock` to control the timing in a test.
+    pub fn with_sleeper(mut self, sleeper: Arc<dyn Sleeper>) -> Self {
+        self.sleeper = sleeper;
+        self
+    }
+
+    /// Runs until stdin is closed or a shutdown is requested. Every filled container is passed
+    /// to the observer.
+    pub fn run(self, observer: impl CompletionObserver + 'static) -> Result<(), Box<dyn Error>> {
+        self.reporter.print(
+            Verbosity::Quiet,
+            "Press enter to give the app more work to do. Type \"stats\" to see progress so far, \"reset\" to reset the counters, \"config\" to see the fill delays, \"delay apple 500\" to change one or \"pause orange\" and \"resume orange\" to stop and restart collecting one fruit type.",
+        );
+
+        self.run_with_input(observer, |input_tx| {
+            signals::forward_shutdown_signals(input_tx.clone());
+            thread::spawn(move || read_stdin(input_tx));
+        })?;
+
+        Ok(())
+    }
+
+    /// Like `run`, but the input comes from whatever `start_input` sets up with the sender it is
+    /// given, instead of from stdin. Returns the stats as they were at the end.
+    fn run_with_input(
+        self,
+        observer: impl CompletionObserver + 'static,
+        start_input: impl FnOnce(Sender<Input>),
+    ) -> Result<ProgressSnapshot, Box<dyn Error>> {
+        let App {
+            config,
+            stats,
+            reporter,
+            sleeper,
+        } = self;
+
+        let observer = Arc::new(observer);
+
+        let (ready_tx, ready_receivers) = if config.per_type_reporters {
+            let mut ready_receivers = Vec::new();
+
+            let ready_senders = PerType::new(&config.item_types, |definition| {
+                let (tx, rx) = queue::<ContainerFilledMessage>(config.result_capacity);
+                ready_receivers.push((format!("{} reporter", definition.item_type), rx));
+                tx
+            });
+
+            (
+                ReadySenders::new(ReadyChannels::PerType(ready_senders), &config),
+                ready_receivers,
+            )
+        } else {
+            let (ready_tx, ready_rx) = queue::<ContainerFilledMessage>(config.result_capacity);
+
+            (
+                ReadySenders::new(ReadyChannels::Shared(ready_tx), &config),
+                vec![("Reporter".to_string(), ready_rx)],
+            )
+        };
+
+        let delays = Arc::new(FillDelays::new(&config.item_types, sleeper));
+        let pauses = Arc::new(Pauses::new(&config.item_types));
+
+        // With inspection, the collectors hand their containers to the inspector, which passes
+        // them on to the reporters.
+        let (collected_tx, inspector_thread) = match config.inspection_reject_rate {
+            Some(reject_rate) => {
+                let (collected_tx, collected_rx) =
+                    queue::<ContainerFilledMessage>(config.result_capacity);
+                let stats = stats.clone();
+                let inspector_thread =
+                    thread::spawn(move || inspect(collected_rx, ready_tx, stats, reject_rate));
+
+                (
+                    ReadySenders::new(ReadyChannels::Shared(collected_tx), &config),
+                    Some(inspector_thread),
+                )
+            }
+            None => (ready_tx, None),
+        };
+
+        let (work_queues, mut collector_threads) = match (config.workers, config.autoscale) {
+            (Some(workers), _) => {
+                spawn_worker_pool(workers, &config, collected_tx, &stats, &delays, &pauses)
+            }
+            (None, Some(autoscale)) => spawn_autoscaled_collectors(
+                autoscale,
+                &config,
+                collected_tx,
+                &stats,
+                &delays,
+                &pauses,
+                &reporter,
+            ),
+            (None, None) => {
+                spawn_per_type_collectors(&config, collected_tx, &stats, &delays, &pauses)
+            }
+        };
+
+        // Joined after the collectors, as it only finishes once they have all finished.
+        collector_threads.extend(
+            inspector_thread.map(|inspector_thread| ("Inspector".to_string(), inspector_thread)),
+        );
+
+        // The reporters all update the same stats, which is what makes the overall percentage
+        // add up when each fruit type has its own reporter.
+        let results_threads: Vec<_> = ready_receivers
+            .into_iter()
+            .map(|(name, ready_rx)| {
+                let stats = stats.clone();
+                let reporter = reporter.clone();
+                let observer = observer.clone();
+                let fair_reporting = config.fair_reporting;
+
+                let results_thread = thread::spawn(move || {
+                    supervise_reporter(ready_rx, stats, reporter, observer, fair_reporting)
+                });
+
+                (name, results_thread)
+            })
+            .collect();
+
+        let (input_tx, input_rx) = mpsc::channel::<Input>();
+
+        if let Some(auto_interval) = config.auto_interval {
+            let input_tx = input_tx.clone();
+
+            thread::spawn(move || loop {
+                thread::sleep(auto_interval);
+
+                if input_tx.send(Input::Tick).is_err() {
+                    // Work is no longer being generated, we are shutting down.
+                    return;
+                }
+            });
+        }
+
+        if let Some(max_runtime) = config.max_runtime {
+            let input_tx = input_tx.clone();
+
+            thread::spawn(move || {
+                thread::sleep(max_runtime);
+                let reason = format!("the maximum runtime of {max_runtime:?} was reached");
+                _ = input_tx.send(Input::Shutdown(reason));
+            });
+        }
+
+        start_input(input_tx);
+
+        let shutdown_requested = generate_work(
+            input_rx,
+            work_queues,
+            &config,
+            stats.clone(),
+            &delays,
+            &pauses,
+            &reporter,
+        )?;
+
+        if shutdown_requested && config.shutdown_policy == ShutdownPolicy::Abort {
+            // The collectors and the reporter are simply left behind, they end with the process.
+            print_stats(&stats, &reporter);
+
+            if config.verify_totals {
+                reporter.print(
+                    Verbosity::Quiet,
+                    "Totals not verified, as the queued work was abandoned.",
+                );
+            }
+
+            return Ok(stats.snapshot());
+        }
+
+        // A paused collector would never finish the queued work we are about to wait for.
+        pauses.resume_all();
+
+        for (name, collector_thread) in collector_threads {
+            if let Err(collector_e) = collector_thread.join() {
+                reporter.print(Verbosity::Quiet, format!("{name} failed: {collector_e:?}"));
+            }
+        }
+
+        for (name, results_thread) in results_threads {
+            if let Err(results_e) = results_thread.join() {
+                reporter.print(
+                    Verbosity::Quiet,
+                    format!("{name} failed, results were not reported: {results_e:?}"),
+                );
+            }
+        }
+
+        if shutdown_requested {
+            print_stats(&stats, &reporter);
+        }
+
+        if config.verify_totals {
+            verify_totals(&stats, &reporter);
+        }
+
+        Ok(stats.snapshot())
+    }
+}
+
+/// Runs the whole pipeline on the given work items, each a fruit type and a container size,
+/// instead of on input from stdin. Returns once all of them have been filled and reported, with
+/// the final stats. With zero fill delays in the config this takes next to no time, which makes
+/// it suitable for tests.
+pub fn run_pipeline(
+    config: Config,
+    work: impl IntoIterator<Item = (ItemType, usize)>,
+) -> Result<ProgressSnapshot, Box<dyn Error>> {
+    run_pipeline_with(config, work, Arc::new(RealSleeper), SilentObserver)
+}
+
+/// Like `run_pipeline`, but the fill delays are waited out with `sleeper` and every filled
+/// container is passed to the observer. With a `clock::ManualClock`, the containers complete in
+/// the order their fill delays dictate without any real waiting.
+pub fn run_pipeline_with(
+    config: Config,
+    work: impl IntoIterator<Item = (ItemType, usize)>,
+    sleeper: Arc<dyn Sleeper>,
+    observer: impl CompletionObserver + 'static,
+) -> Result<ProgressSnapshot, Box<dyn Error>> {
+    let work: Vec<_> = work.into_iter().collect();
+
+    if work.iter().any(|&(_, container_size)| container_size == 0) {
+        return Err("Containers must have a size of at least 1.".into());
+    }
+
+    if let Some((item_type, _)) = work
+        .iter()
+        .find(|(item_type, _)| config.item_types.get(item_type).is_none())
+    {
+        return Err(format!("The item type {item_type} is not registered.").into());
+    }
+
+    let app = App::new(config)?.with_sleeper(sleeper);
+
+    app.run_with_input(observer, move |input_tx| {
+        // The channel is unbounded, so everything can be queued up front.
+        for (item_type, container_size) in work {
+            _ = input_tx.send(Input::Work(item_type, container_size));
+        }
+
+        _ = input_tx.send(Input::StdinClosed);
+    })
+}
+
+/// Does not react to completions at all, for when only the final stats matter.
+struct SilentObserver;
+
+impl CompletionObserver for SilentObserver {
+    fn on_completion(&self, _message: &ContainerFilledMessage) {}
+}
+
+/// Upper limit for the fill delays set with the `delay` command, so a typo cannot stall a
+/// collector for hours.
+const MAX_FILL_DELAY: Duration = Duration::from_secs(60);
+
+/// How long the collectors take to fill a container with each fruit type. The collectors read
+/// this before filling every container, so the `delay` command takes effect for the next one.
+struct FillDelays {
+    millis: PerType<AtomicU64>,
+    /// What the collectors wait out the delays with.
+    sleeper: Arc<dyn Sleeper>,
+}
+
+impl FillDelays {
+    fn new(item_types: &ItemRegistry, sleeper: Arc<dyn Sleeper>) -> Self {
+        Self {
+            millis: PerType::new(item_types, |definition| {
+                AtomicU64::new(definition.fill_delay.as_millis() as u64)
+            }),
+            sleeper,
+        }
+    }
+
+    /// Takes as long as filling a container of the fruit type currently does.
+    fn wait(&self, item_type: &ItemType) {
+        self.sleeper.sleep(self.get(item_type));
+    }
+
+    fn get(&self, item_type: &ItemType) -> Duration {
+        Duration::from_millis(self.millis.get(item_type).load(Ordering::Relaxed))
+    }
+
+    fn set(&self, item_type: &ItemType, delay: Duration) {
+        self.millis
+            .get(item_type)
+            .store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Which fruit types the collectors have been told to stop filling with the `pause` command.
+/// A paused collector holds on to the next work order without filling it, so the rest of that
+/// fruit type's work stays queued until `resume`.
+#[derive(Debug)]
+struct Pauses {
+    flags: PerType<AtomicBool>,
+
+    /// Held while changing a flag and while a collector goes to sleep on it, so the collector
+    /// cannot miss a resume that happens between checking the flag and waiting.
+    lock: Mutex<()>,
+    resumed: Condvar,
+}
+
+impl Pauses {
+    fn new(item_types: &ItemRegistry) -> Self {
+        Self {
+            flags: PerType::new(item_types, |_| AtomicBool::new(false)),
+            lock: Mutex::new(()),
+            resumed: Condvar::new(),
+        }
+    }
+
+    fn set(&self, item_type: &ItemType, paused: bool) {
+        let _lock = self.lock.lock().unwrap();
+        self.flags.get(item_type).store(paused, Ordering::Relaxed);
+        self.resumed.notify_all();
+    }
+
+    fn resume_all(&self) {
+        let _lock = self.lock.lock().unwrap();
+
+        for (_, flag) in self.flags.iter() {
+            flag.store(false, Ordering::Relaxed);
+        }
+
+        self.resumed.notify_all();
+    }
+
+    /// Blocks the calling collector for as long as the fruit type is paused.
+    fn wait_while_paused(&self, item_type: &ItemType) {
+        let flag = self.flags.get(item_type);
+
+        // The common case of not being paused does not need the lock.
+        if !flag.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut lock = self.lock.lock().unwrap();
+
+        while flag.load(Ordering::Relaxed) {
+            lock = self.resumed.wait(lock).unwrap();
+        }
+    }
+}
+
+/// Where the collectors send the filled containers, and what they do if the reporter has fallen so
+/// far behind that there is no room for them.
+#[derive(Clone)]
+struct ReadySenders {
+    channels: ReadyChannels,
+    when_full: FullQueuePolicy,
+}
+
+/// Unless each fruit type has its own reporter, all fruit types lead to the same one.
+#[derive(Clone)]
+enum ReadyChannels {
+    PerType(PerType<QueueSender<ContainerFilledMessage>>),
+    Shared(QueueSender<ContainerFilledMessage>),
+}
+
+impl ReadySenders {
+    fn new(channels: ReadyChannels, config: &Config) -> Self {
+        Self {
+            channels,
+            when_full: config.when_results_full,
+        }
+    }
+
+    /// Never blocks without saying so, so that a lagging reporter does not silently freeze the
+    /// collectors. With `FullQueuePolicy::Drop`, a container that does not fit is counted as lost
+    /// and `QueueError::Full` is returned, which the sender can carry on after.
+    fn send(&self, message: ContainerFilledMessage, stats: &Stats) -> Result<(), QueueError> {
+        let tx = match &self.channels {
+            ReadyChannels::PerType(senders) => senders.get(&message.item_type),
+            ReadyChannels::Shared(tx) => tx,
+        };
+
+        let message = match tx.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(_)) => return Err(QueueError::Closed),
+            Err(TrySendError::Full(message)) => message,
+        };
+
+        match self.when_full {
+            FullQueuePolicy::Block => {
+                eprintln!(
+                    "Result channel full, holding {} container #{} until the reporter catches up.",
+                    message.item_type, message.work_id
+                );
+
+                tx.send(message, FullQueuePolicy::Block)
+            }
+            FullQueuePolicy::Drop => {
+                eprintln!(
+                    "Result channel full, {} container #{} dropped without being reported.",
+                    message.item_type, message.work_id
+                );
+
+                // Like completions, only counted if the work item counts towards the baseline.
+                let baseline = lock_stats(&stats.baseline);
+
+                if message.epoch == baseline.epoch {
+                    saturating_increment(&stats.results_lost);
+                }
+
+                Err(QueueError::Full)
+            }
+        }
+    }
+}
+
+type CollectorThreads = Vec<(String, JoinHandle<()>)>;
+
+fn spawn_per_type_collectors(
+    config: &Config,
+    ready_tx: ReadySenders,
+    stats: &Arc<Stats>,
+    delays: &Arc<FillDelays>,
+    pauses: &Arc<Pauses>,
+) -> (WorkQueues, CollectorThreads) {
+    let fill_distribution = config.fill_distribution;
+    let mut collector_threads = Vec::new();
+
+    let queues = PerType::new(&config.item_types, |definition| {
+        let (tx, rx) = queue::<FillContainerMessage>(config.queue_capacity);
+
+        let name = format!("{} collector", definition.item_type);
+        let definition = definition.clone();
+        let ready_tx = ready_tx.clone();
+        let stats = stats.clone();
+        let delays = delays.clone();
+        let pauses = pauses.clone();
+
+        let collector_thread = thread::spawn(move || {
+            collect(
+                rx,
+                ready_tx,
+                stats,
+                delays,
+                pauses,
+                fill_distribution,
+                definition,
+            )
+        });
+
+        collector_threads.push((name, collector_thread));
+
+        tx
+    });
+
+    (WorkQueues::PerType(queues), collector_threads)
+}
+
+/// Like `spawn_per_type_collectors`, but each fruit type starts out with the minimum number of
+/// collectors sharing its queue, and an autoscaler thread adjusts the number as the work comes
+/// and goes. The autoscaler is among the returned threads and only finishes once all the
+/// collectors it spawned have.
+fn spawn_autoscaled_collectors(
+    autoscale: Autoscale,
+    config: &Config,
+    ready_tx: ReadySenders,
+    stats: &Arc<Stats>,
+    delays: &Arc<FillDelays>,
+    pauses: &Arc<Pauses>,
+    reporter: &Arc<Reporter>,
+) -> (WorkQueues, CollectorThreads) {
+    let fill_distribution = config.fill_distribution;
+    let mut collector_threads = Vec::new();
+    let mut crews = Vec::new();
+
+    let spawn_collector = {
+        let stats = stats.clone();
+        let delays = delays.clone();
+        let pauses = pauses.clone();
+
+        move |crew: Arc<Crew<FillContainerMessage>>, definition: TypeDefinition| {
+            crew.join();
+
+            let ready_tx = ready_tx.clone();
+            let stats = stats.clone();
+            let delays = delays.clone();
+            let pauses = pauses.clone();
+
+            thread::spawn(move || {
+                collect(
+                    iter::from_fn(|| crew.next()),
+                    ready_tx,
+                    stats,
+                    delays,
+                    pauses,
+                    fill_distribution,
+                    definition,
+                )
+            })
+        }
+    };
+
+    let queues = PerType::new(&config.item_types, |definition| {
+        let (tx, rx) = queue::<FillContainerMessage>(config.queue_capacity);
+        let crew = Arc::new(Crew::new(rx, autoscale.min_collectors));
+
+        for _ in 0..autoscale.min_collectors {
+            let collector_thread = spawn_collector(crew.clone(), definition.clone());
+            collector_threads.push((
+                format!("{} collector", definition.item_type),
+                collector_thread,
+            ));
+        }
+
+        crews.push((definition.clone(), crew));
+
+        tx
+    });
+
+    let stats = stats.clone();
+    let reporter = reporter.clone();
+
+    let autoscaler_thread = thread::spawn(move || {
+        autoscale_collectors(crews, autoscale, spawn_collector, stats, reporter)
+    });
+
+    collector_threads.push(("Autoscaler".to_string(), autoscaler_thread));
+
+    (WorkQueues::PerType(queues), collector_threads)
+}
+
+/// Checks the backlog and latency of every fruit type at regular intervals and has its crew of
+/// collectors grow or shrink accordingly. Returns once every work queue has been closed and
+/// drained, after joining the collectors it spawned.
+fn autoscale_collectors(
+    crews: Vec<(TypeDefinition, Arc<Crew<FillContainerMessage>>)>,
+    autoscale: Autoscale,
+    spawn_collector: impl Fn(Arc<Crew<FillContainerMessage>>, TypeDefinition) -> JoinHandle<()>,
+    stats: Arc<Stats>,
+    reporter: Arc<Reporter>,
+) {
+    let mut scalers: Vec<_> = crews.iter().map(|_| Scaler::new(autoscale)).collect();
+    let mut spawned: CollectorThreads = Vec::new();
+
+    let join = |threads: CollectorThreads| {
+        for (name, collector_thread) in threads {
+            if let Err(collector_e) = collector_thread.join() {
+                reporter.print(Verbosity::Quiet, format!("{name} failed: {collector_e:?}"));
+            }
+        }
+    };
+
+    while !crews.iter().all(|(_, crew)| crew.is_closed()) {
+        thread::sleep(autoscale::CHECK_INTERVAL);
+
+        for ((definition, crew), scaler) in crews.iter().zip(&mut scalers) {
+            let item_type = &definition.item_type;
+            let backlog = stats.queued(item_type).load(Ordering::Relaxed);
+            let latency = stats.take_recent_latency(item_type);
+
+            if crew.is_closed() {
+                continue;
+            }
+
+            let before = scaler.collectors();
+
+            let Some(collectors) = scaler.check(backlog, latency) else {
+                continue;
+            };
+
+            crew.set_target(collectors);
+
+            // Surplus collectors that have not noticed yet are simply kept on.
+            for _ in crew.members()..collectors {
+                let collector_thread = spawn_collector(crew.clone(), definition.clone());
+                spawned.push((format!("{item_type} collector"), collector_thread));
+            }
+
+            let latency = latency.map_or(String::new(), |latency| {
+                format!(", slowest recent completion took {latency:.1?}")
+            });
+
+            reporter.print(
+                Verbosity::Normal,
+                format!(
+                    "Scaled the {item_type} collectors {} to {collectors}, {backlog} containers waiting{latency}.",
+                    if collectors > before { "up" } else { "down" }
+                ),
+            );
+        }
+
+        // Retired collectors are joined as we go, so their handles do not pile up.
+        let (finished, running) = spawned
+            .into_iter()
+            .partition(|(_, collector_thread)| collector_thread.is_finished());
+
+        join(finished);
+        spawned = running;
+    }
+
+    join(spawned);
+}
+
+fn spawn_worker_pool(
+    workers: usize,
+    config: &Config,
+    ready_tx: ReadySenders,
+    stats: &Arc<Stats>,
+    delays: &Arc<FillDelays>,
+    pauses: &Arc<Pauses>,
+) -> (WorkQueues, CollectorThreads) {
+    let (work_tx, work_rx) = queue::<FillContainerMessage>(config.queue_capacity);
+    let (work_sources, dispatcher_thread) = work_sources(work_rx, workers, config.worker_pickup);
+    let fill_distribution = config.fill_distribution;
+    let factories = PerType::new(&config.item_types, |definition| definition.new_item.clone());
+
+    let mut worker_threads: CollectorThreads = (1..)
+        .zip(work_sources)
+        .map(|(worker, work_source)| {
+            let ready_tx = ready_tx.clone();
+            let stats = stats.clone();
+            let delays = delays.clone();
+            let pauses = pauses.clone();
+            let factories = factories.clone();
+
+            let worker_thread = thread::spawn(move || {
+                collect_any(
+                    work_source,
+                    ready_tx,
+                    stats,
+                    delays,
+                    pauses,
+                    fill_distribution,
+                    factories,
+                )
+            });
+
+            (format!("Worker {worker}"), worker_thread)
+        })
+        .collect();
+
+    worker_threads.extend(
+        dispatcher_thread.map(|dispatcher_thread| ("Dispatcher".to_string(), dispatcher_thread)),
+    );
+
+    (WorkQueues::Shared(work_tx), worker_threads)
+}
+
+/// Reads stdin on its own thread, so that `generate_work` can also react to other input
+/// while no line is being entered.
+fn read_stdin(input_tx: Sender<Input>) {
+    for line in io::stdin().lines() {
+        let Ok(line) = line else {
+            // Stdin cannot be read any further, which is as good as it having been closed.
+            break;
+        };
+
+        if input_tx.send(Input::Line(line)).is_err() {
+            return;
+        }
+    }
+
+    _ = input_tx.send(Input::StdinClosed);
+}
+
+fn parse_item_type(name: &str, item_types: &ItemRegistry) -> Result<ItemType, String> {
+    item_types
+        .find(name)
+        .map(|definition| definition.item_type.clone())
+        .ok_or_else(|| format!("Unknown fruit type: {name}."))
+}
+
+/// Parses the arguments of the `delay` control word, e.g. `apple 500`.
+fn parse_delay(args: &[&str], item_types: &ItemRegistry) -> Result<(ItemType, Duration), String> {
+    let [item_type, millis] = args else {
+        return Err("Expected a fruit type and a delay.".to_string());
+    };
+
+    let item_type = parse_item_type(item_type, item_types)?;
+
+    let delay = millis
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid delay: {millis}."))?;
+
+    if delay > MAX_FILL_DELAY {
+        return Err(format!("The delay cannot exceed {MAX_FILL_DELAY:?}."));
+    }
+
+    Ok((item_type, delay))
+}
+
+/// Returning drops the work queues, which lets the collectors finish the queued work and exit.
+/// Returns whether this is because a shutdown was requested.
+fn generate_work(
+    input_rx: Receiver<Input>,
+    work_queues: WorkQueues,
+    config: &Config,
+    stats: Arc<Stats>,
+    delays: &FillDelays,
+    pauses: &Pauses,
+    reporter: &Reporter,
+) -> Result<bool, Box<dyn Error>> {
+    let mut rng = rand::thread_rng();
+    let mut rate_limit = config.gen_rate.map(TokenBucket::new);
+
+    loop {
+        let (input, requested_work) = match input_rx.recv() {
+            Ok(Input::Line(line)) => (line, None),
+            Ok(Input::Work(item_type, container_size)) => {
+                (String::new(), Some((item_type, container_size)))
+            }
+            // With automatic generation, work keeps coming without stdin, so only stop at EOF
+            // if stdin is the only source of work. The collectors then finish what is queued.
+            Ok(Input::StdinClosed) if config.auto_interval.is_none() => return Ok(false),
+            Ok(Input::StdinClosed) => continue,
+            // A tick generates work just like pressing enter does.
+            Ok(Input::Tick) => (String::new(), None),
+            Ok(Input::Shutdown(reason)) => {
+                let action = match config.shutdown_policy {
+                    ShutdownPolicy::Drain => "finishing the queued work before exiting",
+                    ShutdownPolicy::Abort => "abandoning the queued work",
+                };
+
+                reporter.print(
+                    Verbosity::Quiet,
+                    format!("Shutting down because {reason}, {action}."),
+                );
+                return Ok(true);
+            }
+            // No source of input is left, so no more work can ever arrive.
+            Err(_) => return Ok(false),
+        };
+
+        if stats.reporter_failed.load(Ordering::Relaxed) {
+            eprintln!("Results are no longer being reported, not accepting any more work.");
+            return Ok(false);
+        }
+
+        if input.trim() == "stats" {
+            print_stats(&stats, reporter);
+            continue;
+        }
+
+        if input.trim() == "reset" {
+            // Containers still being filled complete after this but do not count. So right after
+            // a reset the percentage only covers new work, and it can reach 100 % while old work
+            // is still being reported.
+            stats.reset();
+            reporter.print(
+                Verbosity::Quiet,
+                "Counters reset. Work created before now no longer counts when it completes.",
+            );
+            continue;
+        }
+
+        if input.trim() == "config" {
+            let fill_delays = config
+                .item_types
+                .types()
+                .iter()
+                .map(|definition| {
+                    let item_type = &definition.item_type;
+                    format!("{item_type} takes {:?} to fill", delays.get(item_type))
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            reporter.print(Verbosity::Quiet, format!("Config: {fill_delays}."));
+            continue;
+        }
+
+        if let ["delay", args @ ..] = input.split_whitespace().collect::<Vec<_>>().as_slice() {
+            match parse_delay(args, &config.item_types) {
+                Ok((item_type, delay)) => {
+                    delays.set(&item_type, delay);
+                    reporter.print(
+                        Verbosity::Quiet,
+                        format!("{item_type} containers filled from now on take {delay:?}."),
+                    );
+                }
+                Err(e) => eprintln!(
+                    "{e} Usage: delay {} <milliseconds>",
+                    config.item_types.usage_names()
+                ),
+            }
+
+            continue;
+        }
+
+        if let [command @ ("pause" | "resume"), args @ ..] =
+            input.split_whitespace().collect::<Vec<_>>().as_slice()
+        {
+            let paused = *command == "pause";
+
+            let usage_names = config.item_types.usage_names();
+
+            match args {
+                [name] => match parse_item_type(name, &config.item_types) {
+                    Ok(item_type) => {
+                        pauses.set(&item_type, paused);
+
+                        let state = if paused { "paused" } else { "resumed" };
+                        reporter
+                            .print(Verbosity::Quiet, format!("{item_type} collection {state}."));
+                    }
+                    Err(e) => eprintln!("{e} Usage: {command} {usage_names}"),
+                },
+                _ => eprintln!("Expected a fruit type. Usage: {command} {usage_names}"),
+            }
+
+            continue;
+        }
+
+        // Other than control words, we do not care what the input is.
+        // We just generate more work every time enter is pressed.
+        if let Some(rate_limit) = &mut rate_limit {
+            rate_limit.acquire();
+        }
+
+        // The work item must be counted as created before it is sent, see `Stats::progress`.
+        // Doing so under the baseline lock also keeps the ID and the epoch consistent with a reset.
+        let (work_id, epoch) = {
+            let baseline = lock_stats(&stats.baseline);
+            (saturating_increment(&stats.work_created), baseline.epoch)
+        };
+        let created_at = Instant::now();
+
+        let (item_type, container_size) = requested_work.unwrap_or_else(|| {
+            let item_types = config.item_types.types();
+            let item_type = item_types[rng.gen_range(0..item_types.len())]
+                .item_type
+                .clone();
+
+            (item_type, rng.gen_range(config.container_sizes()))
+        });
+
+        stats.queued(&item_type).fetch_add(1, Ordering::Relaxed);
+
+        let work_order = FillContainerMessage {
+            work_id,
+            created_at,
+            epoch,
+            item_type: item_type.clone(),
+            container: vec![None; container_size],
+        };
+
+        match work_queues.send(work_order, config.when_full) {
+            Ok(()) => {}
+            Err((QueueError::Full, _)) => {
+                // The work never existed as far as the counters are concerned.
+                stats.work_created.fetch_sub(1, Ordering::Relaxed);
+                stats.queued(&item_type).fetch_sub(1, Ordering::Relaxed);
+
+                eprintln!("Queue full for {item_type}, work dropped. Try again later.");
+            }
+            Err((QueueError::Closed, queue)) => {
+                // We never close the work channels while still generating work, so the collectors
+                // on the other end must have died. There is no point carrying on consuming input.
+                eprintln!(
+                    "The {queue} has stopped unexpectedly, no more work can be processed. Stopping."
+                );
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// A dedicated collector for one fruit type, which makes the fruit it collects with the type's
+/// factory. Collects until it runs out of work orders, whether because the queue was closed or
+/// because an autoscaled collector is no longer needed.
+fn collect(
+    work_orders: impl IntoIterator<Item = FillContainerMessage>,
+    ready_tx: ReadySenders,
+    stats: Arc<Stats>,
+    delays: Arc<FillDelays>,
+    pauses: Arc<Pauses>,
+    fill_distribution: FillDistribution,
+    definition: TypeDefinition,
+) {
+    let mut rng = rand::thread_rng();
+    let item_type = &definition.item_type;
+
+    for work_order in work_orders {
+        // Until resumed, the work order still counts as queued.
+        pauses.wait_while_paused(item_type);
+        stats.queued(item_type).fetch_sub(1, Ordering::Relaxed);
+
+        let message = fill(
+            work_order,
+            &delays,
+            fill_distribution,
+            &mut rng,
+            &definition.new_item,
+        );
+
+        if ready_tx.send(message, &stats) == Err(QueueError::Closed) {
+            // Result channel is closed, we cannot function in this mode.
+            return;
+        }
+    }
+}
+
+/// A worker from the shared pool, which collects whatever fruit the next work order asks for.
+fn collect_any(
+    work_source: WorkSource<FillContainerMessage>,
+    ready_tx: ReadySenders,
+    stats: Arc<Stats>,
+    delays: Arc<FillDelays>,
+    pauses: Arc<Pauses>,
+    fill_distribution: FillDistribution,
+    factories: PerType<ItemFactory>,
+) {
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let Some(work_order) = work_source.next() else {
+            // Work channel is closed, there will be no more work.
+            return;
+        };
+
+        let item_type = work_order.item_type.clone();
+
+        pauses.wait_while_paused(&item_type);
+        stats.queued(&item_type).fetch_sub(1, Ordering::Relaxed);
+
+        let message = fill(
+            work_order,
+            &delays,
+            fill_distribution,
+            &mut rng,
+            factories.get(&item_type),
+        );
+
+        if ready_tx.send(message, &stats) == Err(QueueError::Closed) {
+            // Result channel is closed, we cannot function in this mode.
+            return;
+        }
+    }
+}
+
+impl FillDistribution {
+    /// A random number of items to put into a container of the given size, from 1 to the size.
+    /// The biased distributions take the larger or smaller of two uniform draws, which makes the
+    /// expected fill about 2/3 or 1/3 of the container instead of 1/2.
+    fn fill_count(self, container_size: usize, rng: &mut impl Rng) -> usize {
+        let mut draw = || rng.gen_range(1..=container_size);
+
+        match self {
+            FillDistribution::Uniform => draw(),
+            FillDistribution::FullBiased => draw().max(draw()),
+            FillDistribution::Sparse => draw().min(draw()),
+        }
+    }
+}
+
+/// The optional stage between the collectors and the reporters. Removes each item from the
+/// filled containers with the given probability, simulating items found to be bad, and passes
+/// the containers on with only the items that remain.
+fn inspect(
+    rx: Receiver<ContainerFilledMessage>,
+    ready_tx: ReadySenders,
+    stats: Arc<Stats>,
+    reject_rate: f64,
+) {
+    let mut rng = rand::thread_rng();
+
+    for mut message in rx {
+        let rejected = (0..message.items_added)
+            .filter(|_| rng.gen_bool(reject_rate))
+            .count();
+
+        message.items_added -= rejected;
+        message.items_rejected = Some(message.items_rejected.unwrap_or(0) + rejected);
+
+        if rejected > 0 {
+            message.total_weight_grams = None;
+        }
+
+        if ready_tx.send(message, &stats) == Err(QueueError::Closed) {
+            // Result channel is closed, the collectors notice once we are gone.
+            return;
+        }
+    }
+}
+
+/// Fills the container with fruit made by `new_item`, taking as long as `delays` says.
+fn fill(
+    mut work_order: FillContainerMessage,
+    delays: &FillDelays,
+    fill_distribution: FillDistribution,
+    rng: &mut ThreadRng,
+    new_item: &ItemFactory,
+) -> ContainerFilledMessage {
+    delays.wait(&work_order.item_type);
+
+    let items_collected = fill_distribution.fill_count(work_order.container.len(), rng);
+    let mut total_weight_grams = 0;
+
+    for i in 0..items_collected {
+        let item = new_item(rng);
+        total_weight_grams += u64::from(item.weight_grams);
+        work_order.container[i] = Some(item);
+    }
+
+    let message = ContainerFilledMessage {
+        work_id: work_order.work_id,
+        created_at: work_order.created_at,
+        epoch: work_order.epoch,
+        container_size: work_order.container.len(),
+        items_added: items_collected,
+        items_rejected: None,
+        total_weight_grams: Some(total_weight_grams),
+        item_type: work_order.item_type,
+    };
+
+    if cfg!(debug_assertions) {
+        verify_container(&work_order.container, &message)
+            .expect("filled container does not match the report about it");
+    }
+
+    message
+}
+
+/// Checks that the container holds exactly the number, type and weight of items the message
+/// claims were added, so a fill strategy cannot report something other than what it did.
+fn verify_container(
+    container: &[Option<Item>],
+    message: &ContainerFilledMessage,
+) -> Result<(), String> {
+    if let Some(item) = container
+        .iter()
+        .flatten()
+        .find(|item| item.item_type != message.item_type)
+    {
+        return Err(format!(
+            "container holds {} but {} was reported",
+            item.item_type, message.item_type
+        ));
+    }
+
+    let items_present = container.iter().filter(|slot| slot.is_some()).count();
+
+    if items_present != message.items_added || container.len() != message.container_size {
+        return Err(format!(
+            "container of size {} holds {items_present} items but {} items in a container of size {} were reported",
+            container.len(),
+            message.items_added,
+            message.container_size
+        ));
+    }
+
+    let weight_present = container
+        .iter()
+        .flatten()
+        .map(|item| u64::from(item.weight_grams))
+        .sum();
+
+    if message.total_weight_grams != Some(weight_present) {
+        return Err(format!(
+            "container holds {weight_present} g of fruit but {:?} g was reported",
+            message.total_weight_grams
+        ));
+    }
+
+    Ok(())
+}
+
+/// Runs the reporter, restarting it on the same receiver if it panics. Without this, a reporter
+/// panic would drop the receiver and the collectors would quietly exit on their next send.
+fn supervise_reporter(
+    rx: Receiver<ContainerFilledMessage>,
+    stats: Arc<Stats>,
+    reporter: Arc<Reporter>,
+    observer: Arc<impl CompletionObserver>,
+    fair_reporting: bool,
+) {
+    for attempt in 0..=MAX_REPORTER_RESTARTS {
+        // The messages being processed at the time of the panic are lost but the stats remain
+        // usable, as their locks are taken regardless of poisoning (see `lock_stats`), so it is
+        // fine to carry on with the same state.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            report_results(&rx, &stats, &reporter, observer.as_ref(), fair_reporting)
+        }));
+
+        match result {
+            // All senders are gone, which means we are shutting down normally.
+            Ok(()) => return,
+            Err(e) if attempt < MAX_REPORTER_RESTARTS => {
+                eprintln!("Reporter panicked, restarting it: {}", panic_message(&e));
+            }
+            Err(e) => {
+                eprintln!(
+                    "Reporter panicked {} times, giving up: {}",
+                    attempt + 1,
+                    panic_message(&e)
+                );
+            }
+        }
+    }
+
+    stats.reporter_failed.store(true, Ordering::Relaxed);
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+fn report_results(
+    rx: &Receiver<ContainerFilledMessage>,
+    stats: &Stats,
+    reporter: &Reporter,
+    observer: &impl CompletionObserver,
+    fair_reporting: bool,
+) {
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let timeout = match reporter.until_next_heartbeat() {
+            Some(until_heartbeat) => until_heartbeat.min(reporter.until_next_summary()),
+            None => reporter.until_next_summary(),
+        };
+
+        let message = match rx.recv_timeout(timeout) {
+            Ok(message) => {
+                reporter.completion_arrived();
+                message
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // Nothing was completed for a while, but the user should still hear from us.
+                if reporter.take_heartbeat() {
+                    report_heartbeat(stats, reporter);
+                }
+
+                if reporter.until_next_summary().is_zero() {
+                    report_progress(stats, reporter, observer);
+                }
+
+                continue;
+            }
+            // All collectors are gone, there will be nothing more to report.
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let messages = if fair_reporting {
+            interleave_by_type(message, rx)
+        } else {
+            vec![message]
+        };
+
+        for message in messages {
+            let baseline = lock_stats(&stats.baseline);
+            let counts = message.epoch == baseline.epoch;
+
+            if let Err(e) = validate_message(&message) {
+                // This is a bug somewhere upstream but not a reason to stop reporting.
+                eprintln!("Invalid completion message {message:?}: {e}");
+
+                if counts {
+                    saturating_increment(&stats.anomalies);
+                }
+            }
+
+            if counts {
+                saturating_increment(stats.completed(&message.item_type));
+
+                stats
+                    .largest_container
+                    .fetch_max(message.container_size, Ordering::Relaxed);
+                stats
+                    .most_items_added
+                    .fetch_max(message.items_added, Ordering::Relaxed);
+                saturating_add(&stats.items_collected, message.items_collected() as u64);
+                saturating_add(&stats.items_passed, message.items_added as u64);
+                lock_stats(&stats.activity).record(message.created_at, Instant::now());
+
+                let latency = message.created_at.elapsed();
+
+                lock_stats(stats.latencies(&message.item_type)).record(latency, &mut rng);
+                stats
+                    .per_type
+                    .get(&message.item_type)
+                    .recent_latency_micros
+                    .fetch_max(latency.as_micros() as u64, Ordering::Relaxed);
+            }
+
+            drop(baseline);
+
+            observer.on_completion(&message);
+
+            if reporter.summary_due(stats.total_completed()) {
+                report_progress(stats, reporter, observer);
+            }
+        }
+    }
+}
+
+/// Collects the completions that arrive within `FAIR_REPORTING_WINDOW` of the first one and
+/// returns them all, taking turns between the fruit types for as long as more than one has some
+/// left, starting with the fruit type of the first one. The faster fruit types would otherwise
+/// dominate the output.
+fn interleave_by_type(
+    first: ContainerFilledMessage,
+    rx: &Receiver<ContainerFilledMessage>,
+) -> Vec<ContainerFilledMessage> {
+    let deadline = Instant::now() + FAIR_REPORTING_WINDOW;
+
+    // In the order the fruit types first arrived in.
+    let mut by_type: Vec<(ItemType, VecDeque<ContainerFilledMessage>)> = Vec::new();
+    let mut total = 0;
+    let mut message = first;
+
+    loop {
+        let position = by_type
+            .iter()
+            .position(|(item_type, _)| *item_type == message.item_type);
+
+        match position {
+            Some(position) => by_type[position].1.push_back(message),
+            None => by_type.push((message.item_type.clone(), VecDeque::from([message]))),
+        }
+
+        total += 1;
+
+        // If the channel is disconnected we report what we have, the caller notices it next time.
+        let Ok(next) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) else {
+            break;
+        };
+
+        message = next;
+    }
+
+    let mut messages = Vec::with_capacity(total);
+
+    while messages.len() < total {
+        for (_, queue) in &mut by_type {
+            messages.extend(queue.pop_front());
+        }
+    }
+
+    messages
+}
+
+fn validate_message(message: &ContainerFilledMessage) -> Result<(), String> {
+    if message.items_collected() > message.container_size {
+        return Err(format!(
+            "{} items collected into a container of size {}",
+            message.items_collected(),
+            message.container_size
+        ));
+    }
+
+    Ok(())
+}
+
+/// Only while work is outstanding, as an idle app waiting for input is not working on anything.
+fn report_heartbeat(stats: &Stats, reporter: &Reporter) {
+    if stats.outstanding() == 0 {
+        return;
+    }
+
+    let queue_depth: usize = stats
+        .per_type
+        .iter()
+        .map(|(_, type_stats)| type_stats.queued.load(Ordering::Relaxed))
+        .sum();
+
+    reporter.print(
+        Verbosity::Quiet,
+        format!("Still working... queue depth {queue_depth}"),
+    );
+}
+
+fn report_progress(stats: &Stats, reporter: &Reporter, observer: &impl CompletionObserver) {
+    reporter.summary_reported();
+    observer.on_progress(&stats.snapshot());
+}
+
+/// Once all the work has been drained, every work item created must have ended one way or
+/// another. If not, an outcome is being miscounted or work has gone missing somewhere in the
+/// pipeline, e.g. with a reporter that gave up after panicking.
+fn verify_totals(stats: &Stats, reporter: &Reporter) {
+    let books = stats.books();
+
+    if books.ended() == books.created {
+        reporter.print(
+            Verbosity::Quiet,
+            format!(
+                "Totals verified: {} work items created, {} completed and {} lost.",
+                books.created, books.completed, books.lost
+            ),
+        );
+    } else {
+        eprintln!(
+            "Totals do not balance: {} work items created, but {} completed and {} lost add up to {} ({:+}).",
+            books.created,
+            books.completed,
+            books.lost,
+            books.ended(),
+            i128::from(books.ended()) - i128::from(books.created)
+        );
+    }
+}
+
+fn print_stats(stats: &Stats, reporter: &Reporter) {
+    reporter.summary_reported();
+    reporter.print(Verbosity::Quiet, stats.snapshot());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apple() -> ItemType {
+        ItemType::new("Apple")
+    }
+
+    fn orange() -> ItemType {
+        ItemType::new("Orange")
+    }
+
+    fn filled(
+        item_type: ItemType,
+        container_size: usize,
+        items_added: usize,
+    ) -> ContainerFilledMessage {
+        ContainerFilledMessage {
+            work_id: 1,
+            created_at: Instant::now(),
+            epoch: 0,
+            container_size,
+            items_added,
+            items_rejected: None,
+            total_weight_grams: None,
+            item_type,
+        }
+    }
+
+    fn order(item_type: ItemType, work_id: u64) -> FillContainerMessage {
+        FillContainerMessage {
+            work_id,
+            created_at: Instant::now(),
+            epoch: 0,
+            item_type,
+            container: vec![None; 2],
+        }
+    }
+
+    /// The default fruit types, without any fill delays.
+    fn no_delays() -> Config {
+        let mut config = Config::default();
+
+        for name in ["Apple", "Orange"] {
+            config.item_types.find_mut(name).unwrap().fill_delay = Duration::ZERO;
+        }
+
+        config
+    }
+
+    /// Senders that wait for room, as by default.
+    fn ready_senders(channels: ReadyChannels) -> ReadySenders {
+        ReadySenders::new(channels, &Config::default())
+    }
+
+    fn stats() -> Stats {
+        Stats::new(&ItemRegistry::default())
+    }
+
+    fn reporter() -> Reporter {
+        Reporter::new(
+            Verbosity::Normal,
+            100,
+            Duration::from_secs(10),
+            None,
+            false,
+            Box::new(io::sink()),
+        )
+    }
+
+    /// Keeps every filled container it observes.
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<ContainerFilledMessage>>>);
+
+    impl CompletionObserver for Recorder {
+        fn on_completion(&self, message: &ContainerFilledMessage) {
+            self.0.lock().unwrap().push(ContainerFilledMessage {
+                item_type: message.item_type.clone(),
+                ..*message
+            });
+        }
+    }
+
+    #[test]
+    fn completions_are_counted_per_type() {
+        let stats = Arc::new(stats());
+        stats.work_created.fetch_add(4, Ordering::Relaxed);
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        for item_type in [apple(), orange(), apple()] {
+            ready_tx.send(filled(item_type, 5, 3)).unwrap();
+        }
+
+        drop(ready_tx);
+        let recorder = Recorder::default();
+        report_results(&ready_rx, &stats, &reporter(), &recorder, false);
+
+        let observed: Vec<_> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|message| message.item_type.clone())
+            .collect();
+        assert_eq!(observed, [apple(), orange(), apple()]);
+
+        assert_eq!(stats.completed(&apple()).load(Ordering::Relaxed), 2);
+        assert_eq!(stats.completed(&orange()).load(Ordering::Relaxed), 1);
+        assert_eq!(stats.total_completed(), 3);
+        assert_eq!(stats.work_created.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn more_items_than_fit_are_flagged() {
+        assert!(validate_message(&filled(apple(), 3, 3)).is_ok());
+        assert!(validate_message(&filled(apple(), 3, 1)).is_ok());
+
+        assert_eq!(
+            validate_message(&filled(apple(), 3, 4)),
+            Err("4 items collected into a container of size 3".to_string())
+        );
+
+        let stats = Arc::new(stats());
+        let (ready_tx, ready_rx) = mpsc::channel();
+        ready_tx.send(filled(orange(), 3, 4)).unwrap();
+        ready_tx.send(filled(orange(), 3, 2)).unwrap();
+        drop(ready_tx);
+
+        // The bad message is counted, and reporting goes on with the next one.
+        report_results(&ready_rx, &stats, &reporter(), &Recorder::default(), false);
+        assert_eq!(stats.anomalies.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.completed(&orange()).load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn queued_containers_are_counted_until_picked_up() {
+        let config = no_delays();
+        let stats = Arc::new(stats());
+        let (apples_tx, apples_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = queue(None);
+
+        stats.queued(&apple()).fetch_add(1, Ordering::Relaxed);
+        apples_tx.send(order(apple(), 1)).unwrap();
+        drop(apples_tx);
+
+        assert_eq!(stats.queued(&apple()).load(Ordering::Relaxed), 1);
+        assert_eq!(stats.queued(&orange()).load(Ordering::Relaxed), 0);
+
+        collect(
+            apples_rx,
+            ready_senders(ReadyChannels::Shared(ready_tx)),
+            stats.clone(),
+            Arc::new(FillDelays::new(
+                &config.item_types,
+                Arc::new(clock::RealSleeper),
+            )),
+            Arc::new(Pauses::new(&config.item_types)),
+            FillDistribution::Uniform,
+            config.item_types.get(&apple()).unwrap().clone(),
+        );
+
+        assert_eq!(stats.queued(&apple()).load(Ordering::Relaxed), 0);
+        assert_eq!(ready_rx.recv().unwrap().item_type, apple());
+    }
+
+    #[test]
+    fn counters_stop_at_the_maximum() {
+        let counter = AtomicU64::new(u64::MAX - 2);
+
+        assert_eq!(saturating_increment(&counter), u64::MAX - 1);
+        assert_eq!(saturating_increment(&counter), u64::MAX);
+        assert_eq!(saturating_increment(&counter), u64::MAX);
+        assert_eq!(counter.load(Ordering::Relaxed), u64::MAX);
+
+        let counter = AtomicU64::new(41);
+        assert_eq!(saturating_increment(&counter), 42);
+    }
+
+    #[test]
+    fn generated_containers_respect_the_size_bounds() {
+        let config = Config {
+            min_size: 4,
+            max_size: 4,
+            ..no_delays()
+        };
+
+        let (work_tx, work_rx) = queue(None);
+
+        // Once enough work is taken, the queue is closed, which is what stops the generator.
+        let taken = thread::spawn(move || {
+            work_rx
+                .iter()
+                .take(50)
+                .collect::<Vec<FillContainerMessage>>()
+        });
+
+        let (input_tx, input_rx) = mpsc::channel();
+        for _ in 0..100 {
+            input_tx.send(Input::Line(String::new())).unwrap();
+        }
+        drop(input_tx);
+
+        generate_work(
+            input_rx,
+            WorkQueues::Shared(work_tx),
+            &config,
+            Arc::new(stats()),
+            &FillDelays::new(&config.item_types, Arc::new(clock::RealSleeper)),
+            &Pauses::new(&config.item_types),
+            &reporter(),
+        )
+        .unwrap();
+
+        for work_order in taken.join().unwrap() {
+            assert_eq!(work_order.container.len(), 4);
+        }
+    }
+
+    fn fruit(item_type: ItemType, weight_grams: u32) -> Option<Item> {
+        Some(Item {
+            item_type,
+            weight_grams,
+        })
+    }
+
+    #[test]
+    fn tampered_containers_fail_verification() {
+        let weighed = |container_size, items_added: usize| ContainerFilledMessage {
+            total_weight_grams: Some(100 * items_added as u64),
+            ..filled(apple(), container_size, items_added)
+        };
+
+        let container = [fruit(apple(), 100), fruit(apple(), 100), None];
+        assert_eq!(verify_container(&container, &weighed(3, 2)), Ok(()));
+
+        let oranges = [fruit(orange(), 100), None, None];
+        assert_eq!(
+            verify_container(&oranges, &weighed(3, 1)),
+            Err("container holds Orange but Apple was reported".to_string())
+        );
+
+        assert!(verify_container(&container, &weighed(3, 3)).is_err());
+        assert!(verify_container(&container, &weighed(4, 2)).is_err());
+
+        let misweighed = ContainerFilledMessage {
+            total_weight_grams: Some(150),
+            ..weighed(3, 2)
+        };
+        assert_eq!(
+            verify_container(&container, &misweighed),
+            Err("container holds 200 g of fruit but Some(150) g was reported".to_string())
+        );
+    }
+
+    #[test]
+    fn the_weight_of_the_fruit_is_added_up() {
+        let mut rng = rand::thread_rng();
+        let new_item: ItemFactory = Arc::new(|_| Item {
+            item_type: orange(),
+            weight_grams: 35,
+        });
+        let delays = FillDelays::new(&ItemRegistry::default(), Arc::new(clock::NoWait));
+
+        for _ in 0..100 {
+            let work_order = FillContainerMessage {
+                container: vec![None; 5],
+                ..order(orange(), 1)
+            };
+
+            let message = fill(
+                work_order,
+                &delays,
+                FillDistribution::Uniform,
+                &mut rng,
+                &new_item,
+            );
+
+            assert_eq!(
+                message.total_weight_grams,
+                Some(35 * message.items_added as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn fair_reporting_alternates_the_fruit_types() {
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        for item_type in [apple(), apple(), orange()] {
+            ready_tx.send(filled(item_type, 5, 3)).unwrap();
+        }
+
+        drop(ready_tx);
+
+        let reported: Vec<_> = interleave_by_type(filled(apple(), 5, 3), &ready_rx)
+            .into_iter()
+            .map(|message| message.item_type)
+            .collect();
+        assert_eq!(reported, [apple(), orange(), apple(), apple()]);
+    }
+
+    #[test]
+    fn every_tick_generates_a_work_item() {
+        let config = no_delays();
+        let (work_tx, work_rx) = queue(None);
+        let (input_tx, input_rx) = mpsc::channel();
+
+        for _ in 0..3 {
+            input_tx.send(Input::Tick).unwrap();
+        }
+
+        drop(input_tx);
+
+        generate_work(
+            input_rx,
+            WorkQueues::Shared(work_tx),
+            &config,
+            Arc::new(stats()),
+            &FillDelays::new(&config.item_types, Arc::new(clock::RealSleeper)),
+            &Pauses::new(&config.item_types),
+            &reporter(),
+        )
+        .unwrap();
+
+        assert_eq!(work_rx.iter().count(), 3);
+    }
+
+    #[test]
+    fn each_fruit_type_takes_its_configured_fill_delay() {
+        let mut item_types = ItemRegistry::default();
+        item_types.find_mut("Apple").unwrap().fill_delay = Duration::from_millis(50);
+        item_types.find_mut("Orange").unwrap().fill_delay = Duration::ZERO;
+
+        let (work_tx, work_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = queue(None);
+
+        work_tx.send(order(orange(), 1)).unwrap();
+        work_tx.send(order(apple(), 2)).unwrap();
+        drop(work_tx);
+
+        let started = Instant::now();
+        collect_any(
+            WorkSource::Shared(Arc::new(Mutex::new(work_rx))),
+            ready_senders(ReadyChannels::Shared(ready_tx)),
+            Arc::new(Stats::new(&item_types)),
+            Arc::new(FillDelays::new(&item_types, Arc::new(clock::RealSleeper))),
+            Arc::new(Pauses::new(&item_types)),
+            FillDistribution::Uniform,
+            PerType::new(&item_types, |definition| definition.new_item.clone()),
+        );
+
+        let orange_message = ready_rx.recv().unwrap();
+        let apple_message = ready_rx.recv().unwrap();
+        assert_eq!(orange_message.item_type, orange());
+        assert_eq!(apple_message.item_type, apple());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn work_from_before_a_reset_does_not_count() {
+        let stats = Arc::new(stats());
+        stats.work_created.fetch_add(2, Ordering::Relaxed);
+        stats.reset();
+        stats.work_created.fetch_add(1, Ordering::Relaxed);
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        // Made before the reset, and an anomaly too, but neither is counted.
+        let old = filled(apple(), 3, 4);
+        let mut new = filled(apple(), 3, 2);
+        new.epoch = 1;
+        ready_tx.send(old).unwrap();
+        ready_tx.send(new).unwrap();
+        drop(ready_tx);
+
+        report_results(&ready_rx, &stats, &reporter(), &Recorder::default(), false);
+
+        assert_eq!(stats.work_created.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.total_completed(), 1);
+        assert_eq!(stats.anomalies.load(Ordering::Relaxed), 0);
+    }
+
+    /// Runs the work generator on the given input and returns how many work items it generated.
+    fn generate_from(config: &Config, inputs: Vec<Input>) -> usize {
+        let (work_tx, work_rx) = queue(None);
+        let (input_tx, input_rx) = mpsc::channel();
+
+        for input in inputs {
+            input_tx.send(input).unwrap();
+        }
+
+        // Ends the run if nothing before it did. The input channel is still open at this point,
+        // so running out of input cannot be what ends it.
+        input_tx
+            .send(Input::Shutdown("test over".to_string()))
+            .unwrap();
+
+        generate_work(
+            input_rx,
+            WorkQueues::Shared(work_tx),
+            config,
+            Arc::new(Stats::new(&config.item_types)),
+            &FillDelays::new(&config.item_types, Arc::new(clock::RealSleeper)),
+            &Pauses::new(&config.item_types),
+            &reporter(),
+        )
+        .unwrap();
+
+        work_rx.iter().count()
+    }
+
+    #[test]
+    fn eof_stops_the_work() {
+        let inputs = vec![
+            Input::Line(String::new()),
+            Input::Line("more".to_string()),
+            Input::Line("stats".to_string()),
+            Input::Line(String::new()),
+            Input::StdinClosed,
+            Input::Line(String::new()),
+        ];
+
+        assert_eq!(generate_from(&Config::default(), inputs), 3);
+    }
+
+    #[test]
+    fn eof_does_not_stop_automatic_work() {
+        let config = Config {
+            auto_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let inputs = vec![Input::Line(String::new()), Input::StdinClosed, Input::Tick];
+
+        assert_eq!(generate_from(&config, inputs), 2);
+    }
+
+    #[test]
+    fn the_delay_control_word_changes_one_fill_delay() {
+        let config = Config::default();
+        let delays = FillDelays::new(&config.item_types, Arc::new(clock::RealSleeper));
+        let (work_tx, _work_rx) = queue(None);
+        let (input_tx, input_rx) = mpsc::channel();
+
+        for line in ["delay orange 250", "delay apple 61000", "delay pear 1"] {
+            input_tx.send(Input::Line(line.to_string())).unwrap();
+        }
+
+        drop(input_tx);
+
+        generate_work(
+            input_rx,
+            WorkQueues::Shared(work_tx),
+            &config,
+            Arc::new(stats()),
+            &delays,
+            &Pauses::new(&config.item_types),
+            &reporter(),
+        )
+        .unwrap();
+
+        // Only the first one was valid, the others are over the limit or not a fruit type.
+        assert_eq!(delays.get(&orange()), Duration::from_millis(250));
+        assert_eq!(delays.get(&apple()), Duration::from_secs(1));
+
+        assert_eq!(
+            parse_delay(&["APPLES", "60000"], &config.item_types),
+            Ok((apple(), MAX_FILL_DELAY))
+        );
+        assert!(parse_delay(&["apple"], &config.item_types).is_err());
+    }
+
+    #[test]
+    fn filled_containers_go_to_the_reporter_of_their_type() {
+        let item_types = no_delays().item_types;
+        let (work_tx, work_rx) = mpsc::channel();
+        let mut ready_rxs = Vec::new();
+
+        let ready_txs = PerType::new(&item_types, |_| {
+            let (ready_tx, ready_rx) = queue(None);
+            ready_rxs.push(ready_rx);
+            ready_tx
+        });
+
+        for (work_id, item_type) in (1..).zip([orange(), apple(), orange(), apple()]) {
+            work_tx.send(order(item_type, work_id)).unwrap();
+        }
+
+        drop(work_tx);
+
+        collect_any(
+            WorkSource::Shared(Arc::new(Mutex::new(work_rx))),
+            ready_senders(ReadyChannels::PerType(ready_txs)),
+            Arc::new(Stats::new(&item_types)),
+            Arc::new(FillDelays::new(&item_types, Arc::new(clock::RealSleeper))),
+            Arc::new(Pauses::new(&item_types)),
+            FillDistribution::Uniform,
+            PerType::new(&item_types, |definition| definition.new_item.clone()),
+        );
+
+        // In the order the fruit types were registered.
+        let work_ids: Vec<Vec<_>> = ready_rxs
+            .iter()
+            .map(|ready_rx| ready_rx.iter().map(|message| message.work_id).collect())
+            .collect();
+        assert_eq!(work_ids, [[2, 4], [1, 3]]);
+    }
+
+    #[test]
+    fn full_queues_drop_rather_than_block() {
+        let (tx, _rx) = queue::<u32>(Some(0));
+        assert_eq!(tx.send(1, FullQueuePolicy::Drop), Err(QueueError::Full));
+
+        let (tx, rx_with_room) = queue::<u32>(Some(1));
+        assert_eq!(tx.send(1, FullQueuePolicy::Drop), Ok(()));
+        assert_eq!(tx.send(2, FullQueuePolicy::Drop), Err(QueueError::Full));
+        assert_eq!(rx_with_room.try_iter().collect::<Vec<_>>(), [1]);
+
+        let (unbounded, _rx) = queue::<u32>(None);
+        for value in 0..100 {
+            assert_eq!(unbounded.send(value, FullQueuePolicy::Drop), Ok(()));
+        }
+
+        // The receiver is dropped right away.
+        let (tx, _) = queue::<u32>(Some(0));
+        assert_eq!(tx.send(1, FullQueuePolicy::Drop), Err(QueueError::Closed));
+        assert_eq!(tx.send(1, FullQueuePolicy::Block), Err(QueueError::Closed));
+    }
+
+    #[test]
+    fn dropped_work_is_not_counted() {
+        let config = Config {
+            queue_capacity: Some(1),
+            when_full: FullQueuePolicy::Drop,
+            ..Default::default()
+        };
+        let stats = Arc::new(stats());
+        let (work_tx, work_rx) = queue(config.queue_capacity);
+        let (input_tx, input_rx) = mpsc::channel();
+
+        // Nobody collects, so only the first work item fits into the queue.
+        for _ in 0..5 {
+            input_tx.send(Input::Line(String::new())).unwrap();
+        }
+
+        drop(input_tx);
+
+        generate_work(
+            input_rx,
+            WorkQueues::Shared(work_tx),
+            &config,
+            stats.clone(),
+            &FillDelays::new(&config.item_types, Arc::new(clock::RealSleeper)),
+            &Pauses::new(&config.item_types),
+            &reporter(),
+        )
+        .unwrap();
+
+        assert_eq!(work_rx.iter().count(), 1);
+        assert_eq!(stats.work_created.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            stats
+                .snapshot()
+                .per_type
+                .iter()
+                .map(|progress| progress.queued)
+                .sum::<usize>(),
+            1
+        );
+    }
+
+    #[test]
+    fn the_largest_container_is_tracked() {
+        let stats = stats();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        for (item_type, container_size, items_added) in [
+            (apple(), 3, 3),
+            (orange(), 17, 5),
+            (apple(), 2, 1),
+            (orange(), 5, 4),
+        ] {
+            ready_tx
+                .send(filled(item_type, container_size, items_added))
+                .unwrap();
+        }
+
+        drop(ready_tx);
+        report_results(&ready_rx, &stats, &reporter(), &Recorder::default(), false);
+
+        assert_eq!(stats.total_completed(), 4);
+        assert_eq!(stats.largest_container.load(Ordering::Relaxed), 17);
+        assert_eq!(stats.most_items_added.load(Ordering::Relaxed), 5);
+
+        stats.reset();
+        assert_eq!(stats.largest_container.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.most_items_added.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn percentages_are_pending_until_work_is_counted() {
+        assert_eq!(format_percent_completed(0, 0), "pending");
+        assert_eq!(format_percent_completed(3, 2), "pending");
+        assert_eq!(format_percent_completed(0, 4), "0.0 %");
+        assert_eq!(format_percent_completed(1, 3), "33.3 %");
+        assert_eq!(format_percent_completed(4, 4), "100.0 %");
+    }
+
+    /// Checks on every completion that the completed work never outnumbers the created work.
+    struct ProgressChecker {
+        stats: Arc<Stats>,
+        violations: Arc<AtomicU64>,
+    }
+
+    impl CompletionObserver for ProgressChecker {
+        fn on_completion(&self, _message: &ContainerFilledMessage) {
+            let (work_completed, work_created) = self.stats.progress();
+
+            if work_completed > work_created {
+                self.violations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn completed_work_never_outnumbers_created_work() {
+        for (workers, per_type_reporters) in [(None, false), (Some(4), true)] {
+            let config = Config {
+                workers,
+                per_type_reporters,
+                ..no_delays()
+            };
+            let stats = Arc::new(Stats::new(&config.item_types));
+            let delays = Arc::new(FillDelays::new(
+                &config.item_types,
+                Arc::new(clock::RealSleeper),
+            ));
+            let pauses = Arc::new(Pauses::new(&config.item_types));
+            let violations = Arc::new(AtomicU64::new(0));
+
+            let mut ready_rxs = Vec::new();
+            let ready_tx = ready_senders(ReadyChannels::PerType(PerType::new(
+                &config.item_types,
+                |_| {
+                    let (ready_tx, ready_rx) = queue(None);
+                    ready_rxs.push(ready_rx);
+                    ready_tx
+                },
+            )));
+
+            let (work_queues, collector_threads) = match workers {
+                None => spawn_per_type_collectors(&config, ready_tx, &stats, &delays, &pauses),
+                Some(workers) => {
+                    spawn_worker_pool(workers, &config, ready_tx, &stats, &delays, &pauses)
+                }
+            };
+
+            let reporter_threads: Vec<_> = ready_rxs
+                .into_iter()
+                .map(|rx| {
+                    let checker = ProgressChecker {
+                        stats: stats.clone(),
+                        violations: violations.clone(),
+                    };
+                    let stats = stats.clone();
+
+                    thread::spawn(move || report_results(&rx, &stats, &reporter(), &checker, false))
+                })
+                .collect();
+
+            let (input_tx, input_rx) = mpsc::channel();
+
+            for i in 0..2000 {
+                // Resets move the baseline while work is in flight.
+                let line = if i % 500 == 250 { "reset" } else { "" };
+                input_tx.send(Input::Line(line.to_string())).unwrap();
+            }
+
+            input_tx.send(Input::StdinClosed).unwrap();
+
+            generate_work(
+                input_rx,
+                work_queues,
+                &config,
+                stats.clone(),
+                &delays,
+                &pauses,
+                &reporter(),
+            )
+            .unwrap();
+
+            for thread in collector_threads
+                .into_iter()
+                .map(|(_, thread)| thread)
+                .chain(reporter_threads)
+            {
+                thread.join().unwrap();
+            }
+
+            assert_eq!(violations.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[test]
+    fn fill_distributions_lean_the_expected_way() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(159);
+
+        let mut mean_fill = |distribution: FillDistribution| {
+            let samples = 20_000;
+            let total: usize = (0..samples)
+                .map(|_| {
+                    let count = distribution.fill_count(9, &mut rng);
+                    assert!((1..=9).contains(&count));
+                    count
+                })
+                .sum();
+
+            total as f64 / samples as f64
+        };
+
+        // The expected means are 5, 525/81 and 10 - 525/81.
+        let uniform = mean_fill(FillDistribution::Uniform);
+        let full_biased = mean_fill(FillDistribution::FullBiased);
+        let sparse = mean_fill(FillDistribution::Sparse);
+
+        assert!((uniform - 5.0).abs() < 0.1, "uniform {uniform}");
+        assert!(
+            (full_biased - 6.48).abs() < 0.1,
+            "full-biased {full_biased}"
+        );
+        assert!((sparse - 3.52).abs() < 0.1, "sparse {sparse}");
+
+        assert_eq!(FillDistribution::Sparse.fill_count(1, &mut rng), 1);
+    }
+
+    #[test]
+    fn a_paused_collector_holds_its_work_until_resumed() {
+        let config = no_delays();
+        let stats = Arc::new(stats());
+        let pauses = Arc::new(Pauses::new(&config.item_types));
+        let (apples_tx, apples_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = queue(None);
+
+        pauses.set(&apple(), true);
+
+        stats.queued(&apple()).fetch_add(1, Ordering::Relaxed);
+        apples_tx.send(order(apple(), 1)).unwrap();
+        drop(apples_tx);
+
+        let collector = {
+            let stats = stats.clone();
+            let pauses = pauses.clone();
+            let delays = Arc::new(FillDelays::new(
+                &config.item_types,
+                Arc::new(clock::RealSleeper),
+            ));
+            let definition = config.item_types.get(&apple()).unwrap().clone();
+
+            thread::spawn(move || {
+                collect(
+                    apples_rx,
+                    ready_senders(ReadyChannels::Shared(ready_tx)),
+                    stats,
+                    delays,
+                    pauses,
+                    FillDistribution::Uniform,
+                    definition,
+                )
+            })
+        };
+
+        assert!(matches!(
+            ready_rx.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        ));
+        assert_eq!(stats.queued(&apple()).load(Ordering::Relaxed), 1);
+
+        // Resuming the other fruit type does not release it.
+        pauses.set(&orange(), false);
+        assert!(ready_rx.try_recv().is_err());
+
+        pauses.set(&apple(), false);
+        assert_eq!(ready_rx.recv().unwrap().item_type, apple());
+        assert_eq!(stats.queued(&apple()).load(Ordering::Relaxed), 0);
+
+        collector.join().unwrap();
+    }
+
+    #[test]
+    fn inspection_passes_on_only_the_items_that_remain() {
+        for (reject_rate, expected_passed) in [(0.0, 4), (1.0, 0)] {
+            let (collected_tx, collected_rx) = mpsc::channel();
+            let (ready_tx, ready_rx) = queue(None);
+
+            collected_tx.send(filled(apple(), 5, 4)).unwrap();
+            drop(collected_tx);
+
+            inspect(
+                collected_rx,
+                ready_senders(ReadyChannels::Shared(ready_tx)),
+                Arc::new(stats()),
+                reject_rate,
+            );
+
+            let inspected = ready_rx.recv().unwrap();
+            assert_eq!(inspected.items_added, expected_passed);
+            assert_eq!(inspected.items_rejected, Some(4 - expected_passed));
+            assert_eq!(inspected.items_collected(), 4);
+
+            // The container is judged by what was collected into it, not by what passed.
+            assert!(validate_message(&inspected).is_ok());
+
+            let stats = stats();
+            let (ready_tx, ready_rx) = mpsc::channel();
+            ready_tx.send(inspected).unwrap();
+            drop(ready_tx);
+
+            report_results(&ready_rx, &stats, &reporter(), &Recorder::default(), false);
+            assert_eq!(stats.items_collected.load(Ordering::Relaxed), 4);
+            assert_eq!(
+                stats.items_passed.load(Ordering::Relaxed),
+                expected_passed as u64
+            );
+        }
+    }
+
+    /// Keeps every progress snapshot it observes.
+    #[derive(Default)]
+    struct ProgressRecorder(Mutex<Vec<ProgressSnapshot>>);
+
+    impl CompletionObserver for ProgressRecorder {
+        fn on_completion(&self, _message: &ContainerFilledMessage) {}
+
+        fn on_progress(&self, progress: &ProgressSnapshot) {
+            self.0.lock().unwrap().push(progress.clone());
+        }
+    }
+
+    #[test]
+    fn progress_is_observed_every_few_completions() {
+        let stats = stats();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        for _ in 0..5 {
+            saturating_increment(&stats.work_created);
+            ready_tx.send(filled(orange(), 3, 2)).unwrap();
+        }
+
+        drop(ready_tx);
+
+        // Even at normal verbosity, where the summaries are not printed.
+        let reporter = Reporter::new(
+            Verbosity::Normal,
+            2,
+            Duration::from_secs(3600),
+            None,
+            false,
+            Box::new(io::sink()),
+        );
+        let recorder = ProgressRecorder::default();
+        report_results(&ready_rx, &stats, &reporter, &recorder, false);
+
+        let completed: Vec<_> = recorder
+            .0
+            .into_inner()
+            .unwrap()
+            .iter()
+            .map(|progress| (progress.work_completed(), progress.work_created))
+            .collect();
+        assert_eq!(completed, [(2, 5), (4, 5)]);
+    }
+
+    #[test]
+    fn reporting_continues_after_a_panic_under_the_stats_lock() {
+        let stats = Arc::new(stats());
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        // Counting a fruit type that is not registered panics while the baseline is locked.
+        ready_tx.send(filled(ItemType::new("Kiwi"), 3, 2)).unwrap();
+        ready_tx.send(filled(apple(), 3, 2)).unwrap();
+        ready_tx.send(filled(orange(), 3, 3)).unwrap();
+        drop(ready_tx);
+
+        let recorder = Recorder::default();
+        let recorded = Arc::clone(&recorder.0);
+
+        supervise_reporter(
+            ready_rx,
+            Arc::clone(&stats),
+            Arc::new(reporter()),
+            Arc::new(recorder),
+            false,
+        );
+
+        assert!(!stats.reporter_failed.load(Ordering::Relaxed));
+        assert_eq!(stats.total_completed(), 2);
+        assert_eq!(recorded.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn the_pool_finishes_the_work_with_every_pickup() {
+        use config::WorkerPickup;
+
+        for worker_pickup in [
+            WorkerPickup::Any,
+            WorkerPickup::Fifo,
+            WorkerPickup::LeastRecentlyUsed,
+        ] {
+            let config = Config {
+                worker_pickup,
+                ..no_delays()
+            };
+            let stats = Arc::new(Stats::new(&config.item_types));
+            let delays = Arc::new(FillDelays::new(
+                &config.item_types,
+                Arc::new(clock::RealSleeper),
+            ));
+            let pauses = Arc::new(Pauses::new(&config.item_types));
+            let (ready_tx, ready_rx) = queue(None);
+
+            let (work_queues, worker_threads) = spawn_worker_pool(
+                3,
+                &config,
+                ready_senders(ReadyChannels::Shared(ready_tx)),
+                &stats,
+                &delays,
+                &pauses,
+            );
+
+            let (input_tx, input_rx) = mpsc::channel();
+
+            for _ in 0..3 {
+                input_tx.send(Input::Line(String::new())).unwrap();
+            }
+
+            input_tx.send(Input::StdinClosed).unwrap();
+
+            generate_work(
+                input_rx,
+                work_queues,
+                &config,
+                stats,
+                &delays,
+                &pauses,
+                &reporter(),
+            )
+            .unwrap();
+
+            for (_, worker_thread) in worker_threads {
+                worker_thread.join().unwrap();
+            }
+
+            assert_eq!(ready_rx.iter().count(), 3, "{worker_pickup:?}");
+        }
+    }
+
+    #[test]
+    fn the_pipeline_fills_exactly_the_given_work() {
+        let mut item_types = no_delays().item_types;
+        item_types
+            .register(TypeDefinition {
+                item_type: ItemType::new("Plum"),
+                fill_delay: Duration::ZERO,
+                color: None,
+                new_item: registry::weighed_between(ItemType::new("Plum"), 30..=40),
+            })
+            .unwrap();
+
+        let fruit_types = [apple(), orange(), ItemType::new("Plum")];
+        let work: Vec<_> = (0..300)
+            .map(|i| (fruit_types[i % 3].clone(), 1 + i % 7))
+            .collect();
+
+        for workers in [None, Some(3)] {
+            let config = Config {
+                workers,
+                verbosity: Verbosity::Quiet,
+                item_types: item_types.clone(),
+                ..no_delays()
+            };
+
+            let stats = run_pipeline(config, work.clone()).unwrap();
+
+            assert_eq!(stats.work_created, 300, "{workers:?}");
+
+            for progress in &stats.per_type {
+                assert_eq!(progress.completed, 100, "{workers:?} {progress:?}");
+                assert_eq!(progress.queued, 0, "{workers:?} {progress:?}");
+            }
+
+            assert_eq!(stats.per_type.len(), 3, "{workers:?}");
+            assert_eq!(stats.largest_container, 7, "{workers:?}");
+        }
+    }
+
+    #[test]
+    fn the_pipeline_rejects_work_it_cannot_do() {
+        assert!(run_pipeline(no_delays(), [(apple(), 3), (orange(), 0)]).is_err());
+        assert!(run_pipeline(no_delays(), [(ItemType::new("Plum"), 3)]).is_err());
+    }
+
+    /// A reporter that takes its time over every container.
+    struct StalledObserver;
+
+    impl CompletionObserver for StalledObserver {
+        fn on_completion(&self, _message: &ContainerFilledMessage) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn a_stalled_reporter_loses_results_only_when_dropping() {
+        let run = |when_results_full| {
+            let config = Config {
+                result_capacity: Some(1),
+                when_results_full,
+                verbosity: Verbosity::Quiet,
+                ..no_delays()
+            };
+
+            App::new(config)
+                .unwrap()
+                .run_with_input(StalledObserver, |input_tx| {
+                    for _ in 0..200 {
+                        _ = input_tx.send(Input::Work(apple(), 2));
+                    }
+
+                    _ = input_tx.send(Input::StdinClosed);
+                })
+                .unwrap()
+        };
+
+        let stats = run(FullQueuePolicy::Drop);
+        assert!(stats.results_lost > 0, "{stats}");
+        assert_eq!(stats.work_completed() + stats.results_lost, 200, "{stats}");
+
+        let stats = run(FullQueuePolicy::Block);
+        assert_eq!((stats.work_completed(), stats.results_lost), (200, 0));
+    }
+
+    #[test]
+    fn work_is_outstanding_until_completed_or_lost() {
+        let stats = stats();
+        assert_eq!(stats.outstanding(), 0);
+
+        stats.work_created.fetch_add(5, Ordering::Relaxed);
+        assert_eq!(stats.outstanding(), 5);
+
+        stats.completed(&apple()).fetch_add(2, Ordering::Relaxed);
+        stats.completed(&orange()).fetch_add(1, Ordering::Relaxed);
+        stats.results_lost.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(stats.outstanding(), 1);
+
+        stats.completed(&apple()).fetch_add(1, Ordering::Relaxed);
+        assert_eq!(stats.outstanding(), 0);
+    }
+
+    #[test]
+    fn the_books_add_up_every_outcome() {
+        let stats = stats();
+        stats.work_created.fetch_add(5, Ordering::Relaxed);
+        stats.completed(&apple()).fetch_add(2, Ordering::Relaxed);
+        stats.completed(&orange()).fetch_add(1, Ordering::Relaxed);
+        stats.results_lost.fetch_add(1, Ordering::Relaxed);
+
+        let books = stats.books();
+        assert_eq!((books.created, books.completed, books.lost), (5, 3, 1));
+        assert_eq!(books.ended(), 4);
+
+        // Work from before a reset is off the books.
+        stats.reset();
+        assert_eq!(stats.books().created, 0);
+        assert_eq!(stats.books().ended(), 0);
+    }
+
+    #[test]
+    fn no_work_is_generated_for_a_disabled_type() {
+        let mut config = Config {
+            verbosity: Verbosity::Quiet,
+            ..no_delays()
+        };
+        config.item_types.remove("orange").unwrap();
+
+        let stats = App::new(config)
+            .unwrap()
+            .run_with_input(SilentObserver, |input_tx| {
+                for _ in 0..30 {
+                    _ = input_tx.send(Input::Line(String::new()));
+                }
+
+                _ = input_tx.send(Input::StdinClosed);
+            })
+            .unwrap();
+
+        assert_eq!(stats.per_type.len(), 1);
+        assert_eq!(stats.per_type[0].item_type, ItemType::new("Apple"));
+        assert_eq!((stats.work_created, stats.per_type[0].completed), (30, 30));
+    }
+
+    /// Progress output that can still be read after the app that wrote it has been dropped.
+    #[derive(Clone, Default)]
+    struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedOutput {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// Runs the app on the given input lines and returns the progress output, printed as if to a
+    /// terminal or not.
+    fn capture_lines(config: Config, terminal: bool, lines: &[&str]) -> String {
+        let output = CapturedOutput::default();
+        let lines: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        let app = App::with_output(config, Box::new(output.clone()), terminal);
+        let observer = PrintingObserver::new(&app);
+
+        app.run_with_input(observer, move |input_tx| {
+            for line in lines {
+                input_tx.send(Input::Line(line)).unwrap();
+            }
+
+            input_tx.send(Input::StdinClosed).unwrap();
+        })
+        .unwrap();
+
+        output.text()
+    }
+
+    #[test]
+    fn the_progress_output_goes_to_the_chosen_sink() {
+        let output = capture_lines(no_delays(), false, &["", "", "stats"]);
+        let collected: Vec<_> = output
+            .lines()
+            .filter(|line| line.starts_with("Collected "))
+            .collect();
+
+        assert_eq!(collected.len(), 2, "{output}");
+        assert!(
+            collected
+                .iter()
+                .all(|line| line.contains(" into a container of size ")),
+            "{output}"
+        );
+        assert_eq!(
+            output
+                .lines()
+                .filter(|line| line.starts_with("Stats: 2 work items created"))
+                .count(),
+            1,
+            "{output}"
+        );
+
+        let quiet = Config {
+            verbosity: Verbosity::Quiet,
+            ..no_delays()
+        };
+        let output = capture_lines(quiet, false, &["", ""]);
+        assert!(!output.contains("Collected"), "{output}");
+    }
+
+    #[test]
+    fn an_output_file_that_cannot_be_created_fails_up_front() {
+        let config = Config {
+            output: Some(std::env::temp_dir().join("communotron-no-such-dir/output.txt")),
+            ..Default::default()
+        };
+
+        let error = App::new(config).err().unwrap().to_string();
+        assert!(
+            error.starts_with("Failed to create output file "),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn containers_complete_in_the_order_of_their_fill_delays() {
+        let clock = Arc::new(clock::ManualClock::new());
+        let recorder = Recorder::default();
+        let messages = recorder.0.clone();
+
+        let driver = {
+            let clock = clock.clone();
+            let messages = messages.clone();
+
+            thread::spawn(move || {
+                // The Orange collector sleeps 2 s and the Apple collector 1 s.
+                clock.wait_for_sleepers(2);
+                assert_eq!(clock.advance_to_next(), Some(Duration::from_secs(1)));
+
+                while messages.lock().unwrap().is_empty() {
+                    thread::yield_now();
+                }
+
+                assert_eq!(clock.advance_to_next(), Some(Duration::from_secs(1)));
+            })
+        };
+
+        // Orange is queued first, but Apple fills faster.
+        let work = [(ItemType::new("Orange"), 1), (ItemType::new("Apple"), 1)];
+        let config = Config {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let stats = run_pipeline_with(config, work, clock.clone(), recorder).unwrap();
+        driver.join().unwrap();
+
+        let order: Vec<_> = messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|message| message.item_type.clone())
+            .collect();
+        assert_eq!(order, [ItemType::new("Apple"), ItemType::new("Orange")]);
+        assert_eq!(stats.work_completed(), 2);
+        assert_eq!(clock.now(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::config::WorkerPickup;
+
+/// Where a worker of the pool gets its work orders from.
+pub enum WorkSource<T> {
+    /// Straight from the shared queue, racing the other idle workers for every work order.
+    Shared(Arc<Mutex<Receiver<T>>>),
+    /// From the dispatcher, which the worker tells whenever it is idle.
+    Dispatched {
+        worker: usize,
+        idle_tx: Sender<usize>,
+        rx: Receiver<T>,
+    },
+}
+
+impl<T> WorkSource<T> {
+    /// Waits for the next work order. Returns `None` once there will be no more work.
+    pub fn next(&self) -> Option<T> {
+        match self {
+            // The lock is only held while waiting for the next work order,
+            // so the other workers can be filling their containers at the same time.
+            WorkSource::Shared(rx) => rx.lock().unwrap().recv().ok(),
+            WorkSource::Dispatched {
+                worker,
+                idle_tx,
+                rx,
+            } => {
+                idle_tx.send(*worker).ok()?;
+                rx.recv().ok()
+            }
+        }
+    }
+}
+
+/// Gives each of the workers a source of work orders from the shared queue. Unless any worker
+/// may pick up the next work order, a dispatcher thread hands them out and its handle is
+/// returned too. The dispatcher holds on to one work order while no worker is idle, so a bounded
+/// queue effectively holds one more.
+pub fn work_sources<T: Send + 'static>(
+    rx: Receiver<T>,
+    workers: usize,
+    pickup: WorkerPickup,
+) -> (Vec<WorkSource<T>>, Option<JoinHandle<()>>) {
+    if pickup == WorkerPickup::Any {
+        let rx = Arc::new(Mutex::new(rx));
+        let sources = (0..workers)
+            .map(|_| WorkSource::Shared(rx.clone()))
+            .collect();
+
+        return (sources, None);
+    }
+
+    let (idle_tx, idle_rx) = mpsc::channel();
+
+    let (worker_txs, sources) = (0..workers)
+        .map(|worker| {
+            let (tx, rx) = mpsc::channel();
+
+            let source = WorkSource::Dispatched {
+                worker,
+                idle_tx: idle_tx.clone(),
+                rx,
+            };
+
+            (tx, source)
+        })
+        .unzip();
+
+    let dispatcher = thread::spawn(move || dispatch(rx, idle_rx, worker_txs, pickup));
+
+    (sources, Some(dispatcher))
+}
+
+/// Hands every work order to the idle worker chosen by `pickup`. Returning drops the workers'
+/// channels, which lets them exit once the shared queue is closed.
+fn dispatch<T>(
+    rx: Receiver<T>,
+    idle_rx: Receiver<usize>,
+    worker_txs: Vec<Sender<T>>,
+    pickup: WorkerPickup,
+) {
+    // Oldest first, in the order the workers said they were idle.
+    let mut idle = VecDeque::new();
+
+    // The number of the work order each worker was handed last. Workers that have not had one
+    // yet sort first, as `None` is less than any `Some`.
+    let mut last_used: Vec<Option<u64>> = vec![None; worker_txs.len()];
+
+    for (order_number, work_order) in (0u64..).zip(rx) {
+        idle.extend(idle_rx.try_iter());
+
+        if idle.is_empty() {
+            let Ok(worker) = idle_rx.recv() else {
+                // Every worker has exited, nobody is left to do the work.
+                return;
+            };
+
+            idle.push_back(worker);
+            // Others may have become idle at the same time and deserve to be considered too.
+            idle.extend(idle_rx.try_iter());
+        }
+
+        let position = match pickup {
+            WorkerPickup::Any | WorkerPickup::Fifo => 0,
+            WorkerPickup::LeastRecentlyUsed => idle
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, &worker)| (last_used[worker], worker))
+                .map_or(0, |(position, _)| position),
+        };
+
+        let Some(worker) = idle.remove(position) else {
+            continue;
+        };
+
+        last_used[worker] = Some(order_number);
+
+        // A worker only says it is idle right before waiting for its next work order, so it is
+        // still there to receive it.
+        _ = worker_txs[worker].send(work_order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dispatches the work orders to three workers that said they were idle in the given order,
+    /// and returns the worker each work order went to.
+    fn dispatched_to(pickup: WorkerPickup, idle_order: [usize; 3], orders: &[&str]) -> Vec<usize> {
+        let (tx, rx) = mpsc::channel();
+        let (idle_tx, idle_rx) = mpsc::channel();
+        let (worker_txs, worker_rxs): (Vec<_>, Vec<_>) = (0..3).map(|_| mpsc::channel()).unzip();
+
+        for worker in idle_order {
+            idle_tx.send(worker).unwrap();
+        }
+
+        for &order in orders {
+            tx.send(order).unwrap();
+        }
+
+        drop((tx, idle_tx));
+        dispatch(rx, idle_rx, worker_txs, pickup);
+
+        let received: Vec<Vec<_>> = worker_rxs
+            .iter()
+            .map(|worker_rx| worker_rx.try_iter().collect())
+            .collect();
+
+        orders
+            .iter()
+            .map(|order| {
+                received
+                    .iter()
+                    .position(|orders| orders.contains(order))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fifo_hands_out_in_the_order_the_workers_became_idle() {
+        assert_eq!(
+            dispatched_to(WorkerPickup::Fifo, [2, 0, 1], &["a", "b", "c"]),
+            [2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn lru_hands_out_to_the_least_recently_used_worker() {
+        assert_eq!(
+            dispatched_to(WorkerPickup::LeastRecentlyUsed, [2, 0, 1], &["a", "b", "c"]),
+            [0, 1, 2]
+        );
+    }
+}
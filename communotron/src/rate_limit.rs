@@ -0,0 +1,79 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Paces work generation with a token bucket: tokens accumulate at a fixed rate up to one
+/// second's worth, and every work item takes one. Short bursts are allowed, but over the long
+/// run no more than `rate` items per second get through.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` is in tokens per second and must be positive.
+    pub fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, first waiting for one to become available if the bucket is empty.
+    pub fn acquire(&mut self) {
+        self.refill();
+
+        if self.tokens < 1.0 {
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+            self.refill();
+        }
+
+        // Sleeping may come up a hair short of a whole token. That is not worth another wait.
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_goes_through_and_the_rest_is_paced() {
+        let mut bucket = TokenBucket::new(100.0);
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            bucket.acquire();
+        }
+
+        let burst = start.elapsed();
+        assert!(burst < Duration::from_millis(100), "burst took {burst:?}");
+
+        for _ in 0..20 {
+            bucket.acquire();
+        }
+
+        // 20 more at 100 per second, less whatever trickled in during the burst.
+        let paced = start.elapsed() - burst;
+        assert!(
+            paced >= Duration::from_millis(190) - burst,
+            "20 items went through in {paced:?}"
+        );
+        assert!(paced < Duration::from_secs(1), "20 items took {paced:?}");
+    }
+}
@@ -0,0 +1,367 @@
+//! The fruit types the pipeline works with. Every type is registered under a name, with how long
+//! its containers take to fill and how to make one of its fruit. The two classic types, apples
+//! and oranges, are registered by default; a file given with `--item-types` replaces them:
+//!
+//! ```text
+//! # name  fill delay (ms)  weight (g)  color (ANSI SGR parameters, optional)
+//! Apple   1000             150-250     32
+//! Orange  2000             120-300     38;5;208
+//! Banana  500              100-150     33
+//! ```
+
+use rand::{rngs::ThreadRng, Rng};
+use std::{
+    fmt::{self, Display},
+    fs,
+    ops::RangeInclusive,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A fruit type, identified by the name it was registered under. Cheap to clone.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ItemType(Arc<str>);
+
+impl ItemType {
+    pub fn new(name: &str) -> Self {
+        Self(name.into())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for ItemType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A fruit that can be collected into a container.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub item_type: ItemType,
+    pub weight_grams: u32,
+}
+
+/// Makes a fruit of one type, e.g. picking its weight at random. Called on the collector threads.
+pub type ItemFactory = Arc<dyn Fn(&mut ThreadRng) -> Item + Send + Sync>;
+
+/// Everything the pipeline needs to know about a fruit type.
+#[derive(Clone)]
+pub struct TypeDefinition {
+    pub item_type: ItemType,
+    /// How long it takes a collector to fill a container with this fruit type. Can be changed
+    /// while running with the `delay` command.
+    pub fill_delay: Duration,
+    /// The color of the fruit type's report lines, as ANSI SGR parameters. Uncolored if `None`.
+    pub color: Option<String>,
+    pub new_item: ItemFactory,
+}
+
+impl fmt::Debug for TypeDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypeDefinition")
+            .field("item_type", &self.item_type)
+            .field("fill_delay", &self.fill_delay)
+            .field("color", &self.color)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Makes fruit of the given type with a weight picked at random from the range.
+pub fn weighed_between(item_type: ItemType, weights: RangeInclusive<u32>) -> ItemFactory {
+    Arc::new(move |rng| Item {
+        item_type: item_type.clone(),
+        weight_grams: rng.gen_range(weights.clone()),
+    })
+}
+
+/// The registered fruit types, in the order they were registered.
+#[derive(Debug, Clone)]
+pub struct ItemRegistry {
+    types: Vec<TypeDefinition>,
+}
+
+impl Default for ItemRegistry {
+    /// Apples and oranges of typical weights.
+    fn default() -> Self {
+        let apple = ItemType::new("Apple");
+        let orange = ItemType::new("Orange");
+
+        Self {
+            types: vec![
+                TypeDefinition {
+                    item_type: apple.clone(),
+                    fill_delay: Duration::from_secs(1),
+                    color: Some("32".to_string()),
+                    new_item: weighed_between(apple, 150..=250),
+                },
+                TypeDefinition {
+                    item_type: orange.clone(),
+                    fill_delay: Duration::from_secs(2),
+                    // From the 256-color palette, as the basic 8 colors have no orange.
+                    color: Some("38;5;208".to_string()),
+                    new_item: weighed_between(orange, 120..=300),
+                },
+            ],
+        }
+    }
+}
+
+impl ItemRegistry {
+    /// A registry without any fruit types, for registering custom ones.
+    pub fn new() -> Self {
+        Self { types: Vec::new() }
+    }
+
+    /// Reads the fruit types from a file in the format described at the top of this module.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read the item types from {}: {e}", path.display()))?;
+
+        let mut registry = Self::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            parse_definition(line)
+                .and_then(|definition| registry.register(definition))
+                .map_err(|e| format!("{}, line {}: {e}", path.display(), index + 1))?;
+        }
+
+        if registry.types.is_empty() {
+            return Err(format!(
+                "{} does not define any item types.",
+                path.display()
+            ));
+        }
+
+        Ok(registry)
+    }
+
+    /// Fails if the name is not usable in commands or a type of that name is already registered.
+    pub fn register(&mut self, definition: TypeDefinition) -> Result<(), String> {
+        let name = definition.item_type.name();
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Invalid item type name: {name:?}. Only letters, digits, - and _ are allowed."
+            ));
+        }
+
+        if self.find(name).is_some() {
+            return Err(format!("The item type {name} is already registered."));
+        }
+
+        self.types.push(definition);
+        Ok(())
+    }
+
+    pub fn types(&self) -> &[TypeDefinition] {
+        &self.types
+    }
+
+    /// Looks up a fruit type the way the user refers to it in commands: ignoring case and
+    /// optionally in plural, e.g. "apples" for `Apple`.
+    pub fn find(&self, name: &str) -> Option<&TypeDefinition> {
+        self.position(name).map(|index| &self.types[index])
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut TypeDefinition> {
+        self.position(name).map(|index| &mut self.types[index])
+    }
+
+    /// Unregisters the fruit type, looked up the same way as with `find`.
+    pub fn remove(&mut self, name: &str) -> Option<TypeDefinition> {
+        self.position(name).map(|index| self.types.remove(index))
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.types.iter().position(|definition| {
+            let registered = definition.item_type.name();
+
+            name.eq_ignore_ascii_case(registered)
+                || name
+                    .strip_suffix(['s', 'S'])
+                    .is_some_and(|singular| singular.eq_ignore_ascii_case(registered))
+        })
+    }
+
+    pub fn get(&self, item_type: &ItemType) -> Option<&TypeDefinition> {
+        self.types
+            .iter()
+            .find(|definition| definition.item_type == *item_type)
+    }
+
+    /// The names of all fruit types in lowercase, as they are usually typed, e.g. `apple|orange`.
+    pub fn usage_names(&self) -> String {
+        self.types
+            .iter()
+            .map(|definition| definition.item_type.name().to_lowercase())
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+}
+
+/// Parses one fruit type, e.g. `Banana 500 100-150 33`.
+fn parse_definition(line: &str) -> Result<TypeDefinition, String> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+
+    let (name, millis, weights, color) = match fields.as_slice() {
+        [name, millis, weights] => (name, millis, weights, None),
+        [name, millis, weights, color] => (name, millis, weights, Some(color.to_string())),
+        _ => {
+            return Err(format!(
+                "Expected a name, a fill delay in milliseconds, a weight range and optionally a color: {line}"
+            ))
+        }
+    };
+
+    let fill_delay = millis
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid fill delay: {millis}"))?;
+
+    let weights = weights
+        .split_once('-')
+        .and_then(|(min, max)| Some(min.parse::<u32>().ok()?..=max.parse::<u32>().ok()?))
+        .filter(|weights| !weights.is_empty())
+        .ok_or_else(|| format!("Invalid weight range: {weights}, expected e.g. 100-150"))?;
+
+    let item_type = ItemType::new(name);
+
+    Ok(TypeDefinition {
+        item_type: item_type.clone(),
+        fill_delay,
+        color,
+        new_item: weighed_between(item_type, weights),
+    })
+}
+
+/// One value for every registered fruit type, e.g. a counter, in the order they were registered.
+/// The set of fruit types is fixed once the pipeline is running, so no locking is needed to
+/// look a fruit type up.
+#[derive(Debug, Clone)]
+pub(crate) struct PerType<T> {
+    values: Vec<(ItemType, T)>,
+}
+
+impl<T> PerType<T> {
+    pub(crate) fn new(
+        registry: &ItemRegistry,
+        mut value: impl FnMut(&TypeDefinition) -> T,
+    ) -> Self {
+        Self {
+            values: registry
+                .types()
+                .iter()
+                .map(|definition| (definition.item_type.clone(), value(definition)))
+                .collect(),
+        }
+    }
+
+    /// Panics if the fruit type is not registered, as everything in the pipeline comes from the
+    /// registry it was started with.
+    pub(crate) fn get(&self, item_type: &ItemType) -> &T {
+        self.values
+            .iter()
+            .find(|(registered, _)| registered == item_type)
+            .map(|(_, value)| value)
+            .unwrap_or_else(|| panic!("item type {item_type} is not registered"))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&ItemType, &T)> {
+        self.values
+            .iter()
+            .map(|(item_type, value)| (item_type, value))
+    }
+}
+
+impl<T> IntoIterator for PerType<T> {
+    type Item = (ItemType, T);
+    type IntoIter = std::vec::IntoIter<(ItemType, T)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fruit_weighs_within_its_range() {
+        let mut rng = rand::thread_rng();
+        let new_item = weighed_between(ItemType::new("Plum"), 30..=40);
+
+        for _ in 0..1000 {
+            let item = new_item(&mut rng);
+            assert_eq!(item.item_type, ItemType::new("Plum"));
+            assert!((30..=40).contains(&item.weight_grams), "{item:?}");
+        }
+    }
+
+    #[test]
+    fn definitions_are_parsed() {
+        let definition = parse_definition("Banana 500 120-120 33").unwrap();
+        assert_eq!(definition.item_type.name(), "Banana");
+        assert_eq!(definition.fill_delay, Duration::from_millis(500));
+        assert_eq!(definition.color.as_deref(), Some("33"));
+        assert_eq!(
+            (definition.new_item)(&mut rand::thread_rng()).weight_grams,
+            120
+        );
+
+        assert!(parse_definition("Banana 500 150-100").is_err());
+        assert!(parse_definition("Banana 500").is_err());
+        assert!(parse_definition("Banana fast 100-150").is_err());
+    }
+
+    #[test]
+    fn types_are_found_the_way_they_are_typed() {
+        let mut registry = ItemRegistry::default();
+
+        for name in ["Apple", "apple", "APPLES", "apples"] {
+            assert_eq!(
+                registry
+                    .find(name)
+                    .map(|definition| definition.item_type.name()),
+                Some("Apple"),
+                "{name}"
+            );
+        }
+
+        assert!(registry.find("appless").is_none());
+        assert!(registry.find("pear").is_none());
+        assert_eq!(registry.usage_names(), "apple|orange");
+
+        let plum = || TypeDefinition {
+            item_type: ItemType::new("Plum"),
+            fill_delay: Duration::ZERO,
+            color: None,
+            new_item: weighed_between(ItemType::new("Plum"), 30..=40),
+        };
+
+        assert_eq!(registry.register(plum()), Ok(()));
+        assert!(registry.register(plum()).is_err());
+        assert!(registry
+            .register(TypeDefinition {
+                item_type: ItemType::new("Red plum"),
+                ..plum()
+            })
+            .is_err());
+        assert_eq!(registry.usage_names(), "apple|orange|plum");
+    }
+}
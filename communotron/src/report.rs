@@ -0,0 +1,241 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How much progress output the app prints. Each level includes everything printed by the
+/// levels before it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only what the user asked for, periodic summaries and the reasons for stopping.
+    Quiet,
+    /// Additionally, a line for every completed container.
+    #[default]
+    Normal,
+    /// Additionally, the work ID and latency of every completed container.
+    Verbose,
+}
+
+/// All progress output goes through here, so the verbosity is applied in one place.
+/// Errors are not progress output and still go straight to stderr.
+pub struct Reporter {
+    verbosity: Verbosity,
+
+    /// Where the progress output is written, stdout unless chosen otherwise.
+    output: Mutex<Box<dyn Write + Send>>,
+
+    /// A summary is due every time this many containers have been completed or this much time
+    /// has passed since the previous summary, whichever comes first.
+    summary_every: u64,
+    summary_interval: Duration,
+    last_summary: Mutex<Instant>,
+
+    /// If set, a heartbeat is due once this much time has passed without a completion or a
+    /// previous heartbeat.
+    heartbeat_interval: Option<Duration>,
+    last_heard: Mutex<Instant>,
+
+    color: bool,
+}
+
+impl Reporter {
+    pub fn new(
+        verbosity: Verbosity,
+        summary_every: u64,
+        summary_interval: Duration,
+        heartbeat_interval: Option<Duration>,
+        color: bool,
+        output: Box<dyn Write + Send>,
+    ) -> Self {
+        Self {
+            verbosity,
+            output: Mutex::new(output),
+            summary_every,
+            summary_interval,
+            last_summary: Mutex::new(Instant::now()),
+            heartbeat_interval,
+            last_heard: Mutex::new(Instant::now()),
+            color,
+        }
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Prints the message if the configured verbosity is at least `level`.
+    pub fn print(&self, level: Verbosity, message: impl Display) {
+        if self.verbosity < level {
+            return;
+        }
+
+        // Written in one go and flushed right away, so lines from different threads never mix
+        // and a file can be followed while the app runs.
+        let line = format!("{message}\n");
+        let mut output = self.output.lock().unwrap();
+
+        if let Err(e) = output
+            .write_all(line.as_bytes())
+            .and_then(|()| output.flush())
+        {
+            eprintln!("Failed to write progress output: {e}");
+        }
+    }
+
+    /// Wraps the text in ANSI escape codes for the color, given as SGR parameters such as "32"
+    /// for green. Without color output, returns the text as it is.
+    pub fn colorize(&self, color: &str, text: impl Display) -> String {
+        if self.color {
+            format!("\x1b[{color}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// How long the reporter may wait for the next completion before a summary is due.
+    pub fn until_next_summary(&self) -> Duration {
+        let last_summary = *self.last_summary.lock().unwrap();
+        self.summary_interval.saturating_sub(last_summary.elapsed())
+    }
+
+    /// Whether a summary should be printed now that `completed` containers have been completed.
+    pub fn summary_due(&self, completed: u64) -> bool {
+        completed.is_multiple_of(self.summary_every)
+            || self.last_summary.lock().unwrap().elapsed() >= self.summary_interval
+    }
+
+    /// Starts the summary interval over, as a summary has just been reported.
+    pub fn summary_reported(&self) {
+        *self.last_summary.lock().unwrap() = Instant::now();
+    }
+
+    /// How long the reporter may wait for the next completion before a heartbeat is due, if
+    /// heartbeats are enabled.
+    pub fn until_next_heartbeat(&self) -> Option<Duration> {
+        let last_heard = *self.last_heard.lock().unwrap();
+
+        self.heartbeat_interval
+            .map(|interval| interval.saturating_sub(last_heard.elapsed()))
+    }
+
+    /// Starts the heartbeat interval over, as a completion has just arrived.
+    pub fn completion_arrived(&self) {
+        *self.last_heard.lock().unwrap() = Instant::now();
+    }
+
+    /// Whether a heartbeat should be printed now. If so, the heartbeat interval starts over, so
+    /// that of several reporters waiting at the same time only one prints it.
+    pub fn take_heartbeat(&self) -> bool {
+        let Some(interval) = self.heartbeat_interval else {
+            return false;
+        };
+
+        let mut last_heard = self.last_heard.lock().unwrap();
+
+        if last_heard.elapsed() < interval {
+            return false;
+        }
+
+        *last_heard = Instant::now();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, thread};
+
+    use super::*;
+
+    #[test]
+    fn summaries_are_due_at_every_verbosity() {
+        for verbosity in [Verbosity::Quiet, Verbosity::Normal, Verbosity::Verbose] {
+            let reporter = Reporter::new(
+                verbosity,
+                100,
+                Duration::from_secs(3600),
+                None,
+                false,
+                Box::new(io::sink()),
+            );
+            assert!(!reporter.summary_due(99));
+            assert!(reporter.summary_due(100));
+            assert!(reporter.summary_due(200));
+            assert!(reporter.until_next_summary() > Duration::from_secs(3500));
+
+            let interval_passed = Reporter::new(
+                verbosity,
+                100,
+                Duration::ZERO,
+                None,
+                false,
+                Box::new(io::sink()),
+            );
+            assert!(interval_passed.summary_due(1));
+            assert_eq!(interval_passed.until_next_summary(), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn color_codes_only_with_color() {
+        let reporter = |color| {
+            Reporter::new(
+                Verbosity::Normal,
+                100,
+                Duration::from_secs(10),
+                None,
+                color,
+                Box::new(io::sink()),
+            )
+        };
+
+        assert_eq!(reporter(false).colorize("32", "apples"), "apples");
+        assert_eq!(
+            reporter(true).colorize("38;5;208", "oranges"),
+            "\x1b[38;5;208moranges\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn heartbeats_are_due_only_after_a_quiet_interval() {
+        let disabled = Reporter::new(
+            Verbosity::Normal,
+            100,
+            Duration::from_secs(10),
+            None,
+            false,
+            Box::new(io::sink()),
+        );
+        assert_eq!(disabled.until_next_heartbeat(), None);
+        assert!(!disabled.take_heartbeat());
+
+        let reporter = |heartbeat_interval| {
+            Reporter::new(
+                Verbosity::Normal,
+                100,
+                Duration::from_secs(10),
+                Some(heartbeat_interval),
+                false,
+                Box::new(io::sink()),
+            )
+        };
+
+        let quiet = reporter(Duration::from_secs(3600));
+        assert!(quiet.until_next_heartbeat().unwrap() > Duration::from_secs(3500));
+        assert!(!quiet.take_heartbeat());
+
+        // Only the first of several reporters waiting at the same time gets to print it.
+        let due = reporter(Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(due.until_next_heartbeat(), Some(Duration::ZERO));
+        assert!(due.take_heartbeat());
+        assert!(!due.take_heartbeat());
+
+        // A completion starts the interval over just like a heartbeat does.
+        thread::sleep(Duration::from_millis(30));
+        due.completion_arrived();
+        assert!(!due.take_heartbeat());
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::mpsc::Sender;
+
+use crate::Input;
+
+/// Turns SIGINT (Ctrl-C) and SIGTERM into `Input::Shutdown`, so a process supervisor or the user
+/// can stop the app without abandoning the work that has already been queued. Only the first
+/// signal is turned into a shutdown: a second one stops the app right away, as the platform
+/// default would, so that a long drain can still be cut short.
+#[cfg(unix)]
+pub fn forward_shutdown_signals(input_tx: Sender<Input>) {
+    use std::{
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    /// The shutdown signal received, or 0 if none yet. A signal handler may do very little
+    /// safely, so all it does is record the signal here for the forwarding thread to pick up.
+    static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        RECEIVED_SIGNAL.store(signal, Ordering::Relaxed);
+
+        // SAFETY: signal() is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        }
+    }
+
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    // SAFETY: the handler only stores into an atomic and calls signal(), both async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    thread::spawn(move || {
+        // The handler is gone once it has been called, so only one signal ever arrives here.
+        let signal = loop {
+            thread::sleep(Duration::from_millis(100));
+
+            match RECEIVED_SIGNAL.load(Ordering::Relaxed) {
+                0 => continue,
+                signal => break signal,
+            }
+        };
+
+        let signal_name = match signal {
+            libc::SIGINT => "SIGINT",
+            libc::SIGTERM => "SIGTERM",
+            _ => "an unexpected signal",
+        };
+
+        eprintln!("Another SIGINT (Ctrl-C) or SIGTERM stops the app without waiting.");
+
+        // If nobody is reading input any more, there is nothing left to shut down anyway.
+        _ = input_tx.send(Input::Shutdown(format!("received {signal_name}")));
+    });
+}
+
+/// Only Unix signals are handled, elsewhere the platform default behavior applies.
+#[cfg(not(unix))]
+pub fn forward_shutdown_signals(_input_tx: Sender<Input>) {}
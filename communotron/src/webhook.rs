@@ -0,0 +1,347 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{CompletionObserver, ContainerFilledMessage};
+
+/// Completions waiting to be posted. Once full, new completions are dropped rather than letting
+/// a slow endpoint hold up the reporter.
+const WEBHOOK_QUEUE_LENGTH: usize = 1000;
+
+/// The completions arriving within this long of each other are posted together, and no more
+/// than one post is made per this long, so a busy pipeline cannot flood the endpoint.
+const BATCH_WINDOW: Duration = Duration::from_millis(500);
+
+/// Most completions posted in one batch.
+const MAX_BATCH_SIZE: usize = 100;
+
+/// How long connecting to the endpoint, sending a batch or waiting for the response may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Posts every completion as JSON to an HTTP endpoint, in batches of a JSON array each. The
+/// posting happens on a dedicated thread, and failures are logged and the batch dropped, so the
+/// endpoint can never stall the pipeline.
+///
+/// Dropping the observer waits for the completions still queued to be posted, so the last ones
+/// are not lost when the app exits. With an unreachable endpoint, that takes a few seconds.
+pub struct WebhookObserver {
+    /// Taken on drop, which closes the queue and lets the posting thread finish.
+    tx: Option<SyncSender<String>>,
+    posting_thread: Option<JoinHandle<()>>,
+    /// Whether completions are currently being dropped, so only the first one is logged.
+    dropping: AtomicBool,
+}
+
+impl WebhookObserver {
+    /// Only plain `http://host[:port][/path]` URLs are supported.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let endpoint = Endpoint::parse(url)?;
+        let (tx, rx) = mpsc::sync_channel(WEBHOOK_QUEUE_LENGTH);
+
+        let posting_thread = thread::spawn(move || post_batches(rx, endpoint));
+
+        Ok(Self {
+            tx: Some(tx),
+            posting_thread: Some(posting_thread),
+            dropping: AtomicBool::new(false),
+        })
+    }
+}
+
+impl Drop for WebhookObserver {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+
+        if let Some(posting_thread) = self.posting_thread.take() {
+            if let Err(e) = posting_thread.join() {
+                eprintln!("Posting to the webhook failed: {e:?}");
+            }
+        }
+    }
+}
+
+impl CompletionObserver for WebhookObserver {
+    fn on_completion(&self, message: &ContainerFilledMessage) {
+        // Only ever taken on drop.
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        match tx.try_send(to_json(message)) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    eprintln!(
+                        "The webhook is falling behind, dropping completions until it catches up."
+                    );
+                }
+            }
+            // The posting thread only ends if it panicked, which has already been reported.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+fn to_json(message: &ContainerFilledMessage) -> String {
+    let optional = |value: Option<u64>| value.map_or("null".to_string(), |value| value.to_string());
+
+    format!(
+        "{{\"work_id\": {}, \"epoch\": {}, \"item_type\": {}, \"container_size\": {}, \"items_added\": {}, \"items_rejected\": {}, \"total_weight_grams\": {}, \"latency_ms\": {}}}",
+        message.work_id,
+        message.epoch,
+        json_string(message.item_type.name()),
+        message.container_size,
+        message.items_added,
+        optional(message.items_rejected.map(|rejected| rejected as u64)),
+        optional(message.total_weight_grams),
+        message.created_at.elapsed().as_millis()
+    )
+}
+
+/// Quotes a string for JSON, escaping the characters that would otherwise end or break it.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+fn post_batches(rx: Receiver<String>, endpoint: Endpoint) {
+    let mut last_post: Option<Instant> = None;
+
+    // Waiting for the first completion of every batch without a time limit.
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_WINDOW;
+
+        while batch.len() < MAX_BATCH_SIZE {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(completion) => batch.push(completion),
+                Err(RecvTimeoutError::Timeout) => break,
+                // Whatever we have is still worth posting.
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if let Some(last_post) = last_post {
+            thread::sleep(BATCH_WINDOW.saturating_sub(last_post.elapsed()));
+        }
+
+        last_post = Some(Instant::now());
+
+        let body = format!("[{}]", batch.join(", "));
+
+        if let Err(e) = endpoint.post(&body) {
+            eprintln!(
+                "Posting {} completions to the webhook at {} failed, dropping them: {e}",
+                batch.len(),
+                endpoint.url
+            );
+        }
+    }
+}
+
+struct Endpoint {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// webhook URLs are supported: {url}"))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in webhook URL: {url}"))?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(format!("Missing host in webhook URL: {url}"));
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Posts the JSON body and checks that the endpoint accepted it.
+    fn post(&self, body: &str) -> Result<(), String> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or("the host has no address")?;
+
+        let mut stream =
+            TcpStream::connect_timeout(&address, REQUEST_TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(REQUEST_TIMEOUT)))
+            .map_err(|e| e.to_string())?;
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut status_line)
+            .map_err(|e| e.to_string())?;
+
+        // E.g. "HTTP/1.1 204 No Content", anything in the 2xx range is a success.
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(format!("the endpoint replied {}", status_line.trim_end())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, sync::mpsc::Sender};
+
+    use super::*;
+    use crate::ItemType;
+
+    /// Accepts posts like a webhook endpoint would and passes on the body of every one.
+    /// Returns the URL to post to.
+    fn mock_endpoint(bodies: Sender<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+
+                    if header.trim_end().is_empty() {
+                        break;
+                    }
+
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("Content-Length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                // Passed on before replying, so it has arrived by the time the post returns.
+                if bodies.send(String::from_utf8(body).unwrap()).is_err() {
+                    return;
+                }
+
+                _ = reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+            }
+        });
+
+        url
+    }
+
+    fn filled(work_id: u64) -> ContainerFilledMessage {
+        ContainerFilledMessage {
+            work_id,
+            created_at: Instant::now(),
+            epoch: 0,
+            container_size: 3,
+            items_added: 3,
+            items_rejected: None,
+            total_weight_grams: Some(450),
+            item_type: ItemType::new("Apple"),
+        }
+    }
+
+    #[test]
+    fn queued_completions_are_posted_before_drop_returns() {
+        let (bodies_tx, bodies_rx) = mpsc::channel();
+        let observer = WebhookObserver::new(&mock_endpoint(bodies_tx)).unwrap();
+
+        for work_id in 1..=5 {
+            observer.on_completion(&filled(work_id));
+        }
+
+        drop(observer);
+
+        let bodies: Vec<_> = bodies_rx.try_iter().collect();
+        let posted = bodies.concat().matches("\"work_id\": ").count();
+
+        assert_eq!(posted, 5, "posted {bodies:?}");
+        assert!(bodies[0].starts_with("[{\"work_id\": 1, \"epoch\": 0, \"item_type\": \"Apple\""));
+    }
+
+    #[test]
+    fn strings_are_escaped_for_json() {
+        assert_eq!(json_string("Apple"), "\"Apple\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\nb"), "\"a\\u000ab\"");
+    }
+
+    #[test]
+    fn endpoint_urls_are_parsed() {
+        let endpoint = Endpoint::parse("http://localhost:8080/hooks/done").unwrap();
+        assert_eq!(
+            (
+                endpoint.host.as_str(),
+                endpoint.port,
+                endpoint.path.as_str()
+            ),
+            ("localhost", 8080, "/hooks/done")
+        );
+
+        let endpoint = Endpoint::parse("http://example.com").unwrap();
+        assert_eq!((endpoint.port, endpoint.path.as_str()), (80, "/"));
+
+        assert!(Endpoint::parse("https://example.com").is_err());
+        assert!(Endpoint::parse("http://:80/").is_err());
+        assert!(Endpoint::parse("http://example.com:http/").is_err());
+    }
+}